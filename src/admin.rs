@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+use base64::prelude::*;
+use chrono::{DateTime, SecondsFormat, Utc};
+use hyper::{header, Request};
+
+use crate::storage::{self, Visits};
+
+static PAGE: &str = include_str!("admin.html");
+
+/// When each referer was last counted. Only kept in memory, so referers that
+/// weren't visited since the server started have no time.
+#[derive(Default)]
+pub struct LastSeen(Mutex<HashMap<String, DateTime<Utc>>>);
+
+impl LastSeen {
+    pub fn touch(&self, referer: &str) {
+        let mut seen = self.0.lock().unwrap();
+        match seen.get_mut(referer) {
+            Some(time) => *time = Utc::now(),
+            None => {
+                seen.insert(referer.to_string(), Utc::now());
+            }
+        }
+    }
+
+    pub fn forget(&self, referer: &str) {
+        self.0.lock().unwrap().remove(referer);
+    }
+
+    pub fn get(&self, referer: &str) -> Option<DateTime<Utc>> {
+        self.0.lock().unwrap().get(referer).copied()
+    }
+}
+
+/// The basic auth username of `req`, which admins can set to whatever names
+/// them, since only the password is checked.
+pub fn username<B>(req: &Request<B>) -> Option<String> {
+    let basic = req
+        .headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let credentials = BASE64_STANDARD.decode(basic.trim()).ok()?;
+    let colon = credentials.iter().position(|&b| b == b':')?;
+    let user = String::from_utf8_lossy(&credentials[..colon]).into_owned();
+    (!user.is_empty()).then_some(user)
+}
+
+/// Whether `req` carries `token`, either as a bearer token or as the password
+/// of basic auth, which lets browsers prompt for it.
+pub fn authorized<B>(req: &Request<B>, token: &str) -> bool {
+    let Some(auth) = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return false;
+    };
+
+    let given = if let Some(bearer) = auth.strip_prefix("Bearer ") {
+        bearer.as_bytes().to_vec()
+    } else if let Some(basic) = auth.strip_prefix("Basic ") {
+        let Ok(credentials) = BASE64_STANDARD.decode(basic.trim()) else {
+            return false;
+        };
+        match credentials.iter().position(|&b| b == b':') {
+            Some(colon) => credentials[colon + 1..].to_vec(),
+            None => return false,
+        }
+    } else {
+        return false;
+    };
+
+    // Compares every byte, so the time taken doesn't reveal how much matched.
+    given.len() == token.len()
+        && given
+            .iter()
+            .zip(token.as_bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Renders the dashboard listing every referer, busiest first.
+pub fn page(visits: &Visits, last_seen: &LastSeen) -> String {
+    let mut referers: Vec<_> = visits.iter().collect();
+    referers.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+
+    let mut rows = String::new();
+    for (referer, count) in &referers {
+        let seen = last_seen
+            .get(referer)
+            .map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, true))
+            .unwrap_or_default();
+        let referer = escape(referer);
+
+        writeln!(
+            rows,
+            r#"            <tr data-referer="{referer}" data-count="{count}" data-seen="{seen}"><td>{referer}</td><td class="count">{count}</td><td>{}</td></tr>"#,
+            if seen.is_empty() { "-" } else { &seen },
+        )
+        .unwrap();
+    }
+
+    PAGE.replace("{{TOTAL}}", &storage::total(visits).to_string())
+        .replace("{{REFERERS}}", &visits.len().to_string())
+        .replace("{{ROWS}}", rows.trim_end())
+}
+
+/// Escapes `s` for use in HTML text and attribute values.
+pub fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn totals_of_huge_counters() {
+        let visits = Visits::from([
+            ("https://a.com/".to_string(), usize::MAX),
+            ("https://b.com/".to_string(), 1),
+        ]);
+        assert_eq!(storage::total(&visits), usize::MAX);
+        assert!(page(&visits, &LastSeen::default()).contains(&usize::MAX.to_string()));
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::storage::VisitStore;
+
+/// Counts the browser and OS families of each counter's visitors, told apart
+/// coarsely by their `User-Agent`. Its keys are `browser:<family> <referer>` and
+/// `os:<family> <referer>`, so any storage backend can hold them.
+pub struct Agents {
+    store: Arc<dyn VisitStore>,
+}
+
+/// The browser and OS families of one counter's visitors.
+pub struct Families {
+    pub browsers: BTreeMap<String, usize>,
+    pub os: BTreeMap<String, usize>,
+}
+
+impl Agents {
+    pub fn new(store: Arc<dyn VisitStore>) -> Self {
+        Self { store }
+    }
+
+    pub fn store(&self) -> &Arc<dyn VisitStore> {
+        &self.store
+    }
+
+    /// Counts a visit of `referer` with `user_agent`.
+    pub async fn increment(&self, referer: &str, user_agent: &str) -> anyhow::Result<()> {
+        let user_agent = user_agent.to_lowercase();
+        self.store
+            .increment(&format!("browser:{} {referer}", browser(&user_agent)))
+            .await?;
+        self.store
+            .increment(&format!("os:{} {referer}", os(&user_agent)))
+            .await?;
+        Ok(())
+    }
+
+    pub async fn of(&self, referer: &str) -> anyhow::Result<Families> {
+        let mut families = Families {
+            browsers: BTreeMap::new(),
+            os: BTreeMap::new(),
+        };
+        for (key, count) in self.store.snapshot().await? {
+            let Some((family, of)) = key.split_once(' ') else {
+                continue;
+            };
+            if of != referer {
+                continue;
+            }
+            if let Some(browser) = family.strip_prefix("browser:") {
+                families.browsers.insert(browser.to_string(), count);
+            } else if let Some(os) = family.strip_prefix("os:") {
+                families.os.insert(os.to_string(), count);
+            }
+        }
+        Ok(families)
+    }
+
+    /// Drops the families of `referers`.
+    pub async fn forget(&self, referers: &[String]) -> anyhow::Result<()> {
+        let keys: Vec<String> = self
+            .store
+            .snapshot()
+            .await?
+            .into_keys()
+            .filter(|key| {
+                key.split_once(' ')
+                    .is_some_and(|(_, of)| referers.iter().any(|referer| referer == of))
+            })
+            .collect();
+        self.store.remove_many(&keys).await?;
+        Ok(())
+    }
+}
+
+/// The browser family of a lowercase `User-Agent`. Most browsers claim to be
+/// several others too, so the order matters.
+fn browser(user_agent: &str) -> &'static str {
+    let has = |s: &str| user_agent.contains(s);
+    if has("edg/") || has("edge/") || has("edgios") || has("edga/") {
+        "Edge"
+    } else if has("opr/") || has("opera") {
+        "Opera"
+    } else if has("samsungbrowser") {
+        "Samsung"
+    } else if has("firefox/") || has("fxios") {
+        "Firefox"
+    } else if has("chrome/") || has("crios") || has("chromium") {
+        "Chrome"
+    } else if has("safari/") {
+        "Safari"
+    } else {
+        "Other"
+    }
+}
+
+/// The OS family of a lowercase `User-Agent`.
+fn os(user_agent: &str) -> &'static str {
+    let has = |s: &str| user_agent.contains(s);
+    if has("windows") {
+        "Windows"
+    } else if has("iphone") || has("ipad") || has("ipod") {
+        "iOS"
+    } else if has("android") {
+        "Android"
+    } else if has("cros") {
+        "ChromeOS"
+    } else if has("macintosh") || has("mac os x") {
+        "macOS"
+    } else if has("linux") {
+        "Linux"
+    } else {
+        "Other"
+    }
+}
@@ -0,0 +1,79 @@
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use chrono::{SecondsFormat, Utc};
+use serde::Serialize;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+/// An append-only log of every correction made through the admin API, one
+/// JSON object per line, so it's clear who changed which counter and when.
+pub struct AuditLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+/// Who made a correction: the basic auth username if they gave one, and where
+/// they connected from.
+pub struct Actor {
+    pub user: Option<String>,
+    pub client: IpAddr,
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    time: String,
+    user: Option<&'a str>,
+    client: IpAddr,
+    action: &'a str,
+    referer: &'a str,
+    old: usize,
+    new: usize,
+}
+
+impl AuditLog {
+    pub async fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .with_context(|| format!("Can't open the audit log {path:?}"))?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Appends that `actor` changed the count of `referer` from `old` to `new`.
+    pub async fn record(
+        &self,
+        actor: &Actor,
+        action: &str,
+        referer: &str,
+        old: usize,
+        new: usize,
+    ) -> anyhow::Result<()> {
+        let entry = Entry {
+            time: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            user: actor.user.as_deref(),
+            client: actor.client,
+            action,
+            referer,
+            old,
+            new,
+        };
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+
+        let mut file = self.file.lock().await;
+        file.write_all(line.as_bytes())
+            .await
+            .with_context(|| format!("Can't write to the audit log {:?}", self.path))?;
+        file.flush().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+/// Parts of the `User-Agent` of common crawlers, link previewers and uptime
+/// monitors, lowercase.
+const BUILTIN: &[&str] = &[
+    "googlebot",
+    "bingbot",
+    "yandexbot",
+    "baiduspider",
+    "duckduckbot",
+    "slurp",
+    "applebot",
+    "petalbot",
+    "semrushbot",
+    "ahrefsbot",
+    "mj12bot",
+    "dotbot",
+    "facebookexternalhit",
+    "twitterbot",
+    "linkedinbot",
+    "discordbot",
+    "slackbot",
+    "telegrambot",
+    "uptimerobot",
+    "pingdom",
+    "statuscake",
+    "site24x7",
+    "headlesschrome",
+    "crawler",
+    "spider",
+    "bot/",
+];
+
+/// Recognizes bots by their `User-Agent`, so they don't inflate the counts.
+pub struct Bots {
+    patterns: Vec<String>,
+}
+
+impl Bots {
+    /// The built-in crawlers plus `extra` patterns, matched case-insensitively
+    /// anywhere in the `User-Agent`.
+    pub fn new(extra: &[String]) -> Self {
+        let patterns = BUILTIN
+            .iter()
+            .map(|p| p.to_string())
+            .chain(extra.iter().map(|p| p.to_lowercase()))
+            .collect();
+        Self { patterns }
+    }
+
+    pub fn matches(&self, user_agent: &str) -> bool {
+        let user_agent = user_agent.to_lowercase();
+        self.patterns
+            .iter()
+            .any(|p| user_agent.contains(p.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognizes_crawlers_regardless_of_case() {
+        let bots = Bots::new(&["My-Monitor".to_string()]);
+
+        assert!(bots
+            .matches("Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)"));
+        assert!(bots.matches("my-monitor/1.0"));
+        assert!(
+            !bots.matches("Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0")
+        );
+    }
+
+    #[test]
+    fn counts_command_line_clients() {
+        let bots = Bots::new(&[]);
+
+        for user_agent in [
+            "curl/8.5.0",
+            "Wget/1.21.4",
+            "python-requests/2.31.0",
+            "Go-http-client/1.1",
+        ] {
+            assert!(!bots.matches(user_agent), "{user_agent}");
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use std::ffi::OsString;
+use std::fs::read_to_string;
+use std::path::PathBuf;
+
+use anyhow::{bail, Context};
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, Command};
+use toml::{Table, Value};
+
+/// The table of settings for single counters, like `[counters.my-blog]`, holding
+/// the flags taking `<referer>=<value>`, like `goal = 10000` for `--goal`.
+const COUNTERS: &str = "counters";
+
+/// The subcommand taking the flags of the config, besides no subcommand at all.
+const SERVE: &str = "serve";
+
+/// Adds the settings of the `--config` file to the command line `args`, as the
+/// flags they stand for, e.g. `font-family = "serif"` for `--font-family serif`.
+/// Flags given on the command line, or in their environment variable, win over
+/// the file.
+pub fn args(command: Command, args: Vec<OsString>) -> anyhow::Result<Vec<OsString>> {
+    // Only to find the file and what's given already, the rest is checked later.
+    let matches = command.clone().ignore_errors(true).get_matches_from(&args);
+    // `serve` takes the same flags as no subcommand, after its name.
+    let (command, matches, at) = match matches.subcommand() {
+        Some((SERVE, serve)) => {
+            let at = args
+                .iter()
+                .position(|arg| arg == SERVE)
+                .map_or(1, |at| at + 1);
+            let command = command.find_subcommand(SERVE).expect("parsed").clone();
+            (command, serve.clone(), at)
+        }
+        _ => (command, matches, 1),
+    };
+    let Some(path) = matches.get_one::<PathBuf>("config") else {
+        return Ok(args);
+    };
+    let settings: Table = read_to_string(path)
+        .with_context(|| format!("Can't read the config {path:?}"))?
+        .parse()
+        .with_context(|| format!("Can't parse the config {path:?}"))?;
+
+    let mut flags = Vec::new();
+    let mut positional = Vec::new();
+    for (key, value) in &settings {
+        if key == COUNTERS {
+            let Value::Table(counters) = value else {
+                bail!("`{COUNTERS}` of the config {path:?} has to be a table of counters");
+            };
+            for (referer, settings) in counters {
+                let Value::Table(settings) = settings else {
+                    bail!("`{COUNTERS}.{referer}` of the config {path:?} has to be a table");
+                };
+                for (key, value) in settings {
+                    let flag = flag(&command, key)
+                        .with_context(|| format!("Can't use the config {path:?}"))?;
+                    let value = scalar(value).with_context(|| {
+                        format!("Can't use `{COUNTERS}.{referer}.{key}` of the config {path:?}")
+                    })?;
+                    flags.push(OsString::from(format!("{flag}={referer}={value}")));
+                }
+            }
+            continue;
+        }
+
+        let Some(arg) = find(&command, key) else {
+            bail!("Unknown setting `{key}` in the config {path:?}");
+        };
+        let id = arg.get_id().as_str();
+        if id == "config" {
+            bail!("The config {path:?} can't name another one");
+        }
+        if let Some(ValueSource::CommandLine | ValueSource::EnvVariable) = matches.value_source(id)
+        {
+            continue;
+        }
+
+        match (arg.get_long(), value) {
+            (Some(long), Value::Boolean(on)) if matches!(arg.get_action(), ArgAction::SetTrue) => {
+                if *on {
+                    flags.push(OsString::from(format!("--{long}")));
+                }
+            }
+            (Some(_), _) if matches!(arg.get_action(), ArgAction::SetTrue) => {
+                bail!("`{key}` of the config {path:?} has to be true or false");
+            }
+            (long, value) => {
+                let values = values(value)
+                    .with_context(|| format!("Can't use `{key}` of the config {path:?}"))?;
+                match long {
+                    Some(long) => flags.extend(
+                        values
+                            .into_iter()
+                            .map(|value| OsString::from(format!("--{long}={value}"))),
+                    ),
+                    None => positional.extend(values.into_iter().map(OsString::from)),
+                }
+            }
+        }
+    }
+
+    // Where flags go: after the name of the program, or of `serve`.
+    let mut args = args.into_iter();
+    let mut with_config: Vec<OsString> = args.by_ref().take(at).collect();
+    with_config.extend(flags);
+    with_config.extend(positional);
+    with_config.extend(args);
+    Ok(with_config)
+}
+
+/// The argument of the setting `key`, named like its flag, e.g. `alias` for
+/// `--alias`, or like the positional argument, e.g. `template`.
+fn find<'a>(command: &'a Command, key: &str) -> Option<&'a Arg> {
+    let id = key.replace('-', "_");
+    command
+        .get_arguments()
+        .find(|arg| arg.get_long() == Some(key))
+        .or_else(|| {
+            command
+                .get_arguments()
+                .find(|arg| arg.get_long().is_none() && arg.get_id() == id.as_str())
+        })
+}
+
+/// The flag of the setting `key` of a counter, like `--goal` for `goal`.
+fn flag(command: &Command, key: &str) -> anyhow::Result<String> {
+    find(command, key)
+        .and_then(|arg| arg.get_long())
+        .map(|long| format!("--{long}"))
+        .ok_or_else(|| anyhow::anyhow!("Unknown setting `{key}` of a counter"))
+}
+
+/// The values `value` stands for on the command line: one for a string or number,
+/// one per item of an array, and `<key>=<value>` for each entry of a table, like
+/// `[template-map]` with `"example.com" = "dark.html"`.
+fn values(value: &Value) -> anyhow::Result<Vec<String>> {
+    match value {
+        Value::Array(items) => items.iter().map(scalar).collect(),
+        Value::Table(entries) => entries
+            .iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    // Like `--namespace blog=blog.example.com,*.blog.example.com`.
+                    Value::Array(items) => items
+                        .iter()
+                        .map(scalar)
+                        .collect::<anyhow::Result<Vec<_>>>()?
+                        .join(","),
+                    value => scalar(value)?,
+                };
+                Ok(format!("{key}={value}"))
+            })
+            .collect(),
+        value => Ok(vec![scalar(value)?]),
+    }
+}
+
+fn scalar(value: &Value) -> anyhow::Result<String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Integer(n) => Ok(n.to_string()),
+        Value::Float(n) => Ok(n.to_string()),
+        Value::Boolean(b) => Ok(b.to_string()),
+        Value::Datetime(at) => Ok(at.to_string()),
+        Value::Array(_) | Value::Table(_) => bail!("expected a string, number or boolean"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::FromArgMatches;
+
+    use super::*;
+    use crate::Args;
+
+    /// Parses the command line `cli` with `config` as the `--config` file.
+    fn parse(name: &str, config: &str, cli: &[&str]) -> Args {
+        let path =
+            std::env::temp_dir().join(format!("itc-config-{name}-{}.toml", std::process::id()));
+        std::fs::write(&path, config).unwrap();
+        let mut argv = vec![
+            OsString::from("itc"),
+            OsString::from("--config"),
+            path.clone().into(),
+        ];
+        argv.extend(cli.iter().map(OsString::from));
+
+        let command = Args::command_with_env();
+        let argv = args(command.clone(), argv).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        Args::from_arg_matches(&command.try_get_matches_from(argv).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn command_line_wins_over_env_over_file() {
+        let config = r#"font-size = "small""#;
+        assert_eq!(parse("scalar", config, &[]).serve.font_size, "small");
+
+        std::env::set_var("ITC_FONT_SIZE", "large");
+        let from_env = parse("scalar", config, &[]).serve.font_size;
+        let from_cli = parse("scalar", config, &["--font-size", "14px"])
+            .serve
+            .font_size;
+        std::env::remove_var("ITC_FONT_SIZE");
+
+        assert_eq!(from_env, "large");
+        assert_eq!(from_cli, "14px");
+    }
+
+    #[test]
+    fn arrays_give_a_flag_several_times() {
+        let config = r#"ip = ["127.0.0.1:1", "[::1]:2"]"#;
+        let ports = |args: Args| args.serve.ip.iter().map(|ip| ip.port()).collect::<Vec<_>>();
+
+        assert_eq!(ports(parse("vec", config, &[])), [1, 2]);
+        assert_eq!(ports(parse("vec", config, &["--ip", "127.0.0.1:3"])), [3]);
+    }
+
+    #[test]
+    fn counters_set_goals_and_offsets() {
+        let config = r#"
+            [counters."https://example.com/"]
+            goal = 10000
+            offset = 150
+
+            [counters.my-blog]
+            goal = 500
+        "#;
+        let parsed = parse("counters", config, &[]);
+
+        let mut goals = parsed.serve.goals.clone();
+        goals.sort();
+        assert_eq!(
+            goals,
+            [
+                (String::from("https://example.com/"), 10000),
+                (String::from("my-blog"), 500),
+            ]
+        );
+        assert_eq!(
+            parsed.serve.offsets,
+            [(String::from("https://example.com/"), 150)]
+        );
+
+        let path = std::env::temp_dir().join(format!("itc-config-bad-{}.toml", std::process::id()));
+        std::fs::write(&path, "[counters.my-blog]\ngaol = 500\n").unwrap();
+        let argv = vec![
+            OsString::from("itc"),
+            OsString::from("--config"),
+            path.clone().into(),
+        ];
+        let err = args(Args::command_with_env(), argv).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(
+            format!("{err:#}").contains("Unknown setting `gaol`"),
+            "{err:#}"
+        );
+    }
+}
@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use sha2::{Digest, Sha256};
+
+/// Remembers which clients were counted for which referer recently, so a
+/// visitor refreshing the page counts only once per window.
+///
+/// Clients are only kept as a salted hash of their address and the referer.
+/// The salt is random and never leaves memory, so the hashes can't be matched
+/// back to addresses.
+pub struct Dedup {
+    window: Duration,
+    salt: [u8; 32],
+    seen: Mutex<Seen>,
+}
+
+struct Seen {
+    /// When each client was last counted for a referer.
+    counted: HashMap<[u8; 16], Instant>,
+    last_sweep: Instant,
+}
+
+impl Dedup {
+    pub fn new(window: Duration) -> Self {
+        let mut salt = [0; 32];
+        OsRng.fill_bytes(&mut salt);
+
+        Self {
+            window,
+            salt,
+            seen: Mutex::new(Seen {
+                counted: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+        }
+    }
+
+    /// Whether `client` was already counted for `referer` within the window.
+    /// If not, this visit is remembered as counted.
+    pub fn repeated(&self, client: IpAddr, referer: &str) -> bool {
+        let mut hasher = Sha256::new();
+        hasher.update(self.salt);
+        hasher.update(client.to_string());
+        hasher.update([0]);
+        hasher.update(referer);
+        let key: [u8; 16] = hasher.finalize()[..16].try_into().unwrap();
+
+        let now = Instant::now();
+        let mut seen = self.seen.lock().unwrap();
+
+        // Expired clients are dropped once per window, so the map doesn't grow forever.
+        if now.duration_since(seen.last_sweep) >= self.window {
+            seen.counted
+                .retain(|_, counted| now.duration_since(*counted) < self.window);
+            seen.last_sweep = now;
+        }
+
+        match seen.counted.get(&key) {
+            Some(counted) if now.duration_since(*counted) < self.window => true,
+            _ => {
+                seen.counted.insert(key, now);
+                false
+            }
+        }
+    }
+}
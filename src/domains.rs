@@ -0,0 +1,149 @@
+use std::str::FromStr;
+
+use regex::Regex;
+use url::Url;
+
+/// Which sites may count visits, by the host of the page embedding the counter.
+pub struct Domains {
+    allow: Vec<DomainPattern>,
+    deny: Vec<DomainPattern>,
+    /// Shown the counter without counting, like the site owner's own test pages.
+    own: Vec<DomainPattern>,
+    /// Whether pages on the counter's own host are also the owner's.
+    own_host: bool,
+}
+
+impl Domains {
+    pub fn new(
+        allow: Vec<DomainPattern>,
+        deny: Vec<DomainPattern>,
+        own: Vec<DomainPattern>,
+        own_host: bool,
+    ) -> Self {
+        Self {
+            allow,
+            deny,
+            own,
+            own_host,
+        }
+    }
+
+    /// Whether a visit from `page` counts. Denied hosts never do. Without an
+    /// allowlist everything else does, with one only URLs on a listed host.
+    pub fn allows(&self, page: Option<&str>) -> bool {
+        let host = page.and_then(host);
+        let listed = |patterns: &[DomainPattern]| {
+            host.as_deref()
+                .is_some_and(|host| patterns.iter().any(|p| p.matches(host)))
+        };
+        if listed(&self.deny) {
+            return false;
+        }
+        self.allow.is_empty() || listed(&self.allow)
+    }
+
+    /// Whether `page` is one of the owner's, so visiting it shouldn't count.
+    /// `counter_host` is the host the counter itself was requested from.
+    pub fn is_own(&self, page: Option<&str>, counter_host: Option<&str>) -> bool {
+        let Some(host) = page.and_then(host) else {
+            return false;
+        };
+        let same_host = counter_host.is_some_and(|counter_host| {
+            counter_host
+                .trim_end_matches('.')
+                .eq_ignore_ascii_case(&host)
+        });
+        (self.own_host && same_host) || self.own.iter().any(|p| p.matches(&host))
+    }
+}
+
+/// An `--allow-domain` or `--deny-domain`: a host like `example.com`, every
+/// subdomain of one like `*.example.com`, or a regex like `/^blog\d*\.example\.com$/`.
+#[derive(Clone, Debug)]
+pub enum DomainPattern {
+    Exact(String),
+    Subdomains(String),
+    Regex(Regex),
+}
+
+impl DomainPattern {
+    pub fn matches(&self, host: &str) -> bool {
+        match self {
+            DomainPattern::Exact(domain) => host == domain,
+            DomainPattern::Subdomains(parent) => {
+                host.len() > parent.len() && host.ends_with(parent.as_str())
+            }
+            DomainPattern::Regex(regex) => regex.is_match(host),
+        }
+    }
+}
+
+impl FromStr for DomainPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(regex) = s.strip_prefix('/').and_then(|s| s.strip_suffix('/')) {
+            // Hosts are compared lowercase, so patterns shouldn't have to care.
+            return regex::RegexBuilder::new(regex)
+                .case_insensitive(true)
+                .build()
+                .map(DomainPattern::Regex)
+                .map_err(|err| format!("Invalid domain regex: {err}"));
+        }
+
+        let domain = s.trim_end_matches('.').to_lowercase();
+        match domain.strip_prefix("*.") {
+            Some(parent) if !parent.is_empty() => {
+                Ok(DomainPattern::Subdomains(format!(".{parent}")))
+            }
+            _ if domain.contains('*') => Err(format!(
+                "{s:?} can only start with `*.`, use `/regex/` for anything fancier"
+            )),
+            _ => Ok(DomainPattern::Exact(domain)),
+        }
+    }
+}
+
+/// The lowercase host of `page`, if it's a URL.
+pub fn host(page: &str) -> Option<String> {
+    let url = Url::parse(page).ok()?;
+    Some(url.host_str()?.trim_end_matches('.').to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(patterns: &[&str]) -> Vec<DomainPattern> {
+        patterns.iter().map(|p| p.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn denies_before_allowing() {
+        let domains = Domains::new(
+            patterns(&["*.example.com", "/^blog\\d*\\.net$/"]),
+            patterns(&["spam.example.com"]),
+            vec![],
+            false,
+        );
+
+        assert!(domains.allows(Some("https://www.Example.com./page")));
+        assert!(domains.allows(Some("https://blog2.net/")));
+        assert!(!domains.allows(Some("https://example.com/")));
+        assert!(!domains.allows(Some("https://spam.example.com/")));
+        assert!(!domains.allows(None));
+    }
+
+    #[test]
+    fn recognizes_own_pages() {
+        let domains = Domains::new(vec![], vec![], patterns(&["localhost"]), true);
+
+        assert!(domains.is_own(Some("http://localhost:8080/"), None));
+        assert!(domains.is_own(
+            Some("https://counter.example.com/"),
+            Some("Counter.example.com.")
+        ));
+        assert!(!domains.is_own(Some("https://example.com/"), Some("counter.example.com")));
+        assert!(!domains.is_own(None, Some("counter.example.com")));
+    }
+}
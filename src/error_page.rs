@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fs::read_to_string;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use anyhow::Context;
+use http_body_util::combinators::BoxBody;
+use hyper::body::Bytes;
+use hyper::header::HeaderValue;
+use hyper::{header, Response, StatusCode};
+
+use crate::admin;
+
+/// Bodies of error responses, by status. Statuses without a template are
+/// answered with the plain text message.
+pub struct ErrorPages {
+    paths: Vec<(StatusCode, PathBuf)>,
+    /// Replaced as a whole when they're reloaded.
+    pages: RwLock<HashMap<StatusCode, ErrorPage>>,
+}
+
+struct ErrorPage {
+    template: String,
+    json: bool,
+}
+
+impl ErrorPages {
+    /// Reads the template of every status. Templates ending in `.json` are sent
+    /// as JSON, anything else as HTML.
+    pub fn load(pages: &[(StatusCode, PathBuf)]) -> anyhow::Result<Self> {
+        Ok(Self {
+            paths: pages.to_vec(),
+            pages: RwLock::new(read(pages)?),
+        })
+    }
+
+    /// Reads the templates again, keeping the old ones if one can't be read.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        *self.pages.write().unwrap() = read(&self.paths)?;
+        Ok(())
+    }
+
+    /// Answers with `status`, filling `{{STATUS}}` and `{{MESSAGE}}` into its template.
+    pub fn response(
+        &self,
+        status: StatusCode,
+        message: &str,
+    ) -> hyper::http::Result<Response<BoxBody<Bytes, Infallible>>> {
+        let response = Response::builder().status(status);
+
+        let pages = self.pages.read().unwrap();
+        let Some(page) = pages.get(&status) else {
+            return response
+                .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+                .body(BoxBody::new(format!("{message}\n")));
+        };
+
+        let (content_type, message) = if page.json {
+            let quoted = serde_json::to_string(message).expect("strings always serialize");
+            ("application/json", quoted[1..quoted.len() - 1].to_string())
+        } else {
+            ("text/html; charset=utf-8", admin::escape(message))
+        };
+
+        let body = page
+            .template
+            .replace("{{STATUS}}", status.as_str())
+            .replace("{{MESSAGE}}", &message);
+
+        response
+            .header(header::CONTENT_TYPE, content_type)
+            .body(BoxBody::new(body))
+    }
+
+    /// Answers 500 for errors that were logged already, without their details.
+    pub fn internal(&self) -> hyper::http::Result<Response<BoxBody<Bytes, Infallible>>> {
+        self.response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Something went wrong on the counter's side, try again later.",
+        )
+    }
+
+    /// Answers 405 to a method the route doesn't take, listing those it does in `allow`.
+    pub fn method_not_allowed(
+        &self,
+        allow: &'static str,
+    ) -> hyper::http::Result<Response<BoxBody<Bytes, Infallible>>> {
+        let mut response = self.response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            &format!("This only answers {allow} requests."),
+        )?;
+        response
+            .headers_mut()
+            .insert(header::ALLOW, HeaderValue::from_static(allow));
+        Ok(response)
+    }
+}
+
+fn read(pages: &[(StatusCode, PathBuf)]) -> anyhow::Result<HashMap<StatusCode, ErrorPage>> {
+    let mut loaded = HashMap::new();
+    for (status, path) in pages {
+        let template = read_to_string(path)
+            .with_context(|| format!("Can't read the {status} error page {path:?}"))?;
+        let json = path.extension().is_some_and(|ext| ext == "json");
+        loaded.insert(*status, ErrorPage { template, json });
+    }
+    Ok(loaded)
+}
+
+/// Parses `--error-page` values like `404=not-found.html`.
+pub fn parse(s: &str) -> Result<(StatusCode, PathBuf), String> {
+    let (status, path) = s
+        .split_once('=')
+        .ok_or_else(|| format!("{s:?} isn't like `404=not-found.html`"))?;
+
+    let status = status
+        .parse::<u16>()
+        .ok()
+        .and_then(|code| StatusCode::from_u16(code).ok())
+        .filter(|status| status.is_client_error() || status.is_server_error())
+        .ok_or_else(|| format!("{status:?} isn't an error status"))?;
+
+    Ok((status, PathBuf::from(path)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    async fn body(response: Response<BoxBody<Bytes, Infallible>>) -> String {
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn keeps_the_allowed_methods() {
+        let pages = ErrorPages::load(&[]).unwrap();
+        let response = pages.method_not_allowed("GET, HEAD").unwrap();
+
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[header::ALLOW], "GET, HEAD");
+        assert_eq!(
+            body(response).await,
+            "This only answers GET, HEAD requests.\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn fills_in_json_templates() {
+        let path = std::env::temp_dir().join(format!("itc-error-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"status": {{STATUS}}, "error": "{{MESSAGE}}"}"#).unwrap();
+
+        let pages = ErrorPages::load(&[(StatusCode::INTERNAL_SERVER_ERROR, path.clone())]).unwrap();
+        let response = pages.internal().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        assert_eq!(
+            body(response).await,
+            r#"{"status": 500, "error": "Something went wrong on the counter's side, try again later."}"#
+        );
+    }
+}
@@ -0,0 +1,95 @@
+use std::fmt::Write;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use crate::storage::{self, Visits};
+
+/// Renders `visits` as CSV with a `referer,count` header, busiest referers
+/// first. With `exported_at`, every row also gets an `exported_at` column.
+pub fn csv(visits: &Visits, exported_at: Option<DateTime<Utc>>) -> String {
+    let rows = sorted(visits, None);
+
+    let exported_at = exported_at.map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, true));
+
+    let mut out = String::from("referer,count");
+    if exported_at.is_some() {
+        out.push_str(",exported_at");
+    }
+    out.push('\n');
+
+    for (referer, count) in rows {
+        write!(out, "{},{count}", escape(referer)).unwrap();
+        if let Some(exported_at) = &exported_at {
+            write!(out, ",{exported_at}").unwrap();
+        }
+        out.push('\n');
+    }
+
+    out
+}
+
+fn escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Renders `visits` as a table for terminals, busiest referers first, with each
+/// one's share of the total and the total below. Only the `limit` busiest are
+/// listed, but the total counts every referer.
+pub fn table(visits: &Visits, limit: Option<usize>) -> String {
+    let total = storage::total(visits);
+    let rows = sorted(visits, limit);
+
+    let footer = format!("total of {} referers", visits.len());
+    let width = rows
+        .iter()
+        .map(|(referer, _)| referer.chars().count())
+        .chain([footer.len(), "referer".len()])
+        .max()
+        .unwrap_or_default();
+    let count_width = total.to_string().len().max("visits".len());
+
+    let mut out = format!(
+        "{:width$}  {:>count_width$}  {:>6}\n",
+        "referer", "visits", "share"
+    );
+    for (referer, count) in &rows {
+        let share = if total == 0 {
+            0.0
+        } else {
+            **count as f64 * 100.0 / total as f64
+        };
+        writeln!(
+            out,
+            "{referer:width$}  {count:>count_width$}  {share:>5.1}%"
+        )
+        .unwrap();
+    }
+    writeln!(out, "{footer:width$}  {total:>count_width$}").unwrap();
+    out
+}
+
+/// Renders `visits` as JSON, `{"total": n, "referers": [{"referer": ..., "count": ...}]}`,
+/// busiest referers first. Only the `limit` busiest are listed, but the total
+/// counts every referer.
+pub fn json(visits: &Visits, limit: Option<usize>) -> String {
+    let referers: Vec<_> = sorted(visits, limit)
+        .into_iter()
+        .map(|(referer, count)| serde_json::json!({ "referer": referer, "count": count }))
+        .collect();
+    serde_json::json!({
+        "total": storage::total(visits),
+        "referers": referers,
+    })
+    .to_string()
+}
+
+fn sorted(visits: &Visits, limit: Option<usize>) -> Vec<(&String, &usize)> {
+    let mut rows: Vec<_> = visits.iter().collect();
+    rows.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+    rows.truncate(limit.unwrap_or(usize::MAX));
+    rows
+}
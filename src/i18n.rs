@@ -0,0 +1,84 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, read_to_string};
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::locale;
+
+/// The built-in translations, by language.
+const BUILT_IN: &[(&str, &str)] = &[
+    ("en", include_str!("../translations/en.json")),
+    ("de", include_str!("../translations/de.json")),
+    ("fr", include_str!("../translations/fr.json")),
+    ("es", include_str!("../translations/es.json")),
+    ("it", include_str!("../translations/it.json")),
+    ("nl", include_str!("../translations/nl.json")),
+    ("pt", include_str!("../translations/pt.json")),
+    ("pl", include_str!("../translations/pl.json")),
+];
+
+/// The text templates show around the numbers, like `{{LABELS.visits}}`, in every
+/// language there's a translation for.
+pub struct Labels {
+    /// By locale, like `de` or `pt-br`.
+    by_locale: BTreeMap<String, HashMap<String, String>>,
+}
+
+impl Labels {
+    /// The built-in translations, with those of the `<locale>.json` files in `dir`
+    /// added or put in their place, label by label.
+    pub fn load(dir: Option<&Path>) -> anyhow::Result<Self> {
+        let mut by_locale = BTreeMap::new();
+        for (language, json) in BUILT_IN {
+            let labels: HashMap<String, String> =
+                serde_json::from_str(json).expect("built-in translations are valid");
+            by_locale.insert(language.to_string(), labels);
+        }
+
+        if let Some(dir) = dir {
+            let entries = fs::read_dir(dir)
+                .with_context(|| format!("Can't read the translations {dir:?}"))?;
+            for entry in entries {
+                let path = entry
+                    .with_context(|| format!("Can't read the translations {dir:?}"))?
+                    .path();
+                if path.extension().is_none_or(|ext| ext != "json") {
+                    continue;
+                }
+                let Some(name) = path.file_stem().and_then(|name| name.to_str()) else {
+                    continue;
+                };
+                let locale = locale::parse(name)
+                    .map_err(|err| anyhow::anyhow!("Can't name the translation {path:?}: {err}"))?;
+                let json = read_to_string(&path)
+                    .with_context(|| format!("Can't read the translation {path:?}"))?;
+                let labels: HashMap<String, String> = serde_json::from_str(&json)
+                    .with_context(|| format!("Can't parse the translation {path:?}"))?;
+                by_locale.entry(locale).or_default().extend(labels);
+            }
+        }
+
+        Ok(Self { by_locale })
+    }
+
+    /// The labels for `locale`: its own, or else those of its language, or else
+    /// the English ones. Labels nobody translated to English are taken from any
+    /// language, so templates showing them always render.
+    pub fn get(&self, locale: &str) -> HashMap<&str, &str> {
+        let language = locale.split(['-', '_']).next().unwrap_or(locale);
+        let mut labels = HashMap::new();
+        let fallbacks = self.by_locale.values();
+        let preferred = [locale::DEFAULT, language, locale]
+            .into_iter()
+            .filter_map(|locale| self.by_locale.get(locale));
+        for translation in fallbacks.chain(preferred) {
+            labels.extend(
+                translation
+                    .iter()
+                    .map(|(name, text)| (name.as_str(), text.as_str())),
+            );
+        }
+        labels
+    }
+}
@@ -0,0 +1,168 @@
+use std::fs::File;
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use clap::ValueEnum;
+
+/// 5x7 sprites of the digits 0-9, one row per byte, most significant of the
+/// low 5 bits leftmost.
+const DIGITS: [[u8; 7]; 10] = [
+    [0x0e, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0e],
+    [0x04, 0x0c, 0x04, 0x04, 0x04, 0x04, 0x0e],
+    [0x0e, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1f],
+    [0x1f, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0e],
+    [0x02, 0x06, 0x0a, 0x12, 0x1f, 0x02, 0x02],
+    [0x1f, 0x10, 0x1e, 0x01, 0x01, 0x11, 0x0e],
+    [0x06, 0x08, 0x10, 0x1e, 0x11, 0x11, 0x0e],
+    [0x1f, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+    [0x0e, 0x11, 0x11, 0x0e, 0x11, 0x11, 0x0e],
+    [0x0e, 0x11, 0x11, 0x0f, 0x01, 0x02, 0x0c],
+];
+
+const SPRITE_WIDTH: usize = 5;
+const SPRITE_HEIGHT: usize = 7;
+/// Each sprite pixel becomes a square this many pixels wide.
+const SCALE: usize = 3;
+/// Space between a digit and the edges of its cell, in sprite pixels.
+const PADDING: usize = 1;
+
+/// Counts are padded with zeros to at least this many digits, like an odometer,
+/// unless `--digits` says otherwise.
+pub const MIN_DIGITS: usize = 6;
+
+type Rgb = [u8; 3];
+
+/// A transparent 1x1 GIF, for counting where nothing should be shown.
+pub const PIXEL: &[u8] = b"GIF89a\x01\x00\x01\x00\x80\x00\x00\x00\x00\x00\xff\xff\xff\x21\xf9\x04\x01\x00\x00\x00\x00\x2c\x00\x00\x00\x00\x01\x00\x01\x00\x00\x02\x02\x44\x01\x00\x3b";
+
+/// Looks of the counter image, picked with `?style=`.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CounterStyle {
+    /// White digits on black wheels, like a car odometer.
+    #[default]
+    Odometer,
+    /// Green digits on a dark LCD.
+    Lcd,
+    /// Red digits on a LED display.
+    Led,
+}
+
+impl CounterStyle {
+    /// Digit, cell background, and the frame between cells.
+    fn colors(self) -> (Rgb, Rgb, Rgb) {
+        match self {
+            CounterStyle::Odometer => ([0xf0, 0xf0, 0xf0], [0x10, 0x10, 0x10], [0x80, 0x80, 0x80]),
+            CounterStyle::Lcd => ([0x33, 0xff, 0x33], [0x0a, 0x20, 0x0a], [0x00, 0x00, 0x00]),
+            CounterStyle::Led => ([0xff, 0x20, 0x10], [0x20, 0x04, 0x00], [0x00, 0x00, 0x00]),
+        }
+    }
+}
+
+/// Renders `count` as a row of at least `min_digits` digit cells and encodes it as a PNG.
+pub fn png(count: usize, style: CounterStyle, min_digits: usize) -> anyhow::Result<Vec<u8>> {
+    let digits = format!("{count:0min_digits$}");
+    let (digit, cell, frame) = style.colors();
+
+    let cell_width = (SPRITE_WIDTH + 2 * PADDING) * SCALE;
+    let cell_height = (SPRITE_HEIGHT + 2 * PADDING) * SCALE;
+    // One pixel of frame around and between all cells.
+    let width = digits.len() * (cell_width + 1) + 1;
+    let height = cell_height + 2;
+
+    let mut pixels = vec![frame; width * height];
+
+    for (i, c) in digits.bytes().enumerate() {
+        let sprite = &DIGITS[(c - b'0') as usize];
+        let left = 1 + i * (cell_width + 1);
+
+        for y in 0..cell_height {
+            for x in 0..cell_width {
+                let (sx, sy) = (x / SCALE, y / SCALE);
+                let lit = (PADDING..PADDING + SPRITE_WIDTH).contains(&sx)
+                    && (PADDING..PADDING + SPRITE_HEIGHT).contains(&sy)
+                    && sprite[sy - PADDING] & (0x10 >> (sx - PADDING)) != 0;
+
+                pixels[(1 + y) * width + left + x] = if lit { digit } else { cell };
+            }
+        }
+    }
+
+    let mut out = Vec::new();
+    let mut encoder = ::png::Encoder::new(&mut out, width as u32, height as u32);
+    encoder.set_color(::png::ColorType::Rgb);
+    encoder.set_depth(::png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&pixels.concat())?;
+
+    Ok(out)
+}
+
+/// A `--digit-sprites` sheet: images of the digits 0 to 9 side by side, all
+/// equally wide, for drawing counts in any look, like pixel art.
+pub struct Sprites {
+    digit_width: usize,
+    height: usize,
+    /// RGBA, row by row.
+    sheet: Vec<[u8; 4]>,
+}
+
+impl Sprites {
+    /// Reads the sheet from a PNG file.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path).with_context(|| format!("Can't read the sprites {path:?}"))?;
+        let mut decoder = ::png::Decoder::new(file);
+        decoder.set_transformations(::png::Transformations::normalize_to_color8());
+        let mut reader = decoder
+            .read_info()
+            .with_context(|| format!("Can't decode the sprites {path:?}"))?;
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader
+            .next_frame(&mut buf)
+            .with_context(|| format!("Can't decode the sprites {path:?}"))?;
+        let data = &buf[..info.buffer_size()];
+
+        let sheet: Vec<[u8; 4]> = match info.color_type {
+            ::png::ColorType::Rgba => data.chunks(4).map(|p| [p[0], p[1], p[2], p[3]]).collect(),
+            ::png::ColorType::Rgb => data.chunks(3).map(|p| [p[0], p[1], p[2], 0xff]).collect(),
+            ::png::ColorType::GrayscaleAlpha => {
+                data.chunks(2).map(|p| [p[0], p[0], p[0], p[1]]).collect()
+            }
+            ::png::ColorType::Grayscale => data.iter().map(|&g| [g, g, g, 0xff]).collect(),
+            ::png::ColorType::Indexed => bail!("Can't decode the palette of the sprites {path:?}"),
+        };
+
+        let (width, height) = (info.width as usize, info.height as usize);
+        if width == 0 || width % 10 != 0 {
+            bail!("The sprites {path:?} should be 10 digits of equal width side by side, but are {width} pixels wide");
+        }
+        Ok(Self {
+            digit_width: width / 10,
+            height,
+            sheet,
+        })
+    }
+
+    /// Renders `count` as a row of at least `min_digits` sprites and encodes it as a PNG.
+    pub fn png(&self, count: usize, min_digits: usize) -> anyhow::Result<Vec<u8>> {
+        let digits = format!("{count:0min_digits$}");
+        let sheet_width = self.digit_width * 10;
+        let width = digits.len() * self.digit_width;
+
+        let mut pixels = vec![[0; 4]; width * self.height];
+        for (i, c) in digits.bytes().enumerate() {
+            let from = (c - b'0') as usize * self.digit_width;
+            let to = i * self.digit_width;
+            for y in 0..self.height {
+                let row = &self.sheet[y * sheet_width + from..][..self.digit_width];
+                pixels[y * width + to..][..self.digit_width].copy_from_slice(row);
+            }
+        }
+
+        let mut out = Vec::new();
+        let mut encoder = ::png::Encoder::new(&mut out, width as u32, self.height as u32);
+        encoder.set_color(::png::ColorType::Rgba);
+        encoder.set_depth(::png::BitDepth::Eight);
+        encoder.write_header()?.write_image_data(&pixels.concat())?;
+
+        Ok(out)
+    }
+}
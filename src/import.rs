@@ -0,0 +1,99 @@
+use std::io::Read;
+
+use anyhow::Context;
+use clap::ValueEnum;
+
+use crate::storage::Visits;
+
+/// Analytics products whose CSV exports can be imported.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImportSource {
+    /// A GoatCounter pageview export, one row per pageview.
+    Goatcounter,
+    /// A Plausible pages export, one row per page with its pageviews.
+    Plausible,
+}
+
+/// Reads an export into counters keyed like referers, i.e. full page URLs.
+///
+/// Pages are put under `site` (e.g. `https://example.com`). Plausible exports
+/// with a `hostname` column don't need one, GoatCounter exports always do.
+pub fn read(source: ImportSource, data: impl Read, site: Option<&str>) -> anyhow::Result<Visits> {
+    let mut reader = csv::Reader::from_reader(data);
+    let headers: Vec<String> = reader
+        .headers()?
+        .iter()
+        .map(|h| h.trim().to_lowercase())
+        .collect();
+
+    // The first of `names` that's present, so better columns can be preferred.
+    let column = |names: &[&str]| {
+        names
+            .iter()
+            .find_map(|name| headers.iter().position(|h| h == name))
+    };
+
+    let mut visits = Visits::default();
+
+    match source {
+        ImportSource::Goatcounter => {
+            let site = site.context("GoatCounter exports don't name the site, pass --site")?;
+
+            // The first header carries the export version, e.g. `2Path`.
+            let path = headers
+                .iter()
+                .position(|h| h.trim_start_matches(|c: char| c.is_ascii_digit()) == "path")
+                .context("No `Path` column, is this a GoatCounter export?")?;
+            let event = column(&["event"]);
+            let bot = column(&["bot"]);
+
+            for row in reader.records() {
+                let row = row?;
+                let is = |i: Option<usize>, f: fn(&str) -> bool| {
+                    i.and_then(|i| row.get(i)).map(f).unwrap_or(false)
+                };
+
+                if is(event, |v| v == "true") || is(bot, |v| !v.is_empty() && v != "0") {
+                    continue;
+                }
+
+                *visits.entry(key(site, &row[path])).or_insert(0) += 1;
+            }
+        }
+        ImportSource::Plausible => {
+            let path = column(&["page", "name"])
+                .context("No `page` or `name` column, is this a Plausible pages export?")?;
+            let host = column(&["hostname"]);
+            let count = column(&["pageviews", "visits", "visitors"])
+                .context("No `pageviews` column, is this a Plausible pages export?")?;
+
+            for row in reader.records() {
+                let row = row?;
+
+                let site = match (site, host.and_then(|i| row.get(i))) {
+                    (Some(site), _) => site.to_string(),
+                    (None, Some(host)) => format!("https://{host}"),
+                    (None, None) => {
+                        anyhow::bail!("This export has no `hostname` column, pass --site")
+                    }
+                };
+
+                let v: usize = row[count]
+                    .parse()
+                    .with_context(|| format!("Invalid pageview count {:?}", &row[count]))?;
+
+                *visits.entry(key(&site, &row[path])).or_insert(0) += v;
+            }
+        }
+    }
+
+    Ok(visits)
+}
+
+fn key(site: &str, path: &str) -> String {
+    format!(
+        "{}/{}",
+        site.trim_end_matches('/'),
+        path.trim_start_matches('/')
+    )
+}
@@ -0,0 +1,161 @@
+use std::convert::Infallible;
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Empty, StreamBody};
+use hyper::body::{Bytes, Frame};
+use hyper::header::HeaderValue;
+use hyper::{header, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde::Serialize;
+use tokio::sync::{broadcast, watch};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::frame::CloseFrame;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+use crate::error_page::ErrorPages;
+
+/// A counted visit, sent to everyone watching the counters live.
+#[derive(Serialize, Clone, Debug)]
+pub struct Hit {
+    pub referer: String,
+    pub count: usize,
+}
+
+/// How many hits a slow watcher may fall behind before it misses some.
+pub const CAPACITY: usize = 256;
+
+/// Idle event streams get a comment this often, so proxies don't time them out.
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+/// Streams every hit as a Server-Sent Event carrying `{"referer": ..., "count": ...}`,
+/// only those of `referer` if given, until `shutdown` is set.
+pub fn events(
+    hits: broadcast::Receiver<Hit>,
+    referer: Option<String>,
+    shutdown: watch::Receiver<bool>,
+) -> hyper::http::Result<Response<BoxBody<Bytes, Infallible>>> {
+    let state = (hits, referer, shutdown);
+    let stream =
+        futures_util::stream::unfold(state, |(mut hits, referer, mut shutdown)| async move {
+            let keep_alive = tokio::time::sleep(KEEP_ALIVE);
+            tokio::pin!(keep_alive);
+
+            let event = loop {
+                tokio::select! {
+                    hit = hits.recv() => match hit {
+                        Ok(hit) if referer.as_ref().is_none_or(|r| *r == hit.referer) => {
+                            let data = serde_json::to_string(&hit).expect("hits always serialize");
+                            break format!("data: {data}\n\n");
+                        }
+                        Ok(_) => {}
+                        Err(broadcast::error::RecvError::Lagged(missed)) => {
+                            log::warn!("Event stream fell behind, skipped {missed} hits");
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    },
+                    _ = &mut keep_alive => break String::from(":\n\n"),
+                    _ = stopped(&mut shutdown) => return None,
+                }
+            };
+
+            Some((
+                Ok(Frame::data(Bytes::from(event))),
+                (hits, referer, shutdown),
+            ))
+        });
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/event-stream")
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(BodyExt::boxed(StreamBody::new(stream)))
+}
+
+/// Upgrades `req` to a WebSocket that is sent the count of `referer`, first
+/// `count` and then every time it changes, as `{"referer": ..., "count": ...}`.
+/// It's closed once `shutdown` is set.
+pub fn websocket(
+    mut req: Request<hyper::body::Incoming>,
+    referer: String,
+    count: usize,
+    mut hits: broadcast::Receiver<Hit>,
+    mut shutdown: watch::Receiver<bool>,
+    error_pages: &ErrorPages,
+) -> hyper::http::Result<Response<BoxBody<Bytes, Infallible>>> {
+    let Some(key) = req.headers().get(header::SEC_WEBSOCKET_KEY) else {
+        return error_pages
+            .response(StatusCode::UPGRADE_REQUIRED, "This only speaks WebSocket.")
+            .map(|mut response| {
+                response
+                    .headers_mut()
+                    .insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+                response
+            });
+    };
+    let accept = derive_accept_key(key.as_bytes());
+
+    tokio::spawn(async move {
+        let upgraded = match hyper::upgrade::on(&mut req).await {
+            Ok(upgraded) => upgraded,
+            Err(err) => {
+                log::error!("Error upgrading to a WebSocket: {err:?}");
+                return;
+            }
+        };
+
+        let ws = WebSocketStream::from_raw_socket(TokioIo::new(upgraded), Role::Server, None).await;
+        let (mut tx, mut rx) = ws.split();
+
+        let first = Hit { referer, count };
+        if tx.send(message(&first)).await.is_err() {
+            return;
+        }
+        let referer = first.referer;
+
+        loop {
+            tokio::select! {
+                hit = hits.recv() => match hit {
+                    Ok(hit) if hit.referer == referer => {
+                        if tx.send(message(&hit)).await.is_err() {
+                            return;
+                        }
+                    }
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => return,
+                },
+                // Nothing is expected from the client, this only notices it leaving.
+                msg = rx.next() => match msg {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                    Some(Ok(_)) => {}
+                },
+                _ = stopped(&mut shutdown) => break,
+            }
+        }
+
+        let close = CloseFrame {
+            code: CloseCode::Away,
+            reason: "Shutting down".into(),
+        };
+        let _ = tx.send(Message::Close(Some(close))).await;
+    });
+
+    Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::CONNECTION, "upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header(header::SEC_WEBSOCKET_ACCEPT, accept)
+        .body(Empty::default().boxed())
+}
+
+/// Waits until `shutdown` is set, or its sender is gone.
+async fn stopped(shutdown: &mut watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|&shutdown| shutdown).await;
+}
+
+fn message(hit: &Hit) -> Message {
+    Message::text(serde_json::to_string(hit).expect("hits always serialize"))
+}
@@ -0,0 +1,72 @@
+/// The locale numbers are formatted for without `--locale` or `Accept-Language`.
+pub const DEFAULT: &str = "en";
+
+/// The locale of a page: `--locale` if it's set, or else the one the visitor's
+/// browser prefers most in `accept_language`.
+pub fn negotiate(locale: Option<&str>, accept_language: Option<&str>) -> String {
+    if let Some(locale) = locale {
+        return locale.to_string();
+    }
+
+    let mut preferred: Vec<(&str, f32)> = accept_language
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let tag = parts.next()?.trim();
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse().ok())?;
+            (parse(tag).is_ok() && quality > 0.0).then_some((tag, quality))
+        })
+        .collect();
+    // Stable, so equally preferred locales stay in the order they were listed.
+    preferred.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    preferred
+        .first()
+        .map_or(DEFAULT, |(tag, _)| tag)
+        .to_lowercase()
+}
+
+/// What `locale` puts between thousands, like `.` in `1.234.567`.
+pub fn separator(locale: &str) -> &'static str {
+    let locale = locale.to_lowercase();
+    let (language, region) = locale
+        .split_once(['-', '_'])
+        .unwrap_or((locale.as_str(), ""));
+    match (language, region) {
+        (_, "ch" | "li") => "\u{2019}",
+        ("fr", _) => "\u{202f}",
+        (
+            "da" | "de" | "el" | "es" | "hr" | "id" | "it" | "nl" | "pt" | "ro" | "sl" | "sr"
+            | "tr" | "vi",
+            _,
+        ) => ".",
+        (
+            "bg" | "cs" | "et" | "fi" | "hu" | "lt" | "lv" | "nb" | "nn" | "no" | "pl" | "ru"
+            | "sk" | "sv" | "uk",
+            _,
+        ) => "\u{a0}",
+        _ => ",",
+    }
+}
+
+/// What `locale` puts before decimals, like `,` in `1,5`.
+pub fn decimal(locale: &str) -> &'static str {
+    match separator(locale) {
+        "," | "\u{2019}" => ".",
+        _ => ",",
+    }
+}
+
+/// Parses `--locale` values like `de` or `pt-BR`.
+pub fn parse(s: &str) -> Result<String, String> {
+    let valid = !s.is_empty()
+        && s.split('-').all(|part| {
+            (1..=8).contains(&part.len()) && part.chars().all(|c| c.is_ascii_alphanumeric())
+        });
+    if !valid {
+        return Err(format!("{s:?} isn't a locale like `de` or `pt-BR`"));
+    }
+    Ok(s.to_lowercase())
+}
@@ -0,0 +1,70 @@
+use std::io::Write;
+
+use chrono::{SecondsFormat, Utc};
+use clap::ValueEnum;
+use env_logger::Env;
+use log::kv::{Error, Key, Value, VisitSource};
+use log::LevelFilter;
+use serde_json::Map;
+
+/// How log lines are written, picked with `--log-format`.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Lines for people, like `[2024-01-01T00:00:00Z INFO  iframe_traffic_counter] ...`.
+    #[default]
+    Text,
+    /// One JSON object per line, with the time, level and message, and fields like
+    /// `referer`, `status` and `latency_ms` where there are any, for log shippers.
+    Json,
+}
+
+/// The target of the lines `--access-log` writes for requests.
+pub const ACCESS: &str = "iframe_traffic_counter::access";
+
+/// Sets up logging to stderr, at `level`, or else the level of `RUST_LOG`, or else
+/// `info`. Requests are logged with `access_log`, at any level.
+pub fn init(format: LogFormat, level: Option<LevelFilter>, access_log: bool) {
+    let mut builder = match level {
+        Some(level) => {
+            let mut builder = env_logger::Builder::new();
+            builder.filter_level(level);
+            builder
+        }
+        None => env_logger::Builder::from_env(Env::default().default_filter_or("info")),
+    };
+    let access = if access_log {
+        LevelFilter::Trace
+    } else {
+        LevelFilter::Off
+    };
+    builder.filter_module(ACCESS, access);
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let mut line = Map::new();
+            line.insert(
+                "timestamp".into(),
+                Utc::now()
+                    .to_rfc3339_opts(SecondsFormat::Millis, true)
+                    .into(),
+            );
+            line.insert("level".into(), record.level().as_str().into());
+            line.insert("target".into(), record.target().into());
+            line.insert("message".into(), record.args().to_string().into());
+            // Fields can't fail to visit, they're only copied.
+            let _ = record.key_values().visit(&mut Fields(&mut line));
+            writeln!(buf, "{}", serde_json::Value::Object(line))
+        });
+    }
+    builder.init();
+}
+
+/// Copies the fields of a log line, like `status = 200`, into its JSON object.
+struct Fields<'a>(&'a mut Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+        let value = serde_json::to_value(&value).unwrap_or_else(|_| value.to_string().into());
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
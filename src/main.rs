@@ -1,111 +1,3057 @@
-use clap::Parser;
-use env_logger::Env;
+use clap::{Args as ClapArgs, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
 use std::collections::HashMap;
 use std::convert::Infallible;
 use std::fs::read_to_string;
-use std::io::SeekFrom;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
-use std::str::FromStr;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
+use anyhow::Context;
+use chrono::{SecondsFormat, TimeDelta, Utc};
 use http_body_util::combinators::BoxBody;
-use http_body_util::{BodyExt, Empty};
+use http_body_util::{BodyExt, Empty, Full, Limited};
 use hyper::body::Bytes;
-use hyper::server::conn::http1;
+use hyper::header::HeaderValue;
 use hyper::service::service_fn;
-use hyper::{header, Request, Response, StatusCode};
-use hyper_util::rt::TokioIo;
-use tokio::io::AsyncSeekExt;
-use tokio::io::AsyncWriteExt;
+use hyper::{header, Method, Request, Response, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use ipnet::IpNet;
+use log::LevelFilter;
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
 use tokio::net::TcpListener;
 use tokio::signal;
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::{broadcast, mpsc, watch, Semaphore};
 use tokio::time::interval;
 
-static DEFAULT_TEMPLATE: &str = include_str!("../example.html");
+use crate::admin::LastSeen;
+use crate::agent::Agents;
+use crate::audit::{Actor, AuditLog};
+use crate::bots::Bots;
+use crate::dedup::Dedup;
+use crate::domains::{DomainPattern, Domains};
+use crate::error_page::ErrorPages;
+use crate::i18n::Labels;
+use crate::image::{CounterStyle, Sprites};
+use crate::import::ImportSource;
+use crate::live::Hit;
+use crate::logging::LogFormat;
+use crate::merge::MergeStrategy;
+use crate::milestone::Milestones;
+use crate::namespace::{Named, Namespace};
+use crate::online::Online;
+use crate::paths::Paths;
+use crate::proxy::{ClientIp, Https};
+use crate::prune::Prune;
+use crate::referer::{Granularity, Referers};
+use crate::rollup::{Period, Series, TimeZone};
+use crate::storage::{
+    Backups, Cipher, FileOptions, S3Config, S3Sync, StorageFormat, VisitStore, Visits, WriteBehind,
+};
+use crate::template::{Templates, Theme};
+use crate::tls::Tls;
+
+mod admin;
+mod agent;
+mod audit;
+mod bots;
+mod config;
+mod dedup;
+mod domains;
+mod error_page;
+mod export;
+mod i18n;
+mod image;
+mod import;
+mod live;
+mod locale;
+mod logging;
+mod merge;
+mod milestone;
+mod namespace;
+mod online;
+mod paths;
+mod proxy;
+mod prune;
+mod referer;
+mod rollup;
+mod sparkline;
+mod storage;
+mod template;
+mod tls;
+mod top;
+mod unique;
 
 /// An iframe-based website traffic counter / server, written in Rust.
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true
+)]
 struct Args {
-    /// The address the server will bind to.
-    #[arg(long, default_value_t = String::from("127.0.0.1:32069"))]
-    ip: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Without a subcommand, the counter is served like with `serve`.
+    #[command(flatten)]
+    serve: ServeArgs,
+}
+
+/// Everything about serving the counter.
+#[derive(ClapArgs, Debug)]
+struct ServeArgs {
+    /// A TOML file of settings, named like the flags without their dashes, e.g.
+    /// `font-family = "serif"`. Flags given on the command line win over it.
+    /// On `SIGHUP` the colors, fonts, `--background`, `--locale`, `--translations`,
+    /// `--digits`, domain lists, bot patterns, `--respect-dnt`, `--cache-control`,
+    /// `--cors-origin` and goals are read from it again, the rest needs a restart.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// How to write log lines: for people, or as JSON for log shippers.
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// The least important log lines to write: off, error, warn, info, debug or
+    /// trace. Instead of `RUST_LOG`, or `info` without either.
+    #[arg(long)]
+    log_level: Option<LevelFilter>,
+
+    /// Write a line for every request, with its method, path, status, referer and
+    /// how long it took, whatever the `--log-level`.
+    #[arg(long)]
+    access_log: bool,
+
+    /// Load the config, templates, storage and other files, report every problem with
+    /// them, and exit without serving.
+    #[arg(long)]
+    check: bool,
+
+    /// The address the server will bind to. Can be given several times or comma
+    /// separated, e.g. for an IPv4 and an IPv6 address.
+    #[arg(long, default_value = "127.0.0.1:32069", value_delimiter = ',')]
+    ip: Vec<SocketAddr>,
+
+    /// PEM certificate chain to serve HTTPS with, instead of plain HTTP.
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of `--tls-cert`.
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Serve HTTPS for this domain with a certificate from Let's Encrypt, obtained and
+    /// renewed automatically. Needs the server reachable on port 443 of the domain.
+    /// Can be given several times.
+    #[arg(long, conflicts_with = "tls_cert")]
+    acme_domain: Vec<String>,
+
+    /// Email address Let's Encrypt may send expiry notices to.
+    #[arg(long, requires = "acme_domain")]
+    acme_contact: Vec<String>,
+
+    /// Directory to keep the ACME account and certificates in.
+    #[arg(long, default_value = "acme")]
+    acme_cache: PathBuf,
+
+    /// Use the Let's Encrypt staging environment, e.g. while testing a setup.
+    #[arg(long, requires = "acme_domain")]
+    acme_staging: bool,
+
+    /// The path to the HTML template used when serving the iframe.
+    /// See https://github.com/msparkles/iframe-traffic-counter/blob/main/example.html for example file.
+    #[arg()]
+    template: Option<PathBuf>,
+
+    /// The built-in template used without a `TEMPLATE`.
+    #[arg(long, value_enum, default_value_t = Theme::Plain, conflicts_with = "template")]
+    theme: Theme,
+
+    /// The path to the HTML template served at `/lazy`, which should only count the
+    /// visit once the counter is visible, by sending a beacon.
+    /// See https://github.com/msparkles/iframe-traffic-counter/blob/main/lazy.html for example file.
+    #[arg(long)]
+    lazy_template: Option<PathBuf>,
+
+    /// Reload the templates whenever one of their files changes, so they can be
+    /// tweaked without a restart. Changes that don't parse keep the old templates.
+    #[arg(long)]
+    watch_templates: bool,
+
+    /// A directory of templates an embed can pick with `?style=<name>`, for the
+    /// template `<name>.html`, besides the built-in ones.
+    #[arg(long)]
+    template_dir: Option<PathBuf>,
+
+    /// The path to a CSS file filled in as `{{CUSTOM_CSS}}`, which the built-in
+    /// templates include, to restyle them without a template of your own.
+    #[arg(long)]
+    css: Option<PathBuf>,
+
+    /// Whether to count visits per page, per site, or per path across all sites.
+    #[arg(long, value_enum, default_value_t = Granularity::Page)]
+    granularity: Granularity,
+
+    /// Count the visits of pages on one host as the same page on another, as
+    /// `<alias>=<host>`, e.g. `www.example.com=example.com`. Counters already on the
+    /// alias are merged into the other host's on startup. Can be given several times.
+    #[arg(long = "alias", value_parser = referer::parse_alias)]
+    aliases: Vec<(String, String)>,
+
+    /// Name counters after a hash of their referer salted with the secret in this
+    /// file, so referers are never stored. Counters named after a plain referer are
+    /// hashed on startup. Keep the salt, counters can't be found again without it.
+    #[arg(long, conflicts_with_all = ["referer_salt", "path_storage"])]
+    referer_salt_file: Option<PathBuf>,
+
+    /// Like `--referer-salt-file`, but the salt itself. Prefer the environment
+    /// variable, so the salt doesn't show up in the process list.
+    #[arg(
+        long,
+        env = "ITC_REFERER_SALT",
+        hide_env_values = true,
+        conflicts_with = "path_storage"
+    )]
+    referer_salt: Option<String>,
+
+    /// With `--granularity origin`, also count which pages of each site the visits
+    /// came from in this storage, as accepted by `--storage`.
+    #[arg(long)]
+    path_storage: Option<String>,
+
+    /// Also count the browser and OS families of the visitors of each counter in this
+    /// storage, as accepted by `--storage`. They're told apart coarsely by `User-Agent`.
+    #[arg(long)]
+    agent_storage: Option<String>,
+
+    /// Color of the text, in CSS color.
+    #[arg(long, default_value_t = String::from("white"), value_parser = template::parse_css)]
+    color: String,
+
+    /// Color of the text for visitors preferring a light color scheme, instead of
+    /// `--color`.
+    #[arg(long, value_parser = template::parse_css)]
+    color_light: Option<String>,
+
+    /// Color of the text for visitors preferring a dark color scheme, instead of
+    /// `--color`.
+    #[arg(long, value_parser = template::parse_css)]
+    color_dark: Option<String>,
+
+    /// Font of the text, in CSS font-family, e.g. `"Comic Sans MS", cursive`.
+    #[arg(long, default_value_t = String::from("monospace"), value_parser = template::parse_css)]
+    font_family: String,
+
+    /// Size of the text, in CSS font-size, e.g. `14px`.
+    #[arg(long, default_value_t = String::from("medium"), value_parser = template::parse_css)]
+    font_size: String,
+
+    /// Background of the counter, in CSS background.
+    #[arg(long, default_value_t = String::from("transparent"), value_parser = template::parse_css)]
+    background: String,
+
+    /// Format numbers and show labels for this locale, e.g. `de` for `1.234.567`
+    /// and `Besuche`, instead of the one the visitor's browser prefers.
+    #[arg(long, visible_alias = "lang", value_parser = locale::parse)]
+    locale: Option<String>,
+
+    /// A directory of translations of the labels, like `de.json` or `pt-BR.json`,
+    /// adding to or replacing the built-in ones.
+    #[arg(long)]
+    translations: Option<PathBuf>,
+
+    /// Pad counts with zeros to this many digits, like `0004210`, so the counter
+    /// keeps its width. Counter images are padded to 6 by default.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..=20))]
+    digits: Option<u64>,
+
+    /// The path to a PNG of the digits 0 to 9 side by side, all equally wide, to draw
+    /// `/counter.png` with instead of the built-in digits, e.g. for pixel art.
+    #[arg(long)]
+    digit_sprites: Option<PathBuf>,
+
+    #[command(flatten)]
+    storage: StorageArgs,
+
+    /// Also count unique visitors, recognized by a cookie, into this storage, as accepted
+    /// by `--storage`. Their count fills in `{{UNIQUE_COUNT}}` in the template.
+    #[arg(long)]
+    unique_storage: Option<String>,
+
+    /// Also count sessions into this storage, as accepted by `--storage`: visits of a
+    /// client to a page at most once per `--session-window`. Their count fills in
+    /// `{{SESSION_COUNT}}` in the template.
+    #[arg(long)]
+    session_storage: Option<String>,
+
+    /// How long a session lasts, in seconds.
+    #[arg(long, default_value_t = 1800, value_parser = clap::value_parser!(u64).range(1..))]
+    session_window: u64,
+
+    /// Also count visits per day into this storage, as accepted by `--storage`, for
+    /// `/api/history`.
+    #[arg(long)]
+    daily_storage: Option<String>,
+
+    /// Also count visits per hour into this storage, as accepted by `--storage`, for
+    /// `/api/history?period=hour`.
+    #[arg(long)]
+    hourly_storage: Option<String>,
+
+    /// Where days start for the daily and hourly counts and `{{TODAY_COUNT}}`: `utc`,
+    /// `local` for the time zone of the server, or an offset like `+02:00`.
+    #[arg(long, default_value = "utc")]
+    timezone: TimeZone,
+
+    /// How long hourly counts are kept, in hours.
+    #[arg(long, default_value_t = 24 * 30, value_parser = clap::value_parser!(u64).range(1..))]
+    hourly_retention: u64,
+
+    /// How long daily counts are kept, in days. They're kept forever by default.
+    #[arg(long, requires = "daily_storage", value_parser = clap::value_parser!(u64).range(1..))]
+    daily_retention: Option<u64>,
+
+    /// Count the clients that visited a page within this many minutes as online, for
+    /// `{{ONLINE_NOW}}`.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    online_window: Option<u64>,
+
+    /// Only count visits from pages on this domain, like `example.com`, and answer
+    /// anything else with 403. `*.example.com` allows every subdomain, `/regex/` any
+    /// matching host. Can be given several times. Counts every domain by default.
+    #[arg(long = "allow-domain")]
+    allow_domains: Vec<DomainPattern>,
+
+    /// Never count visits from pages on this domain, e.g. a referer spammer, and
+    /// answer them with 403. Takes the same patterns as `--allow-domain`. Can be
+    /// given several times.
+    #[arg(long = "deny-domain")]
+    deny_domains: Vec<DomainPattern>,
+
+    /// Don't count visits from pages on the counter's own host, e.g. its admin
+    /// dashboard or test pages served next to it. They still see the counter.
+    #[arg(long)]
+    exclude_self: bool,
+
+    /// Don't count visits from pages on this domain, e.g. `localhost` or a staging
+    /// site. They still see the counter. Takes the same patterns as `--allow-domain`.
+    /// Can be given several times.
+    #[arg(long = "exclude-domain")]
+    exclude_domains: Vec<DomainPattern>,
+
+    /// Don't count visits from browsers asking not to be tracked with `DNT: 1` or
+    /// `Sec-GPC: 1`. They still see the counter.
+    #[arg(long)]
+    respect_dnt: bool,
+
+    /// Don't count visits whose `User-Agent` contains this, on top of the built-in list
+    /// of crawlers and uptime monitors. Can be given several times.
+    #[arg(long = "bot-pattern", conflicts_with = "count_bots")]
+    bot_patterns: Vec<String>,
+
+    /// Count visits from bots like everyone else's.
+    #[arg(long)]
+    count_bots: bool,
+
+    /// Count the visits of bots into this storage instead, as accepted by `--storage`.
+    #[arg(long, conflicts_with = "count_bots")]
+    bot_storage: Option<String>,
+
+    /// Count repeated visits from the same client address to the same page only once
+    /// within this many seconds, e.g. when a visitor keeps refreshing.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    dedup_window: Option<u64>,
+
+    /// Directory to write a timestamped backup of all visits into on every periodic save.
+    #[arg(long)]
+    backup_dir: Option<PathBuf>,
+
+    /// Number of backups to keep in `--backup-dir`, at least 1, older ones are deleted.
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    backup_keep: u64,
+
+    /// Drop counters with fewer than N visits on every periodic save, e.g. to get rid
+    /// of spam referers that only show up once. Their unique visitors, sessions, bot
+    /// visits, history, paths and browsers are dropped with them.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    prune_below: Option<u64>,
+
+    /// Only drop counters of `--prune-below` that weren't counted for this many seconds,
+    /// so new referers get a chance to grow.
+    #[arg(long, requires = "prune_below")]
+    prune_older_than: Option<u64>,
+
+    #[command(flatten)]
+    sync: SyncArgs,
+
+    /// How often visits are saved, in seconds.
+    #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    save_interval: u64,
+
+    /// Also save visits once N visits happened since the last save, on top of `--save-interval`.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    save_every_n_hits: Option<u64>,
+
+    /// How long to let open connections finish their requests on shutdown, in seconds,
+    /// before closing them anyway.
+    #[arg(long, default_value_t = 30)]
+    shutdown_timeout: u64,
+
+    /// How long a client may take to send the headers of a request, in seconds, before
+    /// the connection is closed.
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    header_read_timeout: u64,
+
+    /// How long answering a request may take, in seconds, including reading its body.
+    /// Slower requests get a `408 Request Timeout`. Live update streams aren't limited.
+    #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+    request_timeout: u64,
+
+    /// How many connections may be open at once, across all `--ip` addresses. Further
+    /// clients wait until a connection closes.
+    #[arg(long, default_value_t = 1024, value_parser = clap::value_parser!(u64).range(1..))]
+    max_connections: u64,
+
+    /// Token guarding the `/admin` dashboard, sent as a bearer token or as the basic
+    /// auth password. Without one the dashboard is disabled.
+    #[arg(long, env = "ITC_ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
+
+    /// `Cache-Control` of the counting responses. Caching them stops counting the
+    /// cached visits and shows a stale count.
+    #[arg(long, default_value = "no-store", value_parser = HeaderValue::from_str)]
+    cache_control: HeaderValue,
+
+    /// Origin allowed to call the API from browsers, e.g. `https://example.com`, or `*`
+    /// for any. Can be given several times.
+    #[arg(long = "cors-origin", value_parser = HeaderValue::from_str)]
+    cors_origins: Vec<HeaderValue>,
+
+    /// Address or CIDR range of a reverse proxy whose `Forwarded` and `X-Forwarded-For`
+    /// headers name the real client, e.g. `127.0.0.1` or `10.0.0.0/8`. Can be given
+    /// several times.
+    #[arg(long = "trusted-proxy", value_parser = parse_net)]
+    trusted_proxies: Vec<IpNet>,
+
+    /// Template of the body sent with an error status, as `<status>=<path>`, e.g.
+    /// `404=not-found.html`. `{{STATUS}}` and `{{MESSAGE}}` are filled in, files ending in
+    /// `.json` are sent as JSON. Can be given several times.
+    #[arg(long = "error-page", value_parser = error_page::parse)]
+    error_pages: Vec<(StatusCode, PathBuf)>,
+
+    /// Starting count of a counter that has no visits yet, as `<referer>=<count>`,
+    /// e.g. `https://example.com/=150000` to carry over an old hit counter. Can be
+    /// given several times.
+    #[arg(long = "offset", value_parser = parse_offset)]
+    offsets: Vec<(String, usize)>,
+
+    /// Visits a counter is aiming for, as `<referer>=<count>`, e.g. `my-blog=10000`. A
+    /// template shows it with `{{GOAL}}`, and how far along it is with `{{PERCENT}}`.
+    /// Can be given several times.
+    #[arg(long = "goal", value_parser = parse_goal)]
+    goals: Vec<(String, usize)>,
+
+    /// Append every change made through the admin API to this file, as one JSON
+    /// object per line with who made it, when, and the counts before and after.
+    #[arg(long)]
+    audit_log: Option<PathBuf>,
+
+    /// Keep the counters of one site apart from the others, as
+    /// `<name>=<domain>,<domain>`, e.g. `blog=blog.example.com,*.blog.example.com`.
+    /// Its counters are stored as `<name>:<referer>`. Pages on its domains count
+    /// into it, as does anything under `/ns/<name>/`. Can be given several times.
+    #[arg(long = "namespace", value_parser = namespace::parse)]
+    namespaces: Vec<(String, Vec<DomainPattern>)>,
+
+    /// Template of a namespace's counters instead of the `TEMPLATE`, as
+    /// `<name>=<path>`. Can be given several times.
+    #[arg(long = "namespace-template", value_parser = namespace::parse_template)]
+    namespace_templates: Vec<(String, PathBuf)>,
+
+    /// Template of the counters embedded on a domain instead of the `TEMPLATE`, as
+    /// `<domain>=<path>`, e.g. `example.com=dark.html`. Domains are like those of
+    /// `--allow-domain`, the first one matching is used. Can be given several times.
+    #[arg(long = "template-map", value_parser = template::parse_map)]
+    template_map: Vec<(DomainPattern, PathBuf)>,
+
+    /// Post to this URL whenever a counter reaches a milestone, set with
+    /// `--milestone-every` or `--milestone`. The body is JSON like
+    /// `{"referer": "https://example.com/", "count": 1000}`.
+    #[arg(long)]
+    milestone_webhook: Option<String>,
+
+    /// Every multiple of this many visits is a milestone, e.g. 1000.
+    #[arg(long, requires = "milestone_webhook", value_parser = clap::value_parser!(u64).range(1..))]
+    milestone_every: Option<u64>,
+
+    /// This number of visits is a milestone. Can be given several times.
+    #[arg(long = "milestone", requires = "milestone_webhook")]
+    milestones: Vec<usize>,
+
+    /// File with the JSON body to post instead, with `{{REFERER}}` and `{{COUNT}}`
+    /// filled in, e.g. `{"content": "{{REFERER}} hit {{COUNT}} visits!"}`.
+    #[arg(long, requires = "milestone_webhook")]
+    milestone_payload: Option<PathBuf>,
+}
+
+/// Accepts plain addresses as single-address networks.
+fn parse_net(s: &str) -> Result<IpNet, String> {
+    s.parse()
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("{s:?} is neither an address nor a CIDR range"))
+}
+
+/// Splits at the last `=`, since referers may contain one themselves.
+fn parse_offset(s: &str) -> Result<(String, usize), String> {
+    let (referer, count) = s
+        .rsplit_once('=')
+        .filter(|(referer, _)| !referer.is_empty())
+        .ok_or_else(|| format!("{s:?} isn't like `example.com=150000`"))?;
+    let count = count
+        .parse()
+        .map_err(|_| format!("{count:?} isn't a count"))?;
+    Ok((referer.to_string(), count))
+}
+
+/// How far `visits` got towards `goal`, in whole percent up to 100. Computed
+/// wide enough for counters set near `usize::MAX`.
+fn percent(visits: usize, goal: usize) -> usize {
+    (visits as u128 * 100 / goal.max(1) as u128).min(100) as usize
+}
+
+fn parse_goal(s: &str) -> Result<(String, usize), String> {
+    let (referer, goal) = parse_offset(s)?;
+    if goal == 0 {
+        return Err(format!("{s:?} has no goal to reach"));
+    }
+    Ok((referer, goal))
+}
+
+impl Args {
+    /// The command line, with every argument of serving also read from an `ITC_*`
+    /// environment variable named after it, like `ITC_STORAGE` for `--storage`,
+    /// unless it has a variable of its own. The other subcommands only read the
+    /// variables their arguments name themselves.
+    fn command_with_env() -> clap::Command {
+        let with_env = |arg: clap::Arg| {
+            if arg.get_env().is_some() {
+                return arg;
+            }
+            let name = arg
+                .get_long()
+                .map_or_else(|| arg.get_id().to_string(), str::to_string);
+            let env = format!("ITC_{}", name.replace('-', "_").to_uppercase());
+            arg.env(env)
+        };
+        Args::command()
+            .mut_args(with_env)
+            .mut_subcommand("serve", |serve| serve.mut_args(with_env))
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Serves the counter, which is also what happens without a subcommand.
+    Serve(Box<ServeArgs>),
+
+    /// Prints every counter as `referer,count` CSV rows, busiest first.
+    Export {
+        #[command(flatten)]
+        storage: StorageArgs,
+
+        /// Write the CSV to this file instead of stdout.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Add an `exported_at` column with the time of the export.
+        #[arg(long)]
+        timestamps: bool,
+
+        /// Only export the counters of this `--namespace`, without its prefix.
+        #[arg(long)]
+        namespace: Option<String>,
+    },
+
+    /// Prints every counter as a table, busiest first, with the total below.
+    Stats {
+        #[command(flatten)]
+        storage: StorageArgs,
+
+        /// Print JSON instead of a table, e.g. for scripts.
+        #[arg(long)]
+        json: bool,
+
+        /// Only list this many of the busiest counters. The total still counts all.
+        #[arg(short = 'n', long)]
+        limit: Option<usize>,
+
+        /// Only list the counters of this `--namespace`, without its prefix.
+        #[arg(long)]
+        namespace: Option<String>,
+    },
+
+    /// Seeds the storage with the page counts from a GoatCounter or Plausible CSV export.
+    Import {
+        /// The CSV file to import.
+        file: PathBuf,
+
+        /// Which product the export comes from.
+        #[arg(long, value_enum)]
+        from: ImportSource,
+
+        /// The site the exported pages belong to, e.g. `https://example.com`. Needed
+        /// when the export doesn't name the host of each page.
+        #[arg(long)]
+        site: Option<String>,
+
+        /// How to combine imported counts with the ones already stored.
+        #[arg(long, value_enum, default_value_t = MergeStrategy::Sum)]
+        strategy: MergeStrategy,
+
+        #[command(flatten)]
+        storage: StorageArgs,
+    },
+
+    /// Combines several storages into one file, e.g. after migrating servers.
+    Merge {
+        /// The storages to merge, as accepted by `--storage`.
+        #[arg(required = true, num_args = 2..)]
+        inputs: Vec<String>,
+
+        /// The file to write the merged visits to.
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Layout of the input and output files.
+        #[arg(long, value_enum, default_value_t = StorageFormat::Text)]
+        storage_format: StorageFormat,
+
+        /// How to combine the counts of a referer found in several inputs.
+        #[arg(long, value_enum, default_value_t = MergeStrategy::Sum)]
+        strategy: MergeStrategy,
+
+        #[command(flatten)]
+        key: KeyArgs,
+    },
+}
+
+#[derive(ClapArgs, Debug)]
+struct StorageArgs {
+    /// Path to the visits storage file, `sqlite://<path>` or `sled://<path>` to use an embedded database,
+    /// `redis://<host>` to share counters through Redis, `postgres://<url>` to use PostgreSQL,
+    /// or `memory://` to not persist visits at all.
+    #[arg(long, default_value_t = String::from("visits.txt"))]
+    storage: String,
+
+    /// Layout of the visits storage file.
+    #[arg(long, value_enum, default_value_t = StorageFormat::Text)]
+    storage_format: StorageFormat,
+
+    /// Append every visit to a write-ahead log next to the storage file, so a crash
+    /// loses nothing. The log is compacted into the storage file once it's large enough.
+    #[arg(long)]
+    wal: bool,
+
+    /// Number of logged visits after which the write-ahead log is compacted.
+    #[arg(long, default_value_t = 10_000, value_parser = clap::value_parser!(u64).range(1..))]
+    wal_compact_after: u64,
+
+    /// Load a damaged storage file anyway, leaving out the lines that can't be
+    /// read. The original is kept next to it as `<file>.bad.bak`.
+    #[arg(long)]
+    skip_bad_lines: bool,
+
+    #[command(flatten)]
+    key: KeyArgs,
+}
+
+impl StorageArgs {
+    async fn open(&self) -> anyhow::Result<Arc<dyn VisitStore>> {
+        self.open_at(&self.storage).await
+    }
+
+    /// Opens another storage with the same options.
+    async fn open_at(&self, spec: &str) -> anyhow::Result<Arc<dyn VisitStore>> {
+        let store = storage::open(spec, self.file_options()?).await?;
+        store.load().await?;
+        Ok(store)
+    }
+
+    /// Reads another storage with the same options, without changing it.
+    async fn read_at(&self, spec: &str) -> anyhow::Result<Visits> {
+        storage::read(spec, self.file_options()?).await
+    }
+
+    fn file_options(&self) -> anyhow::Result<FileOptions> {
+        Ok(FileOptions {
+            format: self.storage_format,
+            wal_compact_after: self.wal.then_some(self.wal_compact_after),
+            cipher: self.key.cipher()?,
+            skip_bad_lines: self.skip_bad_lines,
+        })
+    }
+}
+
+#[derive(ClapArgs, Clone, Debug)]
+struct KeyArgs {
+    /// Encrypt the storage file, its write-ahead log and backups at rest with the
+    /// secret in this file. Existing plain files are encrypted on the next save.
+    #[arg(long, conflicts_with = "storage_key")]
+    storage_key_file: Option<PathBuf>,
+
+    /// Like `--storage-key-file`, but the secret itself. Prefer the environment
+    /// variable, so the secret doesn't show up in the process list.
+    #[arg(long, env = "ITC_STORAGE_KEY", hide_env_values = true)]
+    storage_key: Option<String>,
+}
+
+impl KeyArgs {
+    fn cipher(&self) -> anyhow::Result<Option<Arc<Cipher>>> {
+        if let Some(path) = &self.storage_key_file {
+            let secret = read_to_string(path)?;
+            return Ok(Some(Arc::new(Cipher::new(secret.trim_end().as_bytes()))));
+        }
+
+        Ok(self
+            .storage_key
+            .as_ref()
+            .map(|secret| Arc::new(Cipher::new(secret.as_bytes()))))
+    }
+}
+
+#[derive(ClapArgs, Debug)]
+struct SyncArgs {
+    /// Upload a copy of all visits to S3-compatible storage after every save, as
+    /// `s3://bucket/key`. If the storage file is missing on startup, it's restored from there.
+    #[arg(long)]
+    sync_url: Option<String>,
+
+    /// Endpoint of an S3-compatible service to use instead of AWS, e.g. `http://localhost:9000`.
+    #[arg(long, requires = "sync_url")]
+    sync_endpoint: Option<String>,
+
+    /// Region of the `--sync-url` bucket.
+    #[arg(long, env = "AWS_REGION", default_value_t = String::from("us-east-1"))]
+    sync_region: String,
+
+    /// Access key for `--sync-url`.
+    #[arg(long, env = "AWS_ACCESS_KEY_ID")]
+    sync_access_key: Option<String>,
+
+    /// Secret key for `--sync-url`.
+    #[arg(long, env = "AWS_SECRET_ACCESS_KEY", hide_env_values = true)]
+    sync_secret_key: Option<String>,
+
+    /// Session token for `--sync-url`, when using temporary credentials.
+    #[arg(long, env = "AWS_SESSION_TOKEN", hide_env_values = true)]
+    sync_session_token: Option<String>,
+}
+
+impl SyncArgs {
+    fn open(
+        &self,
+        format: StorageFormat,
+        cipher: Option<Arc<Cipher>>,
+    ) -> anyhow::Result<Option<S3Sync>> {
+        let Some(url) = &self.sync_url else {
+            return Ok(None);
+        };
+
+        let (Some(access_key), Some(secret_key)) = (&self.sync_access_key, &self.sync_secret_key)
+        else {
+            anyhow::bail!("--sync-url needs AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY to be set");
+        };
+
+        let config = S3Config {
+            url: url.clone(),
+            endpoint: self.sync_endpoint.clone(),
+            region: self.sync_region.clone(),
+            access_key: access_key.clone(),
+            secret_key: secret_key.clone(),
+            session_token: self.sync_session_token.clone(),
+        };
+
+        Ok(Some(S3Sync::new(config, format, cipher)?))
+    }
+}
+
+/// Everything a connection task needs to answer requests.
+struct State {
+    /// Replaced as a whole when they're reloaded.
+    templates: RwLock<Arc<Templates>>,
+    /// Replaced as a whole when they're reloaded.
+    settings: RwLock<Arc<Settings>>,
+    /// The `--digit-sprites`, for images without a `?style=`.
+    sprites: Option<Sprites>,
+    store: Arc<dyn VisitStore>,
+    /// Counts every visitor once per referer, with `--unique-storage`.
+    unique: Option<Arc<dyn VisitStore>>,
+    dedup: Option<Dedup>,
+    /// Counts the visits of bots, with `--bot-storage`.
+    bot_visits: Option<Arc<dyn VisitStore>>,
+    online: Option<Online>,
+    sessions: Option<Sessions>,
+    /// Visits per day and/or hour, daily first.
+    series: Vec<Series>,
+    /// Counts the pages of each site, with `--path-storage`.
+    paths: Option<Paths>,
+    /// Counts browsers and operating systems, with `--agent-storage`.
+    agents: Option<Agents>,
+    referers: Referers,
+    admin_token: Option<String>,
+    last_seen: LastSeen,
+    hits: broadcast::Sender<Hit>,
+    /// Set on shutdown, which ends the live streams.
+    shutdown: watch::Receiver<bool>,
+    trusted_proxies: Vec<IpNet>,
+    header_read_timeout: Duration,
+    request_timeout: Duration,
+    error_pages: ErrorPages,
+    audit_log: Option<AuditLog>,
+    namespaces: Vec<Namespace>,
+    milestones: Option<Arc<Milestones>>,
+}
+
+/// What's read again on `SIGHUP`, from the same command line, environment
+/// and `--config` file. Everything else needs a restart.
+struct Settings {
+    /// The `--color`, for templates and drawings.
+    color: String,
+    /// The `--color-light` and `--color-dark`, or else `--color`.
+    color_light: String,
+    color_dark: String,
+    font_family: String,
+    font_size: String,
+    background: String,
+    locale: Option<String>,
+    labels: Labels,
+    /// The `--digits`, for images and scripts.
+    digits: Option<usize>,
+    domains: Domains,
+    respect_dnt: bool,
+    bots: Option<Bots>,
+    cache_control: HeaderValue,
+    cors_origins: Vec<HeaderValue>,
+    /// By referer.
+    goals: HashMap<String, usize>,
+}
+
+impl Settings {
+    fn new(args: &ServeArgs, referers: &Referers) -> anyhow::Result<Self> {
+        Ok(Self {
+            color: args.color.clone(),
+            color_light: args
+                .color_light
+                .clone()
+                .unwrap_or_else(|| args.color.clone()),
+            color_dark: args
+                .color_dark
+                .clone()
+                .unwrap_or_else(|| args.color.clone()),
+            font_family: args.font_family.clone(),
+            font_size: args.font_size.clone(),
+            background: args.background.clone(),
+            locale: args.locale.clone(),
+            labels: Labels::load(args.translations.as_deref())?,
+            digits: args.digits.map(|digits| digits as usize),
+            domains: Domains::new(
+                args.allow_domains.clone(),
+                args.deny_domains.clone(),
+                args.exclude_domains.clone(),
+                args.exclude_self,
+            ),
+            respect_dnt: args.respect_dnt,
+            bots: (!args.count_bots).then(|| Bots::new(&args.bot_patterns)),
+            cache_control: args.cache_control.clone(),
+            cors_origins: args.cors_origins.clone(),
+            goals: args
+                .goals
+                .iter()
+                .map(|(referer, goal)| (referers.normalize(referer), *goal))
+                .collect(),
+        })
+    }
+}
+
+/// Counts visits at most once per client and `--session-window`, with `--session-storage`.
+struct Sessions {
+    store: Arc<dyn VisitStore>,
+    seen: Dedup,
+}
+
+/// The counters of one referer the template can show.
+struct Counts {
+    visits: usize,
+    /// With `--unique-storage`.
+    unique: Option<usize>,
+    /// With `--session-storage`.
+    sessions: Option<usize>,
+    /// With `--daily-storage` or `--hourly-storage`.
+    today: Option<usize>,
+    /// With `--online-window`.
+    online: Option<usize>,
+    /// With a `--goal` for the counter.
+    goal: Option<usize>,
+}
+
+/// Answers with `408 Request Timeout` if `handle` takes longer than allowed, so a
+/// stalled client can't hold on to a connection forever.
+async fn handle_in_time(
+    req: Request<hyper::body::Incoming>,
+    peer: IpAddr,
+    tls: bool,
+    state: Arc<State>,
+) -> hyper::http::Result<Response<BoxBody<Bytes, Infallible>>> {
+    let started = Instant::now();
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    // Named like its counter, so it's hashed with `--referer-salt`.
+    let referer = req
+        .headers()
+        .get(header::REFERER)
+        .and_then(|v| v.to_str().ok())
+        .map(|referer| state.referers.normalize(referer));
+
+    let timeout = state.request_timeout;
+    let response = match tokio::time::timeout(timeout, handle(req, peer, tls, state.clone())).await
+    {
+        Ok(response) => response,
+        Err(_) => {
+            log::debug!("Request from {peer} timed out");
+            state
+                .error_pages
+                .response(StatusCode::REQUEST_TIMEOUT, "The request took too long.")
+                .map(|mut response| {
+                    response
+                        .headers_mut()
+                        .insert(header::CONNECTION, HeaderValue::from_static("close"));
+                    response
+                })
+        }
+    };
+
+    if let Ok(response) = &response {
+        let status = response.status().as_u16();
+        let latency = started.elapsed();
+        log::info!(
+            target: logging::ACCESS,
+            method = method.as_str(),
+            path = path.as_str(),
+            status = status,
+            latency_ms = latency.as_secs_f64() * 1000.0,
+            referer = referer.as_deref();
+            "{method} {path} answered {status} in {latency:?}"
+        );
+    }
+    response
+}
+
+/// Adds the CORS headers for allowed origins, and answers preflight requests.
+async fn handle(
+    mut req: Request<hyper::body::Incoming>,
+    peer: IpAddr,
+    tls: bool,
+    state: Arc<State>,
+) -> hyper::http::Result<Response<BoxBody<Bytes, Infallible>>> {
+    let client = proxy::client_ip(peer, req.headers(), &state.trusted_proxies);
+    req.extensions_mut().insert(ClientIp(client));
+    let https = proxy::https(tls, peer, req.headers(), &state.trusted_proxies);
+    req.extensions_mut().insert(Https(https));
+
+    let settings = state.settings();
+    let origin = req.headers().get(header::ORIGIN).and_then(|origin| {
+        settings
+            .cors_origins
+            .iter()
+            .find(|allowed| *allowed == "*" || *allowed == origin)
+            .cloned()
+    });
+
+    let preflight = req.method() == Method::OPTIONS
+        && req
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+
+    let mut response = if preflight {
+        let response = Response::builder().status(StatusCode::NO_CONTENT);
+        match origin {
+            Some(_) => response
+                .header(
+                    header::ACCESS_CONTROL_ALLOW_METHODS,
+                    "GET, POST, PUT, DELETE, OPTIONS",
+                )
+                .header(
+                    header::ACCESS_CONTROL_ALLOW_HEADERS,
+                    "Authorization, Content-Type",
+                )
+                .header(header::ACCESS_CONTROL_MAX_AGE, "86400"),
+            None => response,
+        }
+        .body(Empty::default().boxed())?
+    } else {
+        route(req, state.clone()).await?
+    };
+
+    if !settings.cors_origins.is_empty() {
+        let headers = response.headers_mut();
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        if let Some(origin) = origin {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        }
+    }
+
+    Ok(response)
+}
+
+/// Only the counter paths count a visit, so stray requests like crawlers
+/// probing paths don't inflate the counts.
+async fn route(
+    mut req: Request<hyper::body::Incoming>,
+    state: Arc<State>,
+) -> hyper::http::Result<Response<BoxBody<Bytes, Infallible>>> {
+    let mut path = req.uri().path().to_string();
+
+    // `/ns/<name>/...` serves the counting routes of a namespace.
+    if let Some(rest) = path.strip_prefix("/ns/") {
+        let (name, rest) = match rest.split_once('/') {
+            Some((name, rest)) => (name, format!("/{rest}")),
+            None => (rest, String::from("/")),
+        };
+        let counting = matches!(
+            rest.as_str(),
+            "/" | "/count" | "/lazy" | "/counter.png" | "/pixel.gif" | "/beacon"
+        ) || rest.starts_with("/c/");
+        if !counting || !state.namespaces.iter().any(|ns| ns.name == name) {
+            return state
+                .error_pages
+                .response(StatusCode::NOT_FOUND, "No such namespace.");
+        }
+        req.extensions_mut().insert(Named(name.to_string()));
+        path = rest;
+    }
+
+    // HEAD is answered like GET, hyper leaves out the body.
+    match (req.method(), path.as_str()) {
+        (&Method::GET | &Method::HEAD, "/" | "/count") => match page_style(&req, &state) {
+            Ok(style) => count(&req, Shown::Page(style.as_deref()), None, &state).await,
+            Err(err) => state.error_pages.response(StatusCode::BAD_REQUEST, &err),
+        },
+        // Counts below-the-fold embeds only once they're scrolled to.
+        (&Method::GET | &Method::HEAD, "/lazy") => count(&req, Shown::Lazy, None, &state).await,
+        // For pages that strip iframes, the same counter is served as an image.
+        (&Method::GET | &Method::HEAD, "/counter.png") => match style(&req) {
+            Ok(style) => count(&req, Shown::Image(style), None, &state).await,
+            Err(err) => state.error_pages.response(StatusCode::BAD_REQUEST, &err),
+        },
+        // For email footers and Markdown, which can only embed images.
+        (&Method::GET | &Method::HEAD, "/pixel.gif") => {
+            count(&req, Shown::Pixel, None, &state).await
+        }
+        // Counters made on purpose, like `/c/guestbook`, named by the path instead.
+        (&Method::GET | &Method::HEAD, path)
+            if path.len() > "/c/".len() && path.starts_with("/c/") =>
+        {
+            let name = percent_decode_str(&path["/c/".len()..]).decode_utf8_lossy();
+            match page_style(&req, &state) {
+                Ok(style) => count(&req, Shown::Page(style.as_deref()), Some(&name), &state).await,
+                Err(err) => state.error_pages.response(StatusCode::BAD_REQUEST, &err),
+            }
+        }
+        (&Method::POST, "/beacon") => beacon(req, &state).await,
+        (&Method::GET | &Method::HEAD, "/favicon.ico") => Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .header(header::CACHE_CONTROL, "public, max-age=604800")
+            .body(Empty::default().boxed()),
+        // Crawlers that ask have no business counting visits.
+        (&Method::GET | &Method::HEAD, "/robots.txt") => Response::builder()
+            .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(BoxBody::new(String::from("User-agent: *\nDisallow: /\n"))),
+        (&Method::GET | &Method::HEAD, "/export.csv") => export_csv(&req, &state).await,
+        (&Method::GET | &Method::HEAD, "/top") => top(&req, false, &state).await,
+        (&Method::GET | &Method::HEAD, "/sparkline.svg") => sparkline(&req, &state).await,
+        (&Method::GET | &Method::HEAD, "/api/top") => top(&req, true, &state).await,
+        (_, "/api/counts") => api_counts(req, &state).await,
+        (_, path) if path.starts_with("/api/counts/") => api_counts(req, &state).await,
+        (&Method::GET | &Method::HEAD, path) if path.starts_with("/api/history/") => {
+            history(&req, &state).await
+        }
+        (&Method::GET | &Method::HEAD, path) if path.starts_with("/api/paths/") => {
+            api_paths(&req, &state).await
+        }
+        (&Method::GET | &Method::HEAD, path) if path.starts_with("/api/agents/") => {
+            api_agents(&req, &state).await
+        }
+        (&Method::GET | &Method::HEAD, path) if path.starts_with("/shield/") => {
+            let referer = percent_decode_str(&path["/shield/".len()..]).decode_utf8_lossy();
+            shield(&req, &state.referers.normalize(&referer), &state).await
+        }
+        (&Method::GET, "/ws") => live_counts(req, &state).await,
+        (&Method::GET, "/events") => live::events(
+            state.hits.subscribe(),
+            watched(&req, &state),
+            state.shutdown.clone(),
+        ),
+        (&Method::GET | &Method::HEAD, "/admin") => admin_page(&req, &state).await,
+        (
+            _,
+            "/" | "/count" | "/lazy" | "/counter.png" | "/pixel.gif" | "/favicon.ico"
+            | "/robots.txt" | "/export.csv" | "/top" | "/api/top" | "/sparkline.svg" | "/admin",
+        ) => state.error_pages.method_not_allowed("GET, HEAD"),
+        (_, path) if path.len() > "/c/".len() && path.starts_with("/c/") => {
+            state.error_pages.method_not_allowed("GET, HEAD")
+        }
+        (_, "/ws" | "/events") => state.error_pages.method_not_allowed("GET"),
+        (_, "/beacon") => state.error_pages.method_not_allowed("POST"),
+        _ => state.error_pages.response(
+            StatusCode::NOT_FOUND,
+            "Nothing here. The counter is served at `/`, or as an image at `/counter.png`.",
+        ),
+    }
+}
+
+/// How a counting request is answered.
+#[derive(Clone, Copy)]
+enum Shown<'a> {
+    /// The filled in template, or the one picked with `?style=`.
+    Page(Option<&'a str>),
+    /// The filled in `--lazy-template`, without counting. Its script counts the
+    /// visit with a beacon once the counter is visible.
+    Lazy,
+    /// The count as an image in the style picked with `?style=`, or else drawn with
+    /// the `--digit-sprites`.
+    Image(Option<CounterStyle>),
+    /// A transparent pixel, the count isn't shown at all.
+    Pixel,
+    /// The counts as JSON, for pages asking with `Accept: application/json`.
+    Json,
+    /// The count as plain text, for pages asking with `Accept: text/plain`.
+    Text,
+}
+
+impl Shown<'_> {
+    /// Pages are answered as JSON or plain text instead if `Accept` prefers them, so
+    /// scripts and shell one-liners can read the count from the same address.
+    fn negotiate<B>(self, req: &Request<B>) -> Self {
+        let Shown::Page(_) = self else {
+            return self;
+        };
+        let Some(accept) = req
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+        else {
+            return self;
+        };
+
+        let mut preferred: Option<(Self, f32)> = None;
+        for range in accept.split(',') {
+            let mut parts = range.split(';');
+            let shown = match parts.next().unwrap_or_default().trim() {
+                "text/html" | "application/xhtml+xml" => self,
+                "application/json" => Shown::Json,
+                "text/plain" => Shown::Text,
+                // Wildcards, like curl's `*/*`, get the page.
+                _ => continue,
+            };
+            let Some(quality) = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())
+            else {
+                continue;
+            };
+            // Equally preferred types go in the order they were listed.
+            if quality > 0.0 && preferred.is_none_or(|(_, best)| quality > best) {
+                preferred = Some((shown, quality));
+            }
+        }
+        preferred.map_or(self, |(shown, _)| shown)
+    }
+}
+
+/// Counts a visit for `key`, the `?key=` or else the `Referer`, and answers as `shown`.
+///
+/// `HEAD` requests, e.g. from uptime monitors, get the same response without
+/// counting a visit, even without a `Referer`.
+async fn count(
+    req: &Request<hyper::body::Incoming>,
+    shown: Shown<'_>,
+    key: Option<&str>,
+    state: &State,
+) -> hyper::http::Result<Response<BoxBody<Bytes, Infallible>>> {
+    let shown = shown.negotiate(req);
+    let page = req
+        .headers()
+        .get(header::REFERER)
+        .and_then(|v| v.to_str().ok());
+    let named = req
+        .extensions()
+        .get::<Named>()
+        .map(|Named(name)| name.as_str());
+    let namespace = namespace::select(&state.namespaces, named, page);
+    let referer = match counter_name(req, key, namespace, &state.referers) {
+        Ok(referer) => referer,
+        Err(err) => return state.error_pages.response(StatusCode::BAD_REQUEST, err),
+    };
+    let accept_language = req
+        .headers()
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|v| v.to_str().ok());
+    let settings = state.settings();
+    let locale = locale::negotiate(settings.locale.as_deref(), accept_language);
+
+    // Sites that aren't allowed don't get to see the count either.
+    if !settings.domains.allows(page) {
+        return state.error_pages.response(
+            StatusCode::FORBIDDEN,
+            "This counter doesn't count visits from this site.",
+        );
+    }
+
+    if req.method() == Method::HEAD || matches!(shown, Shown::Lazy) {
+        let counts = match peek(referer.as_deref(), state).await {
+            Ok(counts) => counts,
+            Err(err) => {
+                log::error!("Error reading visits for a HEAD request: {err:?}");
+                return state.error_pages.internal();
+            }
+        };
+        return counter(
+            &counts,
+            shown,
+            namespace,
+            page,
+            referer.as_deref(),
+            &locale,
+            state,
+        )
+        .await;
+    }
+
+    let Some(referer) = referer else {
+        return state.error_pages.response(
+            StatusCode::BAD_REQUEST,
+            "The request has no `Referer` header, so there's no page to count the visit for. \
+             Browsers leave it out when the embedding page sets `Referrer-Policy: no-referrer`, \
+             or when the counter is opened directly instead of embedded. \
+             Name the counter with `?key=` to not depend on it.",
+        );
+    };
+
+    let uncounted = (settings.respect_dnt && opted_out(req.headers()))
+        || settings.domains.is_own(page, counter_host(req));
+    if uncounted {
+        return match peek(Some(&referer), state).await {
+            Ok(counts) => {
+                counter(
+                    &counts,
+                    shown,
+                    namespace,
+                    page,
+                    Some(&referer),
+                    &locale,
+                    state,
+                )
+                .await
+            }
+            Err(err) => {
+                log::error!("Error reading visits without counting one: {err:?}");
+                state.error_pages.internal()
+            }
+        };
+    }
+
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok());
+    match bot_visit(user_agent, &referer, state).await {
+        Ok(false) => {}
+        // Bots still see the counter, they just don't count.
+        Ok(true) => match peek(Some(&referer), state).await {
+            Ok(counts) => {
+                return counter(
+                    &counts,
+                    shown,
+                    namespace,
+                    page,
+                    Some(&referer),
+                    &locale,
+                    state,
+                )
+                .await
+            }
+            Err(err) => {
+                log::error!("Error reading visits for a bot: {err:?}");
+                return state.error_pages.internal();
+            }
+        },
+        Err(err) => {
+            log::error!("Error counting a bot visit: {err:?}");
+            return state.error_pages.internal();
+        }
+    }
+
+    let ClientIp(client) = *req.extensions().get().expect("set by `handle`");
+    let Https(https) = *req.extensions().get().expect("set by `handle`");
+    let new_visitor = state.unique.is_some() && !unique::seen(req, &referer);
+    let cookie = new_visitor.then(|| unique::cookie(&referer, https));
+
+    let counted = async {
+        let unique = match &state.unique {
+            Some(unique) if new_visitor => Some(unique.increment(&referer).await?),
+            Some(unique) => Some(unique.get(&referer).await?),
+            None => None,
+        };
+        let visitor = Visitor {
+            client,
+            page,
+            user_agent,
+        };
+        let counts = record(&visitor, referer.clone(), state).await?;
+        anyhow::Ok(Counts { unique, ..counts })
+    };
+    let counts = match counted.await {
+        Ok(counts) => counts,
+        Err(err) => {
+            log::error!("Error counting visit: {err:?}");
+            return state.error_pages.internal();
+        }
+    };
+
+    let mut response = counter(
+        &counts,
+        shown,
+        namespace,
+        page,
+        Some(&referer),
+        &locale,
+        state,
+    )
+    .await?;
+    if let Some(cookie) = cookie {
+        response.headers_mut().append(header::SET_COOKIE, cookie);
+    }
+    Ok(response)
+}
+
+/// The host the counter was requested from, without the port.
+fn counter_host<B>(req: &Request<B>) -> Option<&str> {
+    let authority = match req.uri().host() {
+        Some(host) => host,
+        None => req.headers().get(header::HOST)?.to_str().ok()?,
+    };
+    // Bracketed IPv6 addresses have colons of their own.
+    match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => Some(host),
+        _ => Some(authority),
+    }
+}
+
+/// The counter `req` is for: `key`, the `?key=`, or else the `Referer`, in `namespace`.
+/// Names the storage can't hold are refused.
+fn counter_name<B>(
+    req: &Request<B>,
+    key: Option<&str>,
+    namespace: Option<&Namespace>,
+    referers: &Referers,
+) -> Result<Option<String>, &'static str> {
+    // An explicit key works even where `Referrer-Policy` strips the header.
+    let name = key
+        .map(str::to_string)
+        .or_else(|| {
+            form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+                .find(|(k, v)| k == "key" && !v.is_empty())
+                .map(|(_, v)| v.into_owned())
+        })
+        .or_else(|| {
+            req.headers()
+                .get(header::REFERER)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        });
+    if name
+        .as_deref()
+        .is_some_and(|name| !referer::valid_name(name))
+    {
+        return Err(BAD_NAME);
+    }
+
+    Ok(name
+        .map(|referer| referers.normalize(&referer))
+        .map(|referer| match namespace {
+            Some(namespace) => namespace.key(&referer),
+            None => referer,
+        }))
+}
+
+/// Why a counter name sent by a client was refused.
+const BAD_NAME: &str = "Counter names can't contain whitespace or control characters.";
+
+/// Whether the browser asks not to be tracked, with `DNT: 1` or Global Privacy Control.
+fn opted_out(headers: &header::HeaderMap) -> bool {
+    ["dnt", "sec-gpc"].into_iter().any(|name| {
+        headers
+            .get(name)
+            .is_some_and(|v| v.as_bytes().trim_ascii() == b"1")
+    })
+}
+
+/// Counts a visit from a bot into `--bot-storage` instead, returning whether it was one.
+async fn bot_visit(user_agent: Option<&str>, referer: &str, state: &State) -> anyhow::Result<bool> {
+    let settings = state.settings();
+    let (Some(bots), Some(user_agent)) = (&settings.bots, user_agent) else {
+        return Ok(false);
+    };
+    if !bots.matches(user_agent) {
+        return Ok(false);
+    }
+
+    log::debug!("Not counting a visit to {referer:?} from bot {user_agent:?}");
+    if let Some(bot_visits) = &state.bot_visits {
+        bot_visits.increment(referer).await?;
+    }
+    Ok(true)
+}
+
+/// The counters of `referer`, without counting a visit.
+async fn peek(referer: Option<&str>, state: &State) -> anyhow::Result<Counts> {
+    let Some(referer) = referer else {
+        return Ok(Counts {
+            visits: 0,
+            unique: state.unique.as_ref().map(|_| 0),
+            sessions: state.sessions.as_ref().map(|_| 0),
+            today: state.series.first().map(|_| 0),
+            online: state.online.as_ref().map(|_| 0),
+            goal: None,
+        });
+    };
+
+    let unique = match &state.unique {
+        Some(unique) => Some(unique.get(referer).await?),
+        None => None,
+    };
+    let sessions = match &state.sessions {
+        Some(sessions) => Some(sessions.store.get(referer).await?),
+        None => None,
+    };
+    Ok(Counts {
+        visits: state.store.get(referer).await?,
+        unique,
+        sessions,
+        today: today(referer, state).await?,
+        online: state.online.as_ref().map(|online| online.count(referer)),
+        goal: state.settings().goals.get(referer).copied(),
+    })
+}
+
+/// The visits of `referer` today, if they're counted per day or hour.
+async fn today(referer: &str, state: &State) -> anyhow::Result<Option<usize>> {
+    match state.series.first() {
+        Some(series) => Ok(Some(series.today(referer, Utc::now()).await?)),
+        None => Ok(None),
+    }
+}
+
+/// Who made a visit, as far as counting it goes.
+struct Visitor<'a> {
+    client: IpAddr,
+    /// The page embedding the counter.
+    page: Option<&'a str>,
+    user_agent: Option<&'a str>,
+}
+
+/// Counts one visit for `referer` and tells everyone watching, returning the new counts.
+/// Repeated visits within `--dedup-window` only return the counts.
+async fn record(visitor: &Visitor<'_>, referer: String, state: &State) -> anyhow::Result<Counts> {
+    let client = visitor.client;
+    let online = state
+        .online
+        .as_ref()
+        .map(|online| online.touch(client, &referer));
+
+    let sessions = match &state.sessions {
+        Some(sessions) if sessions.seen.repeated(client, &referer) => {
+            Some(sessions.store.get(&referer).await?)
+        }
+        Some(sessions) => Some(sessions.store.increment(&referer).await?),
+        None => None,
+    };
+    let goal = state.settings().goals.get(&referer).copied();
+
+    if state
+        .dedup
+        .as_ref()
+        .is_some_and(|dedup| dedup.repeated(client, &referer))
+    {
+        log::debug!("Repeated visit to {referer:?} from {client}, not counting it");
+        return Ok(Counts {
+            visits: state.store.get(&referer).await?,
+            unique: None,
+            sessions,
+            today: today(&referer, state).await?,
+            online,
+            goal,
+        });
+    }
+
+    log::debug!("Accepted referer: {referer:?} from {client}");
+
+    let visit = state
+        .store
+        .increment(&referer)
+        .await
+        .with_context(|| format!("Can't count a visit for {referer:?}"))?;
+
+    if let (Some(paths), Some(page)) = (&state.paths, visitor.page) {
+        paths
+            .increment(&referer, page, &state.referers)
+            .await
+            .with_context(|| format!("Can't count the path of a visit for {referer:?}"))?;
+    }
+    if let (Some(agents), Some(user_agent)) = (&state.agents, visitor.user_agent) {
+        agents
+            .increment(&referer, user_agent)
+            .await
+            .with_context(|| format!("Can't count the browser of a visit for {referer:?}"))?;
+    }
+
+    let now = Utc::now();
+    for series in &state.series {
+        series.increment(&referer, now).await.with_context(|| {
+            format!("Can't count a visit for {referer:?} in {}", series.store())
+        })?;
+    }
+
+    let today = today(&referer, state).await?;
+
+    if let Some(milestones) = &state.milestones {
+        if milestones.reached(visit) {
+            milestones.notify(&referer, visit);
+        }
+    }
+
+    state.last_seen.touch(&referer);
+    // Nobody watching isn't an error.
+    let _ = state.hits.send(Hit {
+        referer,
+        count: visit,
+    });
+
+    Ok(Counts {
+        visits: visit,
+        unique: None,
+        sessions,
+        today,
+        online,
+        goal,
+    })
+}
+
+/// What pages send to `/beacon`, as JSON or form fields.
+#[derive(Deserialize, Default)]
+struct Beacon {
+    page: Option<String>,
+    key: Option<String>,
+}
+
+impl Beacon {
+    /// Reads a beacon from `body`, refusing names the storage can't hold.
+    fn parse(body: &[u8]) -> Result<Self, String> {
+        // Beacons sent as a plain string arrive as `text/plain`, so look at the body instead.
+        let beacon = if body.trim_ascii_start().starts_with(b"{") {
+            serde_json::from_slice(body).map_err(|err| format!("Invalid beacon: {err}"))?
+        } else {
+            let mut beacon = Beacon::default();
+            for (k, v) in form_urlencoded::parse(body) {
+                match k.as_ref() {
+                    "page" => beacon.page = Some(v.into_owned()),
+                    "key" => beacon.key = Some(v.into_owned()),
+                    _ => {}
+                }
+            }
+            beacon
+        };
+
+        let names = [&beacon.page, &beacon.key];
+        if names
+            .into_iter()
+            .flatten()
+            .any(|name| !referer::valid_name(name))
+        {
+            return Err(BAD_NAME.to_string());
+        }
+        Ok(beacon)
+    }
+}
+
+/// Counts a visit sent with `navigator.sendBeacon`, e.g. when the page is left.
+/// The counter is named by `key`, else `page`, else the `Referer`.
+async fn beacon(
+    req: Request<hyper::body::Incoming>,
+    state: &State,
+) -> hyper::http::Result<Response<BoxBody<Bytes, Infallible>>> {
+    let ClientIp(client) = *req.extensions().get().expect("set by `handle`");
+    let settings = state.settings();
+    let opted_out = settings.respect_dnt && opted_out(req.headers());
+    let counter_host = counter_host(&req).map(str::to_string);
+    let named = req
+        .extensions()
+        .get::<Named>()
+        .map(|Named(name)| name.clone());
+    let referer = req
+        .headers()
+        .get(header::REFERER)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+
+    let body = match Limited::new(req.into_body(), 1024).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(_) => {
+            return state.error_pages.response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "The body can be 1 KiB at most.",
+            )
+        }
+    };
+
+    let beacon = match Beacon::parse(&body) {
+        Ok(beacon) => beacon,
+        Err(err) => return state.error_pages.response(StatusCode::BAD_REQUEST, &err),
+    };
+
+    // The page a beacon names is where it counts, `Referer` is only the fallback.
+    let page = beacon
+        .page
+        .clone()
+        .filter(|page| !page.is_empty())
+        .or(referer.clone());
+    if !settings.domains.allows(page.as_deref()) {
+        return state.error_pages.response(
+            StatusCode::FORBIDDEN,
+            "This counter doesn't count visits from this site.",
+        );
+    }
+
+    let own = settings
+        .domains
+        .is_own(page.as_deref(), counter_host.as_deref());
+    let namespace = namespace::select(&state.namespaces, named.as_deref(), page.as_deref());
+    let name = [beacon.key, beacon.page, referer]
+        .into_iter()
+        .flatten()
+        .find(|name| !name.is_empty());
+    if name
+        .as_deref()
+        .is_some_and(|name| !referer::valid_name(name))
+    {
+        return state
+            .error_pages
+            .response(StatusCode::BAD_REQUEST, BAD_NAME);
+    }
+    let Some(referer) = name
+        .map(|name| state.referers.normalize(&name))
+        .map(|referer| match namespace {
+            Some(namespace) => namespace.key(&referer),
+            None => referer,
+        })
+    else {
+        return state.error_pages.response(
+            StatusCode::BAD_REQUEST,
+            "The beacon names no page, send a `page` or `key` field.",
+        );
+    };
+
+    let counted = async {
+        if !opted_out && !own && !bot_visit(user_agent.as_deref(), &referer, state).await? {
+            let visitor = Visitor {
+                client,
+                page: page.as_deref(),
+                user_agent: user_agent.as_deref(),
+            };
+            record(&visitor, referer, state).await?;
+        }
+        anyhow::Ok(())
+    };
+    if let Err(err) = counted.await {
+        log::error!("Error counting a beacon: {err:?}");
+        return state.error_pages.internal();
+    }
+
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Empty::default().boxed())
+}
+
+/// Shows `counts` of the `referer` counter, embedded in `page`, as asked for by
+/// `shown`, with numbers for `locale`. Images only show the visits.
+async fn counter(
+    counts: &Counts,
+    shown: Shown<'_>,
+    namespace: Option<&Namespace>,
+    page: Option<&str>,
+    referer: Option<&str>,
+    locale: &str,
+    state: &State,
+) -> hyper::http::Result<Response<BoxBody<Bytes, Infallible>>> {
+    let settings = state.settings();
+    if let Shown::Pixel = shown {
+        return Response::builder()
+            .header(header::CONTENT_TYPE, "image/gif")
+            .header(header::CACHE_CONTROL, &settings.cache_control)
+            .body(Full::new(Bytes::from_static(image::PIXEL)).boxed());
+    }
+
+    if let Shown::Image(style) = shown {
+        let digits = settings.digits.unwrap_or(image::MIN_DIGITS);
+        let png = match (style, &state.sprites) {
+            (None, Some(sprites)) => sprites.png(counts.visits, digits),
+            (style, _) => image::png(counts.visits, style.unwrap_or_default(), digits),
+        };
+        return match png {
+            Ok(png) => Response::builder()
+                .header(header::CONTENT_TYPE, "image/png")
+                .header(header::CACHE_CONTROL, &settings.cache_control)
+                .body(Full::new(Bytes::from(png)).boxed()),
+            Err(err) => {
+                log::error!("Error rendering counter image: {err:?}");
+                state.error_pages.internal()
+            }
+        };
+    }
+
+    if let Shown::Json | Shown::Text = shown {
+        let (content_type, body) = match shown {
+            Shown::Json => {
+                let mut body = serde_json::json!({
+                    "referer": referer,
+                    "count": counts.visits,
+                });
+                let optional = [
+                    ("unique", counts.unique),
+                    ("sessions", counts.sessions),
+                    ("today", counts.today),
+                    ("online", counts.online),
+                    ("goal", counts.goal),
+                ];
+                for (name, count) in optional {
+                    if let Some(count) = count {
+                        body[name] = count.into();
+                    }
+                }
+                ("application/json", body.to_string())
+            }
+            _ => ("text/plain; charset=utf-8", format!("{}\n", counts.visits)),
+        };
+        return Response::builder()
+            .header(header::CONTENT_TYPE, content_type)
+            .header(header::CACHE_CONTROL, &settings.cache_control)
+            .header(header::VARY, "Accept")
+            .body(BoxBody::new(body));
+    }
+
+    let templates = state.templates.read().unwrap().clone();
+    let template = match shown {
+        Shown::Lazy => &templates.lazy,
+        Shown::Page(Some(style)) => match templates.style(style) {
+            Some(template) => template,
+            // Gone from the `--template-dir` since the request was checked.
+            None => &templates.main,
+        },
+        _ => templates.select(namespace.map(|namespace| namespace.name.as_str()), page),
+    };
+    // Going through every counter is only worth it for templates showing them.
+    let visits = if template.uses("TOTAL_ALL_SITES") || template.uses("TOP") {
+        match state.store.snapshot().await {
+            Ok(visits) => Some(visits),
+            Err(err) => {
+                log::error!("Error reading the visits of all sites: {err:?}");
+                return state.error_pages.internal();
+            }
+        }
+    } else {
+        None
+    };
+    // The page without its query, which may hold tokens or other secrets.
+    let page = page.map(|page| referer::normalize(page, Granularity::Page));
+
+    let mut context = tera::Context::new();
+    context.insert("LOCALE", locale);
+    context.insert("LABELS", &settings.labels.get(locale));
+    context.insert("DIGITS", &settings.digits);
+    context.insert("COLOR", &settings.color);
+    context.insert("COLOR_LIGHT", &settings.color_light);
+    context.insert("COLOR_DARK", &settings.color_dark);
+    context.insert("FONT_FAMILY", &settings.font_family);
+    context.insert("FONT_SIZE", &settings.font_size);
+    context.insert("BACKGROUND", &settings.background);
+    context.insert("CUSTOM_CSS", &templates.css);
+    context.insert("VISIT_COUNT", &counts.visits);
+    context.insert("HIT_COUNT", &counts.visits);
+    context.insert("UNIQUE_COUNT", &counts.unique);
+    context.insert("SESSION_COUNT", &counts.sessions);
+    context.insert("TODAY_COUNT", &counts.today);
+    context.insert(
+        "COUNT",
+        &serde_json::json!({
+            "hits": counts.visits,
+            "visits": counts.visits,
+            "unique": counts.unique,
+            "sessions": counts.sessions,
+            "today": counts.today,
+            "online": counts.online,
+        }),
+    );
+    context.insert("ONLINE_NOW", &counts.online);
+    context.insert("GOAL", &counts.goal);
+    context.insert(
+        "PERCENT",
+        &counts.goal.map(|goal| percent(counts.visits, goal)),
+    );
+    context.insert("REFERER_HOST", &page.as_deref().and_then(domains::host));
+    context.insert("REFERER", &page);
+    context.insert("COUNTER", &referer);
+    context.insert("TOTAL_ALL_SITES", &visits.as_ref().map(storage::total));
+    context.insert(
+        "TOP",
+        &visits
+            .map(|visits| top::leaders(visits, 10))
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(referer, count)| serde_json::json!({ "referer": referer, "count": count }))
+            .collect::<Vec<_>>(),
+    );
+    context.insert(
+        "UPDATED_AT",
+        &referer
+            .and_then(|referer| state.last_seen.get(referer))
+            .map(|at| at.to_rfc3339_opts(SecondsFormat::Secs, true)),
+    );
+
+    let html = match template.render(&context, locale) {
+        Ok(html) => html,
+        Err(err) => {
+            log::error!("Error rendering the counter template: {err:?}");
+            return state.error_pages.internal();
+        }
+    };
+
+    let mut response = Response::builder()
+        .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .header(header::CACHE_CONTROL, &settings.cache_control);
+    response = match (&shown, &settings.locale) {
+        (Shown::Page(_), None) => response.header(header::VARY, "Accept, Accept-Language"),
+        (Shown::Page(_), Some(_)) => response.header(header::VARY, "Accept"),
+        (_, None) => response.header(header::VARY, "Accept-Language"),
+        (_, Some(_)) => response,
+    };
+    response.body(BoxBody::new(html))
+}
+
+/// The `?style=` of a page, the name of one of its [`Templates`].
+fn page_style<B>(req: &Request<B>, state: &State) -> Result<Option<String>, String> {
+    let Some((_, name)) = form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+        .find(|(k, _)| k == "style")
+    else {
+        return Ok(None);
+    };
+    let templates = state.templates.read().unwrap();
+    if templates.style(&name).is_none() {
+        return Err(format!(
+            "Unknown style {name:?}, expected one of {}",
+            templates.style_names().join(", ")
+        ));
+    }
+    Ok(Some(name.into_owned()))
+}
+
+/// The `?style=` of an image, a [`CounterStyle`].
+fn style<B, S: ValueEnum>(req: &Request<B>) -> Result<Option<S>, String> {
+    form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+        .find(|(k, _)| k == "style")
+        .map(|(_, v)| {
+            S::from_str(&v, true).map_err(|_| {
+                let styles: Vec<_> = S::value_variants()
+                    .iter()
+                    .filter_map(|s| s.to_possible_value())
+                    .map(|s| s.get_name().to_string())
+                    .collect();
+                format!("Unknown style {v:?}, expected one of {}", styles.join(", "))
+            })
+        })
+        .transpose()
+}
+
+async fn export_csv(
+    req: &Request<hyper::body::Incoming>,
+    state: &State,
+) -> hyper::http::Result<Response<BoxBody<Bytes, Infallible>>> {
+    let visits = match state.store.snapshot().await {
+        Ok(visits) => visits,
+        Err(err) => {
+            log::error!("Error reading visits for export: {err:?}");
+            return state.error_pages.internal();
+        }
+    };
+
+    let query = || form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes());
+    let timestamps = query().any(|(k, v)| k == "timestamps" && v != "false" && v != "0");
+    let visits = match query().find(|(k, _)| k == "namespace") {
+        Some((_, name)) => namespace::scope(&visits, &name),
+        None => visits,
+    };
+
+    revalidated(
+        req.headers().get(header::IF_NONE_MATCH),
+        "text/csv; charset=utf-8",
+        export::csv(&visits, timestamps.then(Utc::now)),
+    )
+}
+
+/// `GET /top` shows the busiest referers as a page, `GET /api/top` as JSON. They're
+/// those of all time, or of the current `?period=` with `--daily-storage` or
+/// `--hourly-storage`, e.g. this week.
+async fn top(
+    req: &Request<hyper::body::Incoming>,
+    json: bool,
+    state: &State,
+) -> hyper::http::Result<Response<BoxBody<Bytes, Infallible>>> {
+    let query = || form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes());
+
+    let period = match query().find(|(k, v)| k == "period" && !v.is_empty()) {
+        Some((_, v)) => match Period::from_str(&v, true) {
+            Ok(period) => Some(period),
+            Err(_) => {
+                return state.error_pages.response(
+                    StatusCode::BAD_REQUEST,
+                    "Unknown period, expected one of hour, day, week, month",
+                )
+            }
+        },
+        None => None,
+    };
+    let limit = query()
+        .find(|(k, _)| k == "limit")
+        .and_then(|(_, v)| v.parse::<usize>().ok())
+        .unwrap_or(10)
+        .clamp(1, 100);
+
+    let visits = match period {
+        None => state.store.snapshot().await,
+        Some(period) => match state.series.iter().find(|series| series.covers(period)) {
+            Some(series) => series.totals(period, Utc::now()).await,
+            None => return state.error_pages.response(
+                StatusCode::NOT_FOUND,
+                "Visits are only counted over time with `--daily-storage` or `--hourly-storage`.",
+            ),
+        },
+    };
+    let leaders = match visits {
+        Ok(visits) => top::leaders(visits, limit),
+        Err(err) => {
+            log::error!("Error reading the top referers: {err:?}");
+            return state.error_pages.internal();
+        }
+    };
+
+    let name = |period: Period| {
+        period
+            .to_possible_value()
+            .expect("no skipped periods")
+            .get_name()
+            .to_string()
+    };
+    let if_none_match = req.headers().get(header::IF_NONE_MATCH);
+
+    if json {
+        let referers: Vec<_> = leaders
+            .iter()
+            .map(|(referer, count)| serde_json::json!({ "referer": referer, "count": count }))
+            .collect();
+        let body = serde_json::json!({
+            "period": period.map(name),
+            "referers": referers,
+        });
+        return revalidated(if_none_match, "application/json", body.to_string());
+    }
+
+    let periods: Vec<String> = std::iter::once(String::new())
+        .chain(
+            Period::value_variants()
+                .iter()
+                .filter(|period| state.series.iter().any(|series| series.covers(**period)))
+                .map(|period| name(*period)),
+        )
+        .collect();
+    let periods: Vec<&str> = periods.iter().map(String::as_str).collect();
+    revalidated(
+        if_none_match,
+        "text/html; charset=utf-8",
+        top::page(&leaders, &period.map(name).unwrap_or_default(), &periods),
+    )
+}
+
+/// Answers read-only requests with an `ETag` of `body`, or with `304 Not Modified`
+/// if the client already has it.
+fn revalidated(
+    if_none_match: Option<&HeaderValue>,
+    content_type: &str,
+    body: String,
+) -> hyper::http::Result<Response<BoxBody<Bytes, Infallible>>> {
+    let hash = Sha256::digest(body.as_bytes());
+    let etag = format!(
+        "\"{}\"",
+        hash[..16]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>()
+    );
+
+    let cached = if_none_match
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| {
+            v.split(',')
+                .map(|tag| tag.trim().trim_start_matches("W/"))
+                .any(|tag| tag == etag || tag == "*")
+        });
+
+    let response = Response::builder()
+        .header(header::ETAG, &etag)
+        .header(header::CACHE_CONTROL, "no-cache");
+
+    if cached {
+        return response
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Empty::default().boxed());
+    }
+
+    response
+        .header(header::CONTENT_TYPE, content_type)
+        .body(BoxBody::new(body))
+}
+
+/// `GET /api/counts` returns every counter as a JSON object, `/api/counts/{referer}`
+/// only the one for the percent-encoded referer, wrapped in a JSONP call with
+/// `?callback=`. With the admin token, `PUT` with a
+/// `{"count": n}` body overwrites a counter and `DELETE` drops it.
+async fn api_counts(
+    req: Request<hyper::body::Incoming>,
+    state: &State,
+) -> hyper::http::Result<Response<BoxBody<Bytes, Infallible>>> {
+    let Some(referer) = api_counter(req.uri().path(), &state.namespaces, &state.referers) else {
+        return state
+            .error_pages
+            .response(StatusCode::NOT_FOUND, "No such API route.");
+    };
+
+    match (req.method(), referer) {
+        (&Method::GET | &Method::HEAD, referer) => {
+            let callback = form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+                .find(|(k, _)| k == "callback")
+                .map(|(_, v)| v.into_owned());
+            if let Some(callback) = &callback {
+                if !valid_callback(callback) {
+                    return state.error_pages.response(
+                        StatusCode::BAD_REQUEST,
+                        "`?callback=` has to be a JavaScript function name like `show` or `counter.show`.",
+                    );
+                }
+            }
+
+            let if_none_match = req.headers().get(header::IF_NONE_MATCH);
+            get_counts(
+                referer.as_deref(),
+                callback.as_deref(),
+                if_none_match,
+                state,
+            )
+            .await
+        }
+        (&Method::PUT, Some(referer)) => {
+            if let Some(rejected) = check_admin(&req, state) {
+                return rejected;
+            }
+            set_count(req, &referer, state).await
+        }
+        (&Method::DELETE, Some(referer)) => {
+            if let Some(rejected) = check_admin(&req, state) {
+                return rejected;
+            }
+            delete_count(&req, &referer, state).await
+        }
+        (_, None) => state.error_pages.method_not_allowed("GET, HEAD"),
+        (_, Some(_)) => state
+            .error_pages
+            .method_not_allowed("GET, HEAD, PUT, DELETE"),
+    }
+}
+
+/// The counter an `/api/counts` `path` is about, named by its percent-encoded
+/// referer, `Some(None)` for all of them, or `None` for paths that aren't the API.
+/// Every method reads the same counter, so what `PUT` writes, `GET` returns.
+fn api_counter(
+    path: &str,
+    namespaces: &[Namespace],
+    referers: &Referers,
+) -> Option<Option<String>> {
+    match path.strip_prefix("/api/counts")? {
+        "" | "/" => Some(None),
+        rest => {
+            let referer = percent_decode_str(rest.strip_prefix('/')?).decode_utf8_lossy();
+            Some(Some(api_name(&referer, namespaces, referers)))
+        }
+    }
+}
+
+/// The counter an API client means by `name`, a referer or key that may be
+/// prefixed with `<namespace>:`. Like [`counter_name`], only the part after
+/// the prefix is normalized and hashed.
+fn api_name(name: &str, namespaces: &[Namespace], referers: &Referers) -> String {
+    namespaces
+        .iter()
+        .find_map(|ns| {
+            let referer = name.strip_prefix(&format!("{}:", ns.name))?;
+            Some(ns.key(&referers.normalize(referer)))
+        })
+        .unwrap_or_else(|| referers.normalize(name))
+}
+
+async fn get_counts(
+    referer: Option<&str>,
+    callback: Option<&str>,
+    if_none_match: Option<&HeaderValue>,
+    state: &State,
+) -> hyper::http::Result<Response<BoxBody<Bytes, Infallible>>> {
+    let body = match referer {
+        None => match state.store.snapshot().await {
+            Ok(visits) => serde_json::to_string(&visits),
+            Err(err) => {
+                log::error!("Error reading visits for the API: {err:?}");
+                return state.error_pages.internal();
+            }
+        },
+        Some(referer) => match state.store.get(referer).await {
+            // Like a counter that was never visited, one at 0 has nothing to show.
+            Ok(0) => {
+                return state.error_pages.response(
+                    StatusCode::NOT_FOUND,
+                    "No visits were counted for this referer.",
+                )
+            }
+            Ok(count) => {
+                let mut body = serde_json::json!({
+                    "referer": referer,
+                    "count": count,
+                });
+                if let Some(sessions) = &state.sessions {
+                    match sessions.store.get(referer).await {
+                        Ok(count) => body["sessions"] = count.into(),
+                        Err(err) => {
+                            log::error!("Error reading sessions for the API: {err:?}");
+                            return state.error_pages.internal();
+                        }
+                    }
+                }
+                if let Some(online) = &state.online {
+                    body["online"] = online.count(referer).into();
+                }
+                if let Some(bot_visits) = &state.bot_visits {
+                    match bot_visits.get(referer).await {
+                        Ok(count) => body["bots"] = count.into(),
+                        Err(err) => {
+                            log::error!("Error reading bot visits for the API: {err:?}");
+                            return state.error_pages.internal();
+                        }
+                    }
+                }
+                serde_json::to_string(&body)
+            }
+            Err(err) => {
+                log::error!("Error reading visits for the API: {err:?}");
+                return state.error_pages.internal();
+            }
+        },
+    };
+
+    let body = body.expect("counts always serialize");
+    match callback {
+        Some(callback) => revalidated(
+            if_none_match,
+            "text/javascript; charset=utf-8",
+            format!("/**/{callback}({body});"),
+        ),
+        None => revalidated(if_none_match, "application/json", body),
+    }
+}
+
+/// Only allows dotted identifiers, so the callback can't inject a script of its own.
+fn valid_callback(callback: &str) -> bool {
+    !callback.is_empty()
+        && callback.len() <= 128
+        && callback.split('.').all(|part| {
+            part.chars()
+                .next()
+                .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '$')
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+        })
+}
+
+/// `GET /api/history/{referer}` returns the visits of the percent-encoded referer
+/// per day, or per hour, ISO week or month with `?period=`.
+async fn history(
+    req: &Request<hyper::body::Incoming>,
+    state: &State,
+) -> hyper::http::Result<Response<BoxBody<Bytes, Infallible>>> {
+    if state.series.is_empty() {
+        return state.error_pages.response(
+            StatusCode::NOT_FOUND,
+            "Visits are only counted over time with `--daily-storage` or `--hourly-storage`.",
+        );
+    }
+
+    let referer = percent_decode_str(&req.uri().path()["/api/history/".len()..])
+        .decode_utf8_lossy()
+        .into_owned();
+    let referer = api_name(&referer, &state.namespaces, &state.referers);
+
+    let period = form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+        .find(|(k, _)| k == "period")
+        .map(|(_, v)| Period::from_str(&v, true))
+        .unwrap_or(Ok(Period::default()));
+    let Ok(period) = period else {
+        return state.error_pages.response(
+            StatusCode::BAD_REQUEST,
+            "Unknown period, expected one of hour, day, week, month",
+        );
+    };
+
+    let Some(series) = state.series.iter().find(|series| series.covers(period)) else {
+        return state.error_pages.response(
+            StatusCode::NOT_FOUND,
+            "Visits are only counted per hour with `--hourly-storage`.",
+        );
+    };
+
+    let counts = match series.history(&referer, period).await {
+        Ok(counts) => counts,
+        Err(err) => {
+            log::error!("Error reading the history of {referer:?}: {err:?}");
+            return state.error_pages.internal();
+        }
+    };
+
+    let body = serde_json::json!({
+        "referer": referer,
+        "period": period.to_possible_value().expect("no skipped periods").get_name(),
+        "counts": counts,
+    });
+
+    revalidated(
+        req.headers().get(header::IF_NONE_MATCH),
+        "application/json",
+        body.to_string(),
+    )
+}
+
+/// `GET /api/paths/{referer}` returns the visits of every page of a site with
+/// `--path-storage`, busiest first.
+async fn api_paths(
+    req: &Request<hyper::body::Incoming>,
+    state: &State,
+) -> hyper::http::Result<Response<BoxBody<Bytes, Infallible>>> {
+    let Some(paths) = &state.paths else {
+        return state.error_pages.response(
+            StatusCode::NOT_FOUND,
+            "The pages of each site are only counted with `--path-storage`.",
+        );
+    };
+
+    let referer = percent_decode_str(&req.uri().path()["/api/paths/".len()..])
+        .decode_utf8_lossy()
+        .into_owned();
+    let referer = api_name(&referer, &state.namespaces, &state.referers);
+
+    let counts = match paths.of(&referer).await {
+        Ok(counts) => counts,
+        Err(err) => {
+            log::error!("Error reading the paths of {referer:?}: {err:?}");
+            return state.error_pages.internal();
+        }
+    };
+
+    let paths: Vec<_> = counts
+        .iter()
+        .map(|(path, count)| serde_json::json!({ "path": path, "count": count }))
+        .collect();
+    let body = serde_json::json!({
+        "referer": referer,
+        "paths": paths,
+    });
+
+    revalidated(
+        req.headers().get(header::IF_NONE_MATCH),
+        "application/json",
+        body.to_string(),
+    )
+}
+
+/// `GET /api/agents/{referer}` returns the visits of every browser and OS family
+/// with `--agent-storage`.
+async fn api_agents(
+    req: &Request<hyper::body::Incoming>,
+    state: &State,
+) -> hyper::http::Result<Response<BoxBody<Bytes, Infallible>>> {
+    let Some(agents) = &state.agents else {
+        return state.error_pages.response(
+            StatusCode::NOT_FOUND,
+            "Browsers and operating systems are only counted with `--agent-storage`.",
+        );
+    };
+
+    let referer = percent_decode_str(&req.uri().path()["/api/agents/".len()..])
+        .decode_utf8_lossy()
+        .into_owned();
+    let referer = api_name(&referer, &state.namespaces, &state.referers);
+
+    let families = match agents.of(&referer).await {
+        Ok(families) => families,
+        Err(err) => {
+            log::error!("Error reading the browsers of {referer:?}: {err:?}");
+            return state.error_pages.internal();
+        }
+    };
+
+    let body = serde_json::json!({
+        "referer": referer,
+        "browsers": families.browsers,
+        "os": families.os,
+    });
+
+    revalidated(
+        req.headers().get(header::IF_NONE_MATCH),
+        "application/json",
+        body.to_string(),
+    )
+}
+
+/// `GET /sparkline.svg?key=...` draws the visits of the last 30 days as a line,
+/// with `--daily-storage` or `--hourly-storage`.
+async fn sparkline(
+    req: &Request<hyper::body::Incoming>,
+    state: &State,
+) -> hyper::http::Result<Response<BoxBody<Bytes, Infallible>>> {
+    let Some(series) = state.series.first() else {
+        return state.error_pages.response(
+            StatusCode::NOT_FOUND,
+            "Visits are only counted over time with `--daily-storage` or `--hourly-storage`.",
+        );
+    };
+
+    let page = req
+        .headers()
+        .get(header::REFERER)
+        .and_then(|v| v.to_str().ok());
+    let namespace = namespace::select(&state.namespaces, None, page);
+    let referer = match counter_name(req, None, namespace, &state.referers) {
+        Ok(Some(referer)) => referer,
+        Ok(None) => {
+            return state.error_pages.response(
+                StatusCode::BAD_REQUEST,
+                "Name the counter to draw with `?key=`.",
+            )
+        }
+        Err(err) => return state.error_pages.response(StatusCode::BAD_REQUEST, err),
+    };
+
+    let mut color = state.settings().color.clone();
+    let mut width = 100;
+    let mut height = 20;
+    for (k, v) in form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes()) {
+        match k.as_ref() {
+            "color" => color = v.into_owned(),
+            "width" => width = v.parse().unwrap_or(width).clamp(10, 1000),
+            "height" => height = v.parse().unwrap_or(height).clamp(5, 500),
+            _ => {}
+        }
+    }
+
+    let days = match series.last_days(&referer, 30, Utc::now()).await {
+        Ok(days) => days,
+        Err(err) => {
+            log::error!("Error reading the history of {referer:?} for a sparkline: {err:?}");
+            return state.error_pages.internal();
+        }
+    };
+
+    revalidated(
+        req.headers().get(header::IF_NONE_MATCH),
+        "image/svg+xml",
+        sparkline::svg(&days, width, height, &color),
+    )
+}
+
+/// Describes a badge for the percent-encoded referer in the shields.io endpoint
+/// schema, see https://shields.io/badges/endpoint-badge. Doesn't count a visit.
+async fn shield(
+    req: &Request<hyper::body::Incoming>,
+    referer: &str,
+    state: &State,
+) -> hyper::http::Result<Response<BoxBody<Bytes, Infallible>>> {
+    let count = match state.store.get(referer).await {
+        Ok(count) => count,
+        Err(err) => {
+            log::error!("Error reading visits for a shield: {err:?}");
+            return state.error_pages.internal();
+        }
+    };
+
+    let mut label = String::from("visits");
+    let mut color = String::from("blue");
+    for (k, v) in form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes()) {
+        match k.as_ref() {
+            "label" => label = v.into_owned(),
+            "color" => color = v.into_owned(),
+            _ => {}
+        }
+    }
 
-    /// The path to the HTML template used when serving the iframe.
-    /// See https://github.com/msparkles/iframe-traffic-counter/blob/main/example.html for example file.
-    #[arg()]
-    template: Option<PathBuf>,
+    let body = serde_json::json!({
+        "schemaVersion": 1,
+        "label": label,
+        "message": count.to_string(),
+        "color": color,
+    });
 
-    /// Color of the text, in CSS color.
-    #[arg(long, default_value_t = String::from("white"))]
-    color: String,
+    revalidated(
+        req.headers().get(header::IF_NONE_MATCH),
+        "application/json",
+        body.to_string(),
+    )
+}
 
-    /// Path to the visits storage file
-    #[arg(long, default_value_t = String::from("visits.txt"))]
-    storage: String,
+/// The counter to watch live: `?counter=` as it's stored, like `{{COUNTER}}`
+/// shows it, or else the counter of the page `?referer=` names.
+fn watched<B>(req: &Request<B>, state: &State) -> Option<String> {
+    let mut referer = None;
+    for (k, v) in form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes()) {
+        match k.as_ref() {
+            "counter" if !v.is_empty() => return Some(v.into_owned()),
+            "referer" => referer = Some(state.referers.normalize(&v)),
+            _ => {}
+        }
+    }
+    referer
 }
 
-async fn handle(
+/// Streams the count of a counter over a WebSocket. The iframe names it with
+/// `?counter=` or its page with `?referer=`, as the handshake may not carry a
+/// `Referer` header.
+async fn live_counts(
     req: Request<hyper::body::Incoming>,
-    template: Arc<str>,
-    visits: Arc<Mutex<HashMap<String, usize>>>,
+    state: &State,
 ) -> hyper::http::Result<Response<BoxBody<Bytes, Infallible>>> {
-    let Some(referer) = req
-        .headers()
-        .get(header::REFERER)
-        .and_then(|v| v.to_str().ok())
-    else {
-        return Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .body(Empty::default().boxed());
+    let referer = watched(&req, state).or_else(|| {
+        req.headers()
+            .get(header::REFERER)
+            .and_then(|v| v.to_str().ok())
+            .map(|referer| state.referers.normalize(referer))
+    });
+    let Some(referer) = referer else {
+        return state.error_pages.response(
+            StatusCode::BAD_REQUEST,
+            "Name the page to watch with `?referer=`.",
+        );
+    };
+
+    // Subscribe first, so no hit between reading the count and watching is missed.
+    let hits = state.hits.subscribe();
+    let count = match state.store.get(&referer).await {
+        Ok(count) => count,
+        Err(err) => {
+            log::error!("Error reading visits for a live count: {err:?}");
+            return state.error_pages.internal();
+        }
+    };
+
+    live::websocket(
+        req,
+        referer,
+        count,
+        hits,
+        state.shutdown.clone(),
+        &state.error_pages,
+    )
+}
+
+/// Either sets the count outright, adds to it, e.g. to carry over an old
+/// counter after visits were already counted here, or corrects it either way.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SetCount {
+    Count { count: usize },
+    Offset { offset: usize },
+    Adjust { adjust: i64 },
+}
+
+async fn set_count(
+    req: Request<hyper::body::Incoming>,
+    referer: &str,
+    state: &State,
+) -> hyper::http::Result<Response<BoxBody<Bytes, Infallible>>> {
+    let actor = actor(&req);
+    let body = match Limited::new(req.into_body(), 1024).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(_) => {
+            return state.error_pages.response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "The body can be 1 KiB at most.",
+            )
+        }
     };
 
-    log::debug!("Accepted referer: {:?}", referer);
+    let Ok(set) = serde_json::from_slice::<SetCount>(&body) else {
+        return state.error_pages.response(
+            StatusCode::BAD_REQUEST,
+            "Expected a body like {\"count\": 123}, {\"offset\": 123} or {\"adjust\": -5}",
+        );
+    };
 
-    let html = {
-        let mut lock = visits.lock().await;
-        let visit = lock.entry(referer.to_string()).or_insert(0);
-        *visit += 1;
-        template.replace("{{VISIT_COUNT}}", visit.to_string().as_str())
+    let set = async {
+        let old = state.store.get(referer).await?;
+        let (action, count) = match set {
+            SetCount::Count { count: 0 } => ("reset", 0),
+            SetCount::Count { count } => ("set", count),
+            SetCount::Offset { offset } => ("offset", old.saturating_add(offset)),
+            SetCount::Adjust { adjust } => {
+                let by = adjust.unsigned_abs().try_into().unwrap_or(usize::MAX);
+                let count = if adjust < 0 {
+                    old.saturating_sub(by)
+                } else {
+                    old.saturating_add(by)
+                };
+                ("adjust", count)
+            }
+        };
+        state.store.set(referer, count).await?;
+        audit(&actor, action, referer, old, count, state).await;
+        anyhow::Ok(count)
+    };
+    let count = match set.await {
+        Ok(count) => count,
+        Err(err) => {
+            log::error!("Error setting the count of {referer:?}: {err:?}");
+            return state.error_pages.internal();
+        }
     };
+    log::info!("Set the count of {referer:?} to {count}");
 
-    Ok(Response::new(BoxBody::new(html)))
+    get_counts(Some(referer), None, None, state).await
 }
 
-fn fill_values(args: &Args, template: &str) -> String {
-    template.replace("{{COLOR}}", &args.color)
+async fn delete_count(
+    req: &Request<hyper::body::Incoming>,
+    referer: &str,
+    state: &State,
+) -> hyper::http::Result<Response<BoxBody<Bytes, Infallible>>> {
+    let old = match state.store.get(referer).await {
+        Ok(old) => old,
+        Err(err) => {
+            log::error!("Error reading the count of {referer:?}: {err:?}");
+            return state.error_pages.internal();
+        }
+    };
+
+    forget(&[referer.to_string()], state).await;
+
+    match state.store.remove(referer).await {
+        Ok(true) => {
+            log::info!("Deleted the count of {referer:?}");
+            audit(&actor(req), "delete", referer, old, 0, state).await;
+            state.last_seen.forget(referer);
+            Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(Empty::default().boxed())
+        }
+        Ok(false) => state.error_pages.response(
+            StatusCode::NOT_FOUND,
+            "No visits were counted for this referer.",
+        ),
+        Err(err) => {
+            log::error!("Error deleting the count of {referer:?}: {err:?}");
+            state.error_pages.internal()
+        }
+    }
 }
 
-fn write_visits(visits: &HashMap<String, usize>) -> String {
-    visits
-        .iter()
-        .fold(String::new(), |s, (server, v)| format!("{server} {v}\n{s}"))
+/// Drops what the other storages keep about `referers`, like their unique
+/// visitors, history, paths and browsers, once their counters are gone.
+async fn forget(referers: &[String], state: &State) {
+    for extra in state.extra_stores() {
+        if let Err(err) = extra.remove_many(referers).await {
+            log::error!("Error deleting {referers:?} from {extra}: {err:?}");
+        }
+    }
+
+    for series in &state.series {
+        if let Err(err) = series.forget(referers).await {
+            log::error!("Error deleting the history of {referers:?}: {err:?}");
+        }
+    }
+    if let Some(paths) = &state.paths {
+        if let Err(err) = paths.forget(referers).await {
+            log::error!("Error deleting the paths of {referers:?}: {err:?}");
+        }
+    }
+    if let Some(agents) = &state.agents {
+        if let Err(err) = agents.forget(referers).await {
+            log::error!("Error deleting the browsers of {referers:?}: {err:?}");
+        }
+    }
+}
+
+/// Who is making an admin request, for the audit log.
+fn actor<B>(req: &Request<B>) -> Actor {
+    let ClientIp(client) = *req.extensions().get().expect("set by `handle`");
+    Actor {
+        user: admin::username(req),
+        client,
+    }
+}
+
+/// Writes a correction to `--audit-log`. The change is already made, so
+/// failing to log it is only reported.
+async fn audit(actor: &Actor, action: &str, referer: &str, old: usize, new: usize, state: &State) {
+    let Some(audit_log) = &state.audit_log else {
+        return;
+    };
+    if let Err(err) = audit_log.record(actor, action, referer, old, new).await {
+        log::error!("Error auditing the {action} of {referer:?}: {err:?}");
+    }
+}
+
+/// Rejects requests without the admin token. Admin routes don't exist
+/// without `--admin-token`.
+fn check_admin(
+    req: &Request<hyper::body::Incoming>,
+    state: &State,
+) -> Option<hyper::http::Result<Response<BoxBody<Bytes, Infallible>>>> {
+    let Some(token) = &state.admin_token else {
+        return Some(state.error_pages.response(
+            StatusCode::NOT_FOUND,
+            "The admin routes need an `--admin-token`.",
+        ));
+    };
+
+    if !admin::authorized(req, token) {
+        return Some(
+            state
+                .error_pages
+                .response(StatusCode::UNAUTHORIZED, "This needs the admin token.")
+                .map(|mut response| {
+                    response.headers_mut().insert(
+                        header::WWW_AUTHENTICATE,
+                        HeaderValue::from_static(r#"Basic realm="admin""#),
+                    );
+                    response
+                }),
+        );
+    }
+
+    None
+}
+
+async fn admin_page(
+    req: &Request<hyper::body::Incoming>,
+    state: &State,
+) -> hyper::http::Result<Response<BoxBody<Bytes, Infallible>>> {
+    if let Some(rejected) = check_admin(req, state) {
+        return rejected;
+    }
+
+    let visits = match state.store.snapshot().await {
+        Ok(visits) => visits,
+        Err(err) => {
+            log::error!("Error reading visits for the dashboard: {err:?}");
+            return state.error_pages.internal();
+        }
+    };
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(BoxBody::new(admin::page(&visits, &state.last_seen)))
+}
+
+/// Speaks HTTP/1.1 or HTTP/2, whichever the client starts with. Once `shutdown`
+/// is set, the requests in flight are finished and the connection is closed.
+async fn serve_connection<I>(
+    io: I,
+    peer: IpAddr,
+    tls: bool,
+    state: Arc<State>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    I: hyper::rt::Read + hyper::rt::Write + Unpin + Send + 'static,
+{
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(state.header_read_timeout);
+    let conn = builder.serve_connection_with_upgrades(
+        io,
+        service_fn(move |v| handle_in_time(v, peer, tls, state.clone())),
+    );
+    tokio::pin!(conn);
+
+    tokio::select! {
+        result = conn.as_mut() => return result,
+        _ = shutdown.wait_for(|&shutdown| shutdown) => {}
+    }
+
+    conn.as_mut().graceful_shutdown();
+    conn.await
+}
+
+async fn flush_due(write_behind: Option<&WriteBehind>) {
+    match write_behind {
+        Some(write_behind) => write_behind.due().await,
+        None => std::future::pending().await,
+    }
+}
+
+impl State {
+    fn settings(&self) -> Arc<Settings> {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// The stores counting something on top of the visits.
+    fn extra_stores(&self) -> impl Iterator<Item = &Arc<dyn VisitStore>> {
+        self.unique
+            .iter()
+            .chain(self.sessions.as_ref().map(|sessions| &sessions.store))
+            .chain(&self.bot_visits)
+    }
+
+    async fn flush_extra(&self) -> anyhow::Result<()> {
+        for extra in self.extra_stores() {
+            extra.flush().await?;
+        }
+        for series in &self.series {
+            series.store().flush().await?;
+        }
+        if let Some(paths) = &self.paths {
+            paths.store().flush().await?;
+        }
+        if let Some(agents) = &self.agents {
+            agents.store().flush().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Uploads the counters after a save. A failed upload only gets logged, the
+/// next save tries again.
+async fn upload(store: &dyn VisitStore, sync: Option<&S3Sync>) -> anyhow::Result<()> {
+    if let Some(sync) = sync {
+        if let Err(err) = sync.upload(&store.snapshot().await?).await {
+            log::error!("Error syncing visits to {sync}: {err:?}");
+        }
+    }
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
+    let command = Args::command_with_env();
+    let args = config::args(command.clone(), std::env::args_os().collect())?;
+    let args =
+        Args::from_arg_matches(&command.get_matches_from(args)).unwrap_or_else(|err| err.exit());
 
-    let template: Arc<str>;
-    if let Some(path) = args.template.clone() {
-        template = Arc::from(fill_values(&args, &read_to_string(path)?));
-    } else {
-        template = Arc::from(fill_values(&args, DEFAULT_TEMPLATE));
+    let served = match &args.command {
+        Some(Command::Serve(serve)) => Some(serve.as_ref()),
+        None => Some(&args.serve),
+        Some(_) => None,
+    };
+    logging::init(
+        served.map_or(LogFormat::Text, |serve| serve.log_format),
+        served.and_then(|serve| serve.log_level),
+        served.is_some_and(|serve| serve.access_log),
+    );
+
+    match args.command {
+        Some(Command::Export {
+            storage,
+            output,
+            timestamps,
+            namespace,
+        }) => {
+            let mut visits = storage.open().await?.snapshot().await?;
+            if let Some(name) = namespace {
+                visits = namespace::scope(&visits, &name);
+            }
+            let csv = export::csv(&visits, timestamps.then(Utc::now));
+
+            match output {
+                Some(path) => std::fs::write(path, csv)?,
+                None => print!("{csv}"),
+            }
+            Ok(())
+        }
+        Some(Command::Stats {
+            storage,
+            json,
+            limit,
+            namespace,
+        }) => {
+            let mut visits = storage.open().await?.snapshot().await?;
+            if let Some(name) = namespace {
+                visits = namespace::scope(&visits, &name);
+            }
+            if json {
+                println!("{}", export::json(&visits, limit));
+            } else {
+                print!("{}", export::table(&visits, limit));
+            }
+            Ok(())
+        }
+        Some(Command::Import {
+            file,
+            from,
+            site,
+            strategy,
+            storage,
+        }) => {
+            let imported = import::read(from, std::fs::File::open(&file)?, site.as_deref())
+                .with_context(|| format!("Can't import {file:?}"))?;
+
+            let store = storage.open().await?;
+            let existing = store.snapshot().await?;
+
+            let total = storage::total(&imported);
+            let pages = imported.len();
+
+            let merged = merge::merge([existing.clone(), imported], strategy);
+            for (server, count) in &merged {
+                if existing.get(server) != Some(count) {
+                    store.set(server, *count).await?;
+                }
+            }
+            store.flush().await?;
+
+            log::info!("Imported {total} visits to {pages} pages into {store}");
+            Ok(())
+        }
+        Some(Command::Merge {
+            inputs,
+            output,
+            storage_format,
+            strategy,
+            key,
+        }) => {
+            let mut all = Vec::new();
+            for input in &inputs {
+                let store = StorageArgs {
+                    storage: input.clone(),
+                    storage_format,
+                    wal: false,
+                    wal_compact_after: 0,
+                    skip_bad_lines: false,
+                    key: key.clone(),
+                }
+                .open()
+                .await?;
+
+                let visits = store.snapshot().await?;
+                if visits.is_empty() {
+                    log::warn!("{store} has no visits, is that the right path?");
+                }
+                all.push(visits);
+            }
+
+            let merged = merge::merge(all, strategy);
+            log::info!(
+                "Merged {} inputs into {} referers in {output:?}",
+                inputs.len(),
+                merged.len()
+            );
+            let data =
+                storage::seal_file(key.cipher()?.as_deref(), storage_format.encode(&merged)?)?;
+            storage::write_atomic(&output, &data).await
+        }
+        Some(Command::Serve(args)) => serve(*args).await,
+        None => serve(args.serve).await,
+    }
+}
+
+fn template_sources(args: &ServeArgs) -> template::Sources {
+    template::Sources {
+        main: args.template.clone(),
+        theme: args.theme,
+        lazy: args.lazy_template.clone(),
+        by_domain: args.template_map.clone(),
+        by_namespace: args.namespace_templates.clone(),
+        dir: args.template_dir.clone(),
+        css: args.css.clone(),
+        digits: args.digits.map(|digits| digits as usize),
+    }
+}
+
+/// Loads the templates, storages and other files `serve` would, and checks the
+/// addresses belong to this machine, without listening on them or counting
+/// anything. Every problem is logged, not only the first.
+async fn check(args: &ServeArgs) -> anyhow::Result<()> {
+    let mut checks: Vec<(String, anyhow::Result<()>)> = Vec::new();
+
+    for addr in &args.ip {
+        // Any port, so a counter already running there doesn't get in the way.
+        let bound = std::net::TcpListener::bind(SocketAddr::new(addr.ip(), 0))
+            .map(drop)
+            .with_context(|| format!("{} isn't an address of this machine", addr.ip()));
+        checks.push((format!("address {addr}"), bound));
     }
 
+    checks.push((
+        String::from("templates"),
+        template_sources(args).load().map(drop),
+    ));
+    checks.push((
+        String::from("error pages"),
+        ErrorPages::load(&args.error_pages).map(drop),
+    ));
+    if let Some(path) = &args.digit_sprites {
+        checks.push((format!("sprites {path:?}"), Sprites::load(path).map(drop)));
+    }
+    if let Some(dir) = &args.translations {
+        checks.push((
+            format!("translations {dir:?}"),
+            Labels::load(Some(dir)).map(drop),
+        ));
+    }
+    if let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) {
+        checks.push((
+            format!("TLS certificate {cert:?}"),
+            Tls::from_files(cert, key).map(drop),
+        ));
+    }
+    if let Some(path) = &args.referer_salt_file {
+        let read = read_to_string(path)
+            .map(drop)
+            .with_context(|| format!("Can't read {path:?}"));
+        checks.push((format!("referer salt {path:?}"), read));
+    }
+
+    let specs = [
+        Some(&args.storage.storage),
+        args.unique_storage.as_ref(),
+        args.bot_storage.as_ref(),
+        args.session_storage.as_ref(),
+        args.daily_storage.as_ref(),
+        args.hourly_storage.as_ref(),
+        args.path_storage.as_ref(),
+        args.agent_storage.as_ref(),
+    ];
+    for spec in specs.into_iter().flatten() {
+        let read = args.storage.read_at(spec).await;
+        checks.push((format!("storage {spec}"), read.map(drop)));
+    }
+
+    let mut problems = 0;
+    for (what, result) in checks {
+        match result {
+            Ok(()) => log::info!("Checked the {what}"),
+            Err(err) => {
+                log::error!("Problem with the {what}: {err:?}");
+                problems += 1;
+            }
+        }
+    }
+    match problems {
+        0 => {
+            log::info!("Everything checks out");
+            Ok(())
+        }
+        1 => anyhow::bail!("Found a problem"),
+        n => anyhow::bail!("Found {n} problems"),
+    }
+}
+
+async fn serve(args: ServeArgs) -> anyhow::Result<()> {
+    if args.check {
+        return check(&args).await;
+    }
+
+    let sources = template_sources(&args);
+    let templates = sources.load()?;
+
+    let namespaces: Vec<_> = args
+        .namespaces
+        .iter()
+        .map(|(name, domains)| Namespace::new(name.clone(), domains.clone()))
+        .collect();
+    if let Some((name, _)) = args
+        .namespace_templates
+        .iter()
+        .find(|(name, _)| !namespaces.iter().any(|ns| ns.name == *name))
     {
-        let mut env = Env::default();
-        env = env.default_filter_or("debug");
-        env_logger::Builder::from_env(env).init();
+        anyhow::bail!("There's a template for namespace {name:?}, but no `--namespace {name}`");
     }
 
-    let addr = SocketAddr::from_str(&args.ip)?;
+    let milestones = match &args.milestone_webhook {
+        Some(url) => {
+            let payload = match &args.milestone_payload {
+                Some(path) => Some(
+                    read_to_string(path)
+                        .with_context(|| format!("Can't read the milestone payload {path:?}"))?,
+                ),
+                None => None,
+            };
+            Some(Arc::new(Milestones::new(
+                url,
+                args.milestone_every.map(|every| every as usize),
+                args.milestones.clone(),
+                payload,
+            )?))
+        }
+        None => None,
+    };
+
+    let tls = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => Some(Tls::from_files(cert, key)?),
+        _ if !args.acme_domain.is_empty() => Some(Tls::acme(
+            &args.acme_domain,
+            &args.acme_contact,
+            args.acme_cache.clone(),
+            args.acme_staging,
+        )),
+        _ => None,
+    };
 
-    log::info!("Listening on {addr}");
-    let listener = TcpListener::bind(addr).await?;
+    let mut listeners = Vec::new();
+    for addr in &args.ip {
+        log::info!(
+            "Listening on {}://{addr}",
+            if tls.is_some() { "https" } else { "http" }
+        );
+        listeners.push(
+            TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("Can't listen on {addr}"))?,
+        );
+    }
 
     let (cancel_tx, mut cancel_rx) = mpsc::channel::<()>(1);
     tokio::spawn(async move {
@@ -114,66 +3060,555 @@ async fn main() -> anyhow::Result<()> {
         }
     });
 
-    let storage_path = PathBuf::from(args.storage);
-    let visits = {
-        let read_visits = read_to_string(&storage_path).unwrap_or(String::new());
+    let (save_tx, mut save_rx) = mpsc::channel::<()>(1);
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        let Ok(mut usr1) = signal::unix::signal(signal::unix::SignalKind::user_defined1()) else {
+            log::warn!("Can't listen for SIGUSR1, saving on demand won't work");
+            return;
+        };
+        while usr1.recv().await.is_some() {
+            // A save is already queued if this fails, no need for another.
+            let _ = save_tx.try_send(());
+        }
+    });
+    #[cfg(not(unix))]
+    drop(save_tx);
 
-        let mut visits = HashMap::default();
+    let cipher = args.storage.key.cipher()?;
+    let sync = args
+        .sync
+        .open(args.storage.storage_format, cipher.clone())?;
 
-        for visit in read_visits.lines() {
-            let mut split = visit.split(' ');
-            if let Some((server, v)) = split.next().zip(split.next()) {
-                if let Ok(v) = v.parse::<usize>() {
-                    visits.insert(server.to_string(), v);
-                }
+    if let (Some(sync), Some(path)) = (&sync, storage::file_path(&args.storage.storage)) {
+        if !path.exists() {
+            if let Some(data) = sync.download().await? {
+                storage::write_atomic(&path, &data).await?;
+                log::info!("Restored {path:?} from {sync}");
             }
         }
+    }
 
-        Arc::new(Mutex::new(visits))
+    let salt = match &args.referer_salt_file {
+        Some(path) => Some(read_to_string(path)?.trim_end().as_bytes().to_vec()),
+        None => args
+            .referer_salt
+            .as_ref()
+            .map(|salt| salt.as_bytes().to_vec()),
+    };
+    let referers = Referers::new(args.granularity, &args.aliases, salt);
+    let mut store = args.storage.open().await?;
+    referers.merge_aliases(&*store, &namespaces).await?;
+    // Only ever seeds new counters, so restarting with the same offsets is fine.
+    for (referer, offset) in &args.offsets {
+        let referer = referers.normalize(referer);
+        match store.get(&referer).await? {
+            0 => {
+                store.set(&referer, *offset).await?;
+                log::info!("Started the count of {referer:?} at {offset}");
+            }
+            count => log::debug!("Not offsetting {referer:?}, it already has {count} visits"),
+        }
+    }
+    let unique = match &args.unique_storage {
+        Some(spec) => Some(args.storage.open_at(spec).await?),
+        None => None,
+    };
+    let bot_visits = match &args.bot_storage {
+        Some(spec) => Some(args.storage.open_at(spec).await?),
+        None => None,
+    };
+    let mut series = Vec::new();
+    if let Some(spec) = &args.daily_storage {
+        let retention = args.daily_retention.map(|days| {
+            let retention = Duration::from_secs(days.saturating_mul(24 * 3600));
+            TimeDelta::from_std(retention).unwrap_or(TimeDelta::MAX)
+        });
+        series.push(Series::daily(
+            args.storage.open_at(spec).await?,
+            retention,
+            args.timezone,
+        ));
+    }
+    if let Some(spec) = &args.hourly_storage {
+        let retention = Duration::from_secs(args.hourly_retention.saturating_mul(3600));
+        let retention = TimeDelta::from_std(retention).unwrap_or(TimeDelta::MAX);
+        series.push(Series::hourly(
+            args.storage.open_at(spec).await?,
+            retention,
+            args.timezone,
+        ));
+    }
+    let paths = match &args.path_storage {
+        Some(_) if args.granularity != Granularity::Origin => {
+            anyhow::bail!(
+                "--path-storage needs --granularity origin, counters already are per page"
+            )
+        }
+        Some(spec) => Some(Paths::new(args.storage.open_at(spec).await?)),
+        None => None,
+    };
+    let agents = match &args.agent_storage {
+        Some(spec) => Some(Agents::new(args.storage.open_at(spec).await?)),
+        None => None,
+    };
+    let sessions = match &args.session_storage {
+        Some(spec) => Some(Sessions {
+            store: args.storage.open_at(spec).await?,
+            seen: Dedup::new(Duration::from_secs(args.session_window)),
+        }),
+        None => None,
     };
+    for extra in unique
+        .iter()
+        .chain(&bot_visits)
+        .chain(sessions.as_ref().map(|sessions| &sessions.store))
+    {
+        referers.merge_aliases(&**extra, &namespaces).await?;
+    }
+
+    let write_behind = args
+        .save_every_n_hits
+        .map(|every| Arc::new(WriteBehind::new(store.clone(), every)));
+    if let Some(write_behind) = &write_behind {
+        store = write_behind.clone();
+    }
+
+    let settings = Settings::new(&args, &referers)?;
+    let backups = args.backup_dir.map(|dir| {
+        Backups::new(
+            dir,
+            args.backup_keep as usize,
+            args.storage.storage_format,
+            cipher,
+        )
+    });
+
+    let prune = args.prune_below.map(|below| {
+        Prune::new(
+            below as usize,
+            args.prune_older_than.map(|secs| {
+                TimeDelta::from_std(Duration::from_secs(secs)).unwrap_or(TimeDelta::MAX)
+            }),
+        )
+    });
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let state = Arc::new(State {
+        templates: RwLock::new(Arc::new(templates)),
+        settings: RwLock::new(Arc::new(settings)),
+        sprites: args
+            .digit_sprites
+            .as_deref()
+            .map(Sprites::load)
+            .transpose()?,
+        store: store.clone(),
+        unique,
+        sessions,
+        series,
+        paths,
+        agents,
+        dedup: args
+            .dedup_window
+            .map(|secs| Dedup::new(Duration::from_secs(secs))),
+        bot_visits,
+        online: args
+            .online_window
+            .map(|mins| Online::new(Duration::from_secs(mins.saturating_mul(60)))),
+        referers,
+        admin_token: args.admin_token.clone(),
+        last_seen: LastSeen::default(),
+        hits: broadcast::channel(live::CAPACITY).0,
+        shutdown: shutdown_rx.clone(),
+        trusted_proxies: args.trusted_proxies.clone(),
+        header_read_timeout: Duration::from_secs(args.header_read_timeout),
+        request_timeout: Duration::from_secs(args.request_timeout),
+        error_pages: ErrorPages::load(&args.error_pages)?,
+        audit_log: match &args.audit_log {
+            Some(path) => Some(AuditLog::open(path).await?),
+            None => None,
+        },
+        namespaces,
+        milestones,
+    });
+
+    if args.watch_templates {
+        let state = state.clone();
+        let sources = sources.clone();
+        tokio::spawn(async move {
+            let mut modified = sources.modified();
+            let mut timer = interval(Duration::from_secs(1));
+            loop {
+                timer.tick().await;
+                let now = sources.modified();
+                if now != modified {
+                    modified = now;
+                    reload_templates(&state, &sources);
+                }
+            }
+        });
+    }
+
+    #[cfg(unix)]
+    {
+        let state = state.clone();
+        let tls = tls.clone();
+        tokio::spawn(async move {
+            let Ok(mut hup) = signal::unix::signal(signal::unix::SignalKind::hangup()) else {
+                log::warn!("Can't listen for SIGHUP, reloading on demand won't work");
+                return;
+            };
+            while hup.recv().await.is_some() {
+                log::info!("Reloading on SIGHUP!");
+                reload_templates(&state, &sources);
+                reload_settings(&state);
+                if let Err(err) = state.error_pages.reload() {
+                    log::error!("Error reloading the error pages, keeping the old ones: {err:?}");
+                }
+                if let Some(Err(err)) = tls.as_ref().map(Tls::reload) {
+                    log::error!(
+                        "Error reloading the TLS certificate, keeping the old one: {err:?}"
+                    );
+                }
+            }
+        });
+    }
 
-    let mut storage = tokio::fs::OpenOptions::new()
-        .write(true)
-        .append(false)
-        .create(true)
-        .open(&storage_path)
-        .await?;
+    // Every connection holds a sender, so once they're all gone the channel closes.
+    let (open_tx, mut open_rx) = mpsc::channel::<()>(1);
+    let slots = Arc::new(Semaphore::new(args.max_connections as usize));
+    for listener in listeners {
+        tokio::spawn(accept(
+            listener,
+            tls.clone(),
+            state.clone(),
+            shutdown_rx.clone(),
+            open_tx.clone(),
+            slots.clone(),
+        ));
+    }
+    drop(open_tx);
 
-    let mut update_timer = interval(Duration::from_secs(60));
+    let mut update_timer = interval(Duration::from_secs(args.save_interval));
 
     loop {
         let cancel_rx = &mut cancel_rx;
-        let template = template.clone();
-        let visits = visits.clone();
+        let save_rx = &mut save_rx;
 
         tokio::select! {
             _ = cancel_rx.recv() => {
                 log::info!("Shutting down!");
-                let visits = visits.lock().await;
-                storage.seek(SeekFrom::Start(0)).await?;
-                storage.write_all(write_visits(&visits).as_bytes()).await?;
-                storage.flush().await?;
+                shutdown_tx.send_replace(true);
+
+                let timeout = Duration::from_secs(args.shutdown_timeout);
+                if tokio::time::timeout(timeout, open_rx.recv()).await.is_err() {
+                    log::warn!(
+                        "Closing connections still open after {}s",
+                        args.shutdown_timeout
+                    );
+                }
+
+                store.flush().await?;
+                state.flush_extra().await?;
+                upload(&*store, sync.as_ref()).await?;
                 return Ok(());
             }
             _ = update_timer.tick() => {
-                log::debug!("Periodically saving visits to {storage_path:?}!");
-                let visits = visits.lock().await;
-                storage.seek(SeekFrom::Start(0)).await?;
-                storage.write_all(write_visits(&visits).as_bytes()).await?;
-                storage.flush().await?;
-            }
-            Ok((stream, _)) = listener.accept() => {
-                let io = TokioIo::new(stream);
-
-                tokio::task::spawn(async move {
-                    if let Err(err) = http1::Builder::new()
-                        .serve_connection(io, service_fn(move |v| handle(v, template.clone(), visits.clone())))
-                        .await
-                    {
-                        log::error!("Error serving connection: {err:?}");
+                log::debug!("Periodically saving visits to {store}!");
+                if let Some(prune) = &prune {
+                    match prune.run(&*store, &state.last_seen).await {
+                        Ok(pruned) if !pruned.is_empty() => forget(&pruned, &state).await,
+                        Ok(_) => {}
+                        Err(err) => {
+                            log::error!("Error pruning, trying again on the next save: {err:?}")
+                        }
                     }
-                });
+                }
+                for series in &state.series {
+                    if let Err(err) = series.expire(Utc::now()).await {
+                        log::error!(
+                            "Error expiring old counts, trying again on the next save: {err:?}"
+                        );
+                    }
+                }
+                store.flush().await?;
+                state.flush_extra().await?;
+
+                if let Some(backups) = &backups {
+                    // A full disk shouldn't take the counter down with it.
+                    let written = async { backups.write(&store.snapshot().await?).await };
+                    if let Err(err) = written.await {
+                        log::error!("Error writing a backup, trying again on the next save: {err:?}");
+                    }
+                }
+
+                upload(&*store, sync.as_ref()).await?;
+            }
+            Some(()) = save_rx.recv() => {
+                log::info!("Saving visits to {store} on SIGUSR1!");
+                store.flush().await?;
+                state.flush_extra().await?;
+                upload(&*store, sync.as_ref()).await?;
+            }
+            _ = flush_due(write_behind.as_deref()) => {
+                log::debug!("Saving visits to {store}, hit threshold reached!");
+                store.flush().await?;
+            }
+        }
+    }
+}
+
+/// Reads the templates from `sources` again, keeping the old ones if they don't parse.
+/// Reads the [`Settings`] again, keeping the old ones if they're invalid.
+fn reload_settings(state: &State) {
+    match serve_args().and_then(|args| Settings::new(&args, &state.referers)) {
+        Ok(settings) => {
+            *state.settings.write().unwrap() = Arc::new(settings);
+            log::info!("Reloaded the settings");
+        }
+        Err(err) => log::error!("Error reloading the settings, keeping the old ones: {err:?}"),
+    }
+}
+
+/// Parses the command line, environment and `--config` file again.
+fn serve_args() -> anyhow::Result<ServeArgs> {
+    let command = Args::command_with_env();
+    let args = config::args(command.clone(), std::env::args_os().collect())?;
+    let args = Args::from_arg_matches(&command.try_get_matches_from(args)?)?;
+    match args.command {
+        Some(Command::Serve(serve)) => Ok(*serve),
+        None => Ok(args.serve),
+        Some(_) => anyhow::bail!("The command line isn't serving anymore"),
+    }
+}
+
+fn reload_templates(state: &State, sources: &template::Sources) {
+    match sources.load() {
+        Ok(templates) => {
+            *state.templates.write().unwrap() = Arc::new(templates);
+            log::info!("Reloaded the templates");
+        }
+        Err(err) => log::error!("Error reloading the templates, keeping the old ones: {err:?}"),
+    }
+}
+
+/// Serves every connection of `listener` in its own task, until `shutdown` is set.
+/// Each task holds on to `open` and one of `slots` while it runs, new connections
+/// are only accepted while there's a slot left.
+async fn accept(
+    listener: TcpListener,
+    tls: Option<Tls>,
+    state: Arc<State>,
+    mut shutdown: watch::Receiver<bool>,
+    open: mpsc::Sender<()>,
+    slots: Arc<Semaphore>,
+) {
+    loop {
+        let slot = match slots.clone().try_acquire_owned() {
+            Ok(slot) => slot,
+            Err(_) => {
+                log::warn!("Connection limit reached, waiting for a connection to close");
+                tokio::select! {
+                    slot = slots.clone().acquire_owned() => slot.expect("never closed"),
+                    _ = shutdown.wait_for(|&shutdown| shutdown) => return,
+                }
+            }
+        };
+
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.wait_for(|&shutdown| shutdown) => return,
+        };
+        let (stream, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                // Usually out of file descriptors, give other connections time to close.
+                log::error!("Error accepting a connection: {err:?}");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let tls = tls.clone();
+        let state = state.clone();
+        let shutdown = shutdown.clone();
+        let open = open.clone();
+
+        tokio::task::spawn(async move {
+            let _open = open;
+            let _slot = slot;
+            let result = match tls {
+                Some(tls) => match tls.accept(stream).await {
+                    Ok(Some(stream)) => {
+                        serve_connection(TokioIo::new(stream), peer.ip(), true, state, shutdown)
+                            .await
+                    }
+                    Ok(None) => return,
+                    Err(err) => {
+                        log::debug!("TLS handshake failed: {err:?}");
+                        return;
+                    }
+                },
+                None => {
+                    serve_connection(TokioIo::new(stream), peer.ip(), false, state, shutdown).await
+                }
+            };
+
+            if let Err(err) = result {
+                log::error!("Error serving connection: {err:?}");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{FileOptions, FileStore, MemoryStore};
+
+    #[tokio::test]
+    async fn api_puts_and_gets_the_same_counter() {
+        let referers = Referers::new(Granularity::Page, &[], None);
+        let store = MemoryStore::default();
+        let path = "/api/counts/https%3A%2F%2Fexample.com%2F";
+
+        let put = api_counter(path, &[], &referers).unwrap().unwrap();
+        store.set(&put, 42).await.unwrap();
+        let get = api_counter(path, &[], &referers).unwrap().unwrap();
+
+        assert_eq!(get, "https://example.com/");
+        assert_eq!(store.get(&get).await.unwrap(), 42);
+        assert!(store
+            .remove(&api_counter(path, &[], &referers).unwrap().unwrap())
+            .await
+            .unwrap());
+    }
+
+    #[test]
+    fn api_counter_paths() {
+        let referers = Referers::new(Granularity::Page, &[], None);
+        assert_eq!(api_counter("/api/counts", &[], &referers), Some(None));
+        assert_eq!(api_counter("/api/counts/", &[], &referers), Some(None));
+        assert_eq!(
+            api_counter(
+                "/api/counts/https%3A%2F%2FExample.com%2Fblog%2F",
+                &[],
+                &referers
+            ),
+            Some(Some(String::from("https://example.com/blog")))
+        );
+        assert_eq!(api_counter("/api/countsx", &[], &referers), None);
+    }
+
+    #[test]
+    fn api_counters_in_a_namespace() {
+        let referers = Referers::new(Granularity::Page, &[], Some(b"salt".to_vec()));
+        let namespaces = [Namespace::new(String::from("blog"), Vec::new())];
+        let stored = namespaces[0].key(&referers.normalize("https://example.com/blog"));
+        assert_eq!(
+            api_counter(
+                "/api/counts/blog%3Ahttps%3A%2F%2FExample.com%2Fblog%2F",
+                &namespaces,
+                &referers
+            ),
+            Some(Some(stored))
+        );
+        assert_eq!(
+            api_counter(
+                "/api/counts/https%3A%2F%2Fexample.com%2Fblog",
+                &namespaces,
+                &referers
+            ),
+            Some(Some(referers.normalize("https://example.com/blog")))
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_keys_that_would_break_the_storage() {
+        let referers = Referers::new(Granularity::Page, &[], None);
+        let path = std::env::temp_dir().join(format!("itc-key-{}.txt", std::process::id()));
+        let store = FileStore::open(path.clone(), FileOptions::default())
+            .await
+            .unwrap();
+
+        for uri in [
+            "/?key=a%0Ab",
+            "/?key=evil%0Ahttps://x%201",
+            "/?key=guestbook",
+        ] {
+            let req = Request::get(uri).body(()).unwrap();
+            if let Ok(Some(name)) = counter_name(&req, None, None, &referers) {
+                store.increment(&name).await.unwrap();
             }
         }
+        store.flush().await.unwrap();
+
+        let reloaded = FileStore::open(path.clone(), FileOptions::default())
+            .await
+            .unwrap();
+        reloaded.load().await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            reloaded
+                .snapshot()
+                .await
+                .unwrap()
+                .into_iter()
+                .collect::<Vec<_>>(),
+            [(String::from("guestbook"), 1)]
+        );
+        let req = Request::get("/?key=a%0Ab").body(()).unwrap();
+        assert_eq!(counter_name(&req, None, None, &referers), Err(BAD_NAME));
+    }
+
+    #[test]
+    fn refuses_path_names_that_would_break_the_storage() {
+        let referers = Referers::new(Granularity::Page, &[], None);
+        let req = Request::get("/c/a%0Ab").body(()).unwrap();
+        let name = percent_decode_str("a%0Ab").decode_utf8_lossy();
+
+        assert_eq!(
+            counter_name(&req, Some(&name), None, &referers),
+            Err(BAD_NAME)
+        );
+        assert_eq!(
+            counter_name(&req, Some("guestbook"), None, &referers),
+            Ok(Some(String::from("guestbook")))
+        );
+    }
+
+    #[test]
+    fn refuses_beacons_that_would_break_the_storage() {
+        assert_eq!(
+            Beacon::parse(br#"{"key": "a\nb"}"#).err().as_deref(),
+            Some(BAD_NAME)
+        );
+        assert_eq!(
+            Beacon::parse(b"page=https%3A%2F%2Fa.com%2F%0Ax%201")
+                .err()
+                .as_deref(),
+            Some(BAD_NAME)
+        );
+
+        let beacon = Beacon::parse(b"key=guestbook").unwrap();
+        assert_eq!(beacon.key.as_deref(), Some("guestbook"));
+    }
+
+    #[test]
+    fn percent_of_huge_counters() {
+        assert_eq!(percent(250, 1000), 25);
+        assert_eq!(percent(usize::MAX, 1000), 100);
+        assert_eq!(percent(usize::MAX / 2, usize::MAX), 49);
+    }
+
+    #[test]
+    fn ips_can_be_comma_separated() {
+        let matches = Args::command_with_env()
+            .try_get_matches_from(["itc", "--ip", "127.0.0.1:1,[::1]:2", "--ip", "127.0.0.1:3"])
+            .unwrap();
+        let args = Args::from_arg_matches(&matches).unwrap();
+
+        let ips: Vec<_> = args.serve.ip.iter().map(|ip| ip.port()).collect();
+        assert_eq!(ips, [1, 2, 3]);
     }
 }
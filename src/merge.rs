@@ -0,0 +1,65 @@
+use clap::ValueEnum;
+
+use crate::storage::Visits;
+
+/// What to do with a referer that appears in more than one input.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MergeStrategy {
+    /// Add the counts together, for inputs that counted different visits.
+    #[default]
+    Sum,
+    /// Keep the largest count, for inputs that are copies of the same history.
+    Max,
+}
+
+/// Combines every input into one set of counters. The result doesn't depend
+/// on the order of the inputs.
+pub fn merge(inputs: impl IntoIterator<Item = Visits>, strategy: MergeStrategy) -> Visits {
+    let mut merged = Visits::default();
+
+    for visits in inputs {
+        for (server, v) in visits {
+            let count = merged.entry(server).or_insert(0);
+            *count = match strategy {
+                MergeStrategy::Sum => count.saturating_add(v),
+                MergeStrategy::Max => (*count).max(v),
+            };
+        }
+    }
+
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn visits(counts: &[(&str, usize)]) -> Visits {
+        counts
+            .iter()
+            .map(|(server, v)| (server.to_string(), *v))
+            .collect()
+    }
+
+    #[test]
+    fn sums_or_keeps_the_largest() {
+        let a = visits(&[("a", 1), ("b", 5)]);
+        let b = visits(&[("b", 3), ("c", 2)]);
+
+        assert_eq!(
+            merge([a.clone(), b.clone()], MergeStrategy::Sum),
+            visits(&[("a", 1), ("b", 8), ("c", 2)])
+        );
+        assert_eq!(
+            merge([b, a], MergeStrategy::Max),
+            visits(&[("a", 1), ("b", 5), ("c", 2)])
+        );
+    }
+
+    #[test]
+    fn saturates_instead_of_overflowing() {
+        let big = visits(&[("a", usize::MAX - 1)]);
+        let merged = merge([big.clone(), big], MergeStrategy::Sum);
+        assert_eq!(merged.get("a"), Some(&usize::MAX));
+    }
+}
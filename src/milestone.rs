@@ -0,0 +1,80 @@
+use std::sync::Arc;
+
+use anyhow::Context;
+use reqwest::Url;
+
+/// Posts to a webhook whenever a counter reaches a milestone, e.g. to announce
+/// "we hit 100k!" in a chat.
+pub struct Milestones {
+    url: Url,
+    every: Option<usize>,
+    thresholds: Vec<usize>,
+    /// JSON with `{{REFERER}}` and `{{COUNT}}` to fill in, instead of the default body.
+    payload: Option<String>,
+    client: reqwest::Client,
+}
+
+impl Milestones {
+    pub fn new(
+        url: &str,
+        every: Option<usize>,
+        thresholds: Vec<usize>,
+        payload: Option<String>,
+    ) -> anyhow::Result<Self> {
+        if every.is_none() && thresholds.is_empty() {
+            anyhow::bail!("--milestone-webhook needs --milestone-every or --milestone");
+        }
+        Ok(Self {
+            url: Url::parse(url).with_context(|| format!("{url:?} isn't a webhook URL"))?,
+            every,
+            thresholds,
+            payload,
+            client: reqwest::Client::new(),
+        })
+    }
+
+    /// Whether `count` is a milestone. Visits are counted one at a time, so every
+    /// milestone is reached exactly, counts set through the API can skip past one.
+    pub fn reached(&self, count: usize) -> bool {
+        self.every.is_some_and(|every| count.is_multiple_of(every))
+            || self.thresholds.contains(&count)
+    }
+
+    /// Posts that `referer` reached `count` in the background, so the visit
+    /// doesn't wait for the webhook. Failures are only logged.
+    pub fn notify(self: &Arc<Self>, referer: &str, count: usize) {
+        let body = match &self.payload {
+            Some(payload) => {
+                let quoted = serde_json::to_string(referer).expect("strings always serialize");
+                payload
+                    .replace("{{REFERER}}", &quoted[1..quoted.len() - 1])
+                    .replace("{{COUNT}}", &count.to_string())
+            }
+            None => serde_json::json!({ "referer": referer, "count": count }).to_string(),
+        };
+
+        let milestones = self.clone();
+        let referer = referer.to_string();
+        tokio::spawn(async move {
+            let response = milestones
+                .client
+                .post(milestones.url.clone())
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body)
+                .send()
+                .await;
+            match response {
+                Ok(response) if response.status().is_success() => {
+                    log::info!("Announced {referer:?} reaching {count} visits");
+                }
+                Ok(response) => log::warn!(
+                    "The milestone webhook answered {} for {referer:?} reaching {count} visits",
+                    response.status()
+                ),
+                Err(err) => {
+                    log::warn!("Can't announce {referer:?} reaching {count} visits: {err:?}")
+                }
+            }
+        });
+    }
+}
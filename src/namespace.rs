@@ -0,0 +1,81 @@
+use std::path::PathBuf;
+
+use crate::domains::{self, DomainPattern};
+use crate::storage::Visits;
+
+/// The namespace named in the path of a request, stored in its extensions.
+#[derive(Clone)]
+pub struct Named(pub String);
+
+/// One of several unrelated sites served by the same instance. Its counters are
+/// stored as `<name>:<referer>`, so they never mix with another site's.
+pub struct Namespace {
+    pub name: String,
+    domains: Vec<DomainPattern>,
+}
+
+impl Namespace {
+    pub fn new(name: String, domains: Vec<DomainPattern>) -> Self {
+        Self { name, domains }
+    }
+
+    /// The name `referer` is stored under in this namespace.
+    pub fn key(&self, referer: &str) -> String {
+        format!("{}:{referer}", self.name)
+    }
+}
+
+/// The namespace a counting request belongs to: the one named in its path as
+/// `/ns/<name>/`, or else the first one listing the host of `page`.
+pub fn select<'a>(
+    namespaces: &'a [Namespace],
+    named: Option<&str>,
+    page: Option<&str>,
+) -> Option<&'a Namespace> {
+    if let Some(name) = named {
+        return namespaces.iter().find(|ns| ns.name == name);
+    }
+    let host = page.and_then(domains::host)?;
+    namespaces
+        .iter()
+        .find(|ns| ns.domains.iter().any(|domain| domain.matches(&host)))
+}
+
+/// The counters of namespace `name`, without its prefix.
+pub fn scope(visits: &Visits, name: &str) -> Visits {
+    let prefix = format!("{name}:");
+    visits
+        .iter()
+        .filter_map(|(key, count)| Some((key.strip_prefix(&prefix)?.to_string(), *count)))
+        .collect()
+}
+
+/// Parses `--namespace` values like `blog=blog.example.com,*.blog.example.com`.
+/// Without domains, the namespace is only reachable through its path.
+pub fn parse(s: &str) -> Result<(String, Vec<DomainPattern>), String> {
+    let (name, domains) = s.split_once('=').unwrap_or((s, ""));
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(format!(
+            "{name:?} isn't a namespace name, use letters, digits, `-` and `_`"
+        ));
+    }
+
+    let domains = domains
+        .split(',')
+        .filter(|domain| !domain.is_empty())
+        .map(str::parse)
+        .collect::<Result<_, _>>()?;
+    Ok((name.to_string(), domains))
+}
+
+/// Parses `--namespace-template` values like `blog=blog.html`.
+pub fn parse_template(s: &str) -> Result<(String, PathBuf), String> {
+    let (name, path) = s
+        .split_once('=')
+        .ok_or_else(|| format!("{s:?} isn't like `blog=blog.html`"))?;
+    Ok((name.to_string(), PathBuf::from(path)))
+}
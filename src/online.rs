@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use sha2::{Digest, Sha256};
+
+/// Counts the clients seen on each referer within the last `window`, for
+/// "X people online" widgets. Only kept in memory, with clients as a salted
+/// hash of their address like in [`crate::dedup::Dedup`].
+pub struct Online {
+    window: Duration,
+    salt: [u8; 32],
+    seen: Mutex<Seen>,
+}
+
+struct Seen {
+    /// When each client was last seen, per referer.
+    referers: HashMap<String, HashMap<[u8; 16], Instant>>,
+    last_sweep: Instant,
+}
+
+impl Online {
+    pub fn new(window: Duration) -> Self {
+        let mut salt = [0; 32];
+        OsRng.fill_bytes(&mut salt);
+
+        Self {
+            window,
+            salt,
+            seen: Mutex::new(Seen {
+                referers: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+        }
+    }
+
+    /// Marks `client` as online on `referer`, returning how many are.
+    pub fn touch(&self, client: IpAddr, referer: &str) -> usize {
+        let mut hasher = Sha256::new();
+        hasher.update(self.salt);
+        hasher.update(client.to_string());
+        let key: [u8; 16] = hasher.finalize()[..16].try_into().unwrap();
+
+        let now = Instant::now();
+        let mut seen = self.seen.lock().unwrap();
+
+        // Referers nobody visits anymore are dropped once per window.
+        if now.duration_since(seen.last_sweep) >= self.window {
+            seen.referers.retain(|_, clients| {
+                clients.retain(|_, at| now.duration_since(*at) < self.window);
+                !clients.is_empty()
+            });
+            seen.last_sweep = now;
+        }
+
+        let clients = seen.referers.entry(referer.to_string()).or_default();
+        clients.insert(key, now);
+        clients.retain(|_, at| now.duration_since(*at) < self.window);
+        clients.len()
+    }
+
+    /// How many clients are online on `referer`.
+    pub fn count(&self, referer: &str) -> usize {
+        let now = Instant::now();
+        self.seen
+            .lock()
+            .unwrap()
+            .referers
+            .get(referer)
+            .map_or(0, |clients| {
+                clients
+                    .values()
+                    .filter(|at| now.duration_since(**at) < self.window)
+                    .count()
+            })
+    }
+}
@@ -0,0 +1,95 @@
+use std::sync::Arc;
+
+use url::Url;
+
+use crate::referer::Referers;
+use crate::storage::VisitStore;
+
+/// Counts which pages embedded each counter, for counters per origin. Its keys
+/// are `<referer> <path>`, so any storage backend can hold them. With
+/// `--referer-salt` the path is hashed like the referer.
+pub struct Paths {
+    store: Arc<dyn VisitStore>,
+}
+
+impl Paths {
+    pub fn new(store: Arc<dyn VisitStore>) -> Self {
+        Self { store }
+    }
+
+    pub fn store(&self) -> &Arc<dyn VisitStore> {
+        &self.store
+    }
+
+    /// Counts a visit of `referer` from `page`, unless it isn't a URL.
+    pub async fn increment(
+        &self,
+        referer: &str,
+        page: &str,
+        referers: &Referers,
+    ) -> anyhow::Result<()> {
+        if Url::parse(page).is_err() {
+            return Ok(());
+        }
+        let path = referers.path(page);
+        self.store.increment(&format!("{referer} {path}")).await?;
+        Ok(())
+    }
+
+    /// The visits of every path of `referer`, busiest first.
+    pub async fn of(&self, referer: &str) -> anyhow::Result<Vec<(String, usize)>> {
+        let mut paths: Vec<_> = self
+            .store
+            .snapshot()
+            .await?
+            .into_iter()
+            .filter_map(|(key, count)| {
+                let (of, path) = key.rsplit_once(' ')?;
+                (of == referer).then(|| (path.to_string(), count))
+            })
+            .collect();
+        paths.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+        Ok(paths)
+    }
+
+    /// Drops the paths of `referers`.
+    pub async fn forget(&self, referers: &[String]) -> anyhow::Result<()> {
+        let keys: Vec<String> = self
+            .store
+            .snapshot()
+            .await?
+            .into_keys()
+            .filter(|key| {
+                key.rsplit_once(' ')
+                    .is_some_and(|(of, _)| referers.iter().any(|referer| referer == of))
+            })
+            .collect();
+        self.store.remove_many(&keys).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::referer::Granularity;
+    use crate::storage::MemoryStore;
+
+    #[tokio::test]
+    async fn hashes_paths_with_the_salt() {
+        let referers = Referers::new(Granularity::Origin, &[], Some(b"salt".to_vec()));
+        let paths = Paths::new(Arc::new(MemoryStore::default()));
+        let referer = referers.normalize("https://example.com/blog/post");
+        paths
+            .increment(&referer, "https://example.com/blog/post", &referers)
+            .await
+            .unwrap();
+
+        let counts = paths.of(&referer).await.unwrap();
+        assert_eq!(
+            counts,
+            [(referers.path("https://example.com/blog/post"), 1)]
+        );
+        assert!(!counts[0].0.contains("blog"));
+    }
+}
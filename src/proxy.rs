@@ -0,0 +1,180 @@
+use std::net::IpAddr;
+
+use hyper::header::{self, HeaderMap};
+use ipnet::IpNet;
+
+/// The address of the client behind any trusted proxies, stored in the request
+/// extensions.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientIp(pub IpAddr);
+
+/// Whether the client sent a request over HTTPS, stored in the request extensions.
+#[derive(Clone, Copy, Debug)]
+pub struct Https(pub bool);
+
+/// Whether a request from `peer`, that came in over TLS if `tls`, was sent by
+/// the client over HTTPS. Only trusted proxies may say so, with the first
+/// `proto=` of `Forwarded` or the first `X-Forwarded-Proto`.
+pub fn https(tls: bool, peer: IpAddr, headers: &HeaderMap, trusted: &[IpNet]) -> bool {
+    if tls {
+        return true;
+    }
+    if !trusted.iter().any(|net| net.contains(&peer)) {
+        return false;
+    }
+
+    let proto = if headers.contains_key(header::FORWARDED) {
+        headers
+            .get_all(header::FORWARDED)
+            .iter()
+            .flat_map(|v| v.to_str().unwrap_or_default().split(','))
+            .find_map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (key, value) = pair.trim().split_once('=')?;
+                    key.eq_ignore_ascii_case("proto")
+                        .then(|| value.trim_matches('"').to_string())
+                })
+            })
+    } else {
+        headers
+            .get("x-forwarded-proto")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(',').next())
+            .map(|proto| proto.trim().to_string())
+    };
+    proto.is_some_and(|proto| proto.eq_ignore_ascii_case("https"))
+}
+
+/// Finds the client address of a request from `peer`. Only trusted proxies may
+/// name it in `Forwarded` or `X-Forwarded-For`, so those are followed from the
+/// closest hop back until the first address that isn't a trusted proxy.
+pub fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted: &[IpNet]) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(ip));
+
+    if !is_trusted(&peer) {
+        return peer;
+    }
+
+    let hops = if headers.contains_key(header::FORWARDED) {
+        forwarded(headers)
+    } else {
+        x_forwarded_for(headers)
+    };
+
+    let mut client = peer;
+    for hop in hops.into_iter().rev() {
+        // Whatever a proxy got from an unparseable hop can't be trusted further.
+        let Some(hop) = hop else { break };
+        client = hop;
+        if !is_trusted(&hop) {
+            break;
+        }
+    }
+    client
+}
+
+/// The `for=` addresses of every `Forwarded` element, in order.
+fn forwarded(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all(header::FORWARDED)
+        .iter()
+        .flat_map(|v| v.to_str().unwrap_or_default().split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                key.eq_ignore_ascii_case("for")
+                    .then(|| parse_node(value.trim_matches('"')))
+            })
+        })
+        .collect()
+}
+
+fn x_forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .flat_map(|v| v.to_str().unwrap_or_default().split(','))
+        .map(|hop| parse_node(hop.trim()))
+        .collect()
+}
+
+/// Parses `1.2.3.4`, `1.2.3.4:5678`, `[::1]` and `[::1]:5678`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    node.rsplit_once(':')?.0.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn client(peer: &str, header: &'static str, value: &'static str) -> IpAddr {
+        let mut headers = HeaderMap::new();
+        headers.insert(header, HeaderValue::from_static(value));
+        let trusted = ["10.0.0.0/8".parse().unwrap()];
+        client_ip(peer.parse().unwrap(), &headers, &trusted)
+    }
+
+    #[test]
+    fn ignores_headers_from_untrusted_peers() {
+        let ip = client("203.0.113.9", "x-forwarded-for", "198.51.100.1");
+        assert_eq!(ip, "203.0.113.9".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn follows_trusted_hops_only() {
+        let ip = client("10.0.0.1", "x-forwarded-for", "198.51.100.1, 10.0.0.2");
+        assert_eq!(ip, "198.51.100.1".parse::<IpAddr>().unwrap());
+
+        // Whatever the client claims before the first untrusted hop is spoofable.
+        let ip = client("10.0.0.1", "x-forwarded-for", "192.0.2.66, 198.51.100.1");
+        assert_eq!(ip, "198.51.100.1".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn reads_forwarded_nodes() {
+        let ip = client(
+            "10.0.0.1",
+            "forwarded",
+            r#"for="[2001:db8::1]:4711";proto=https, for=10.0.0.2:80"#,
+        );
+        assert_eq!(ip, "2001:db8::1".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn stops_at_unparseable_hops() {
+        let ip = client("10.0.0.1", "x-forwarded-for", "198.51.100.1, unknown");
+        assert_eq!(ip, "10.0.0.1".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn trusts_the_proto_of_trusted_proxies_only() {
+        let https_from = |peer: &str, header: &'static str, value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header, HeaderValue::from_static(value));
+            let trusted = ["10.0.0.0/8".parse().unwrap()];
+            https(false, peer.parse().unwrap(), &headers, &trusted)
+        };
+
+        assert!(https_from("10.0.0.1", "x-forwarded-proto", "https"));
+        assert!(https_from(
+            "10.0.0.1",
+            "forwarded",
+            "for=192.0.2.1;proto=https"
+        ));
+        assert!(!https_from("10.0.0.1", "x-forwarded-proto", "http"));
+        assert!(!https_from("203.0.113.9", "x-forwarded-proto", "https"));
+        assert!(https(
+            true,
+            "203.0.113.9".parse().unwrap(),
+            &HeaderMap::new(),
+            &[]
+        ));
+    }
+}
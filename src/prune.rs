@@ -0,0 +1,102 @@
+use chrono::{DateTime, TimeDelta, Utc};
+
+use crate::admin::LastSeen;
+use crate::storage::VisitStore;
+
+/// Drops counters of referers that barely got any visits, like spam referers
+/// that only ever show up once, so the storage doesn't grow forever.
+pub struct Prune {
+    below: usize,
+    older_than: Option<TimeDelta>,
+    /// Stands in for the last-seen time of referers not counted since then.
+    started: DateTime<Utc>,
+}
+
+impl Prune {
+    pub fn new(below: usize, older_than: Option<TimeDelta>) -> Self {
+        Self {
+            below,
+            older_than,
+            started: Utc::now(),
+        }
+    }
+
+    /// Removes every counter below the threshold, all at once, and returns their
+    /// referers. With `older_than`, only those that weren't counted for that long,
+    /// so new referers get a chance to grow.
+    pub async fn run(
+        &self,
+        store: &dyn VisitStore,
+        last_seen: &LastSeen,
+    ) -> anyhow::Result<Vec<String>> {
+        let now = Utc::now();
+        let pruned: Vec<String> = store
+            .snapshot()
+            .await?
+            .into_iter()
+            .filter(|(_, count)| *count < self.below)
+            .filter(|(referer, _)| {
+                self.older_than.is_none_or(|older_than| {
+                    now - last_seen.get(referer).unwrap_or(self.started) >= older_than
+                })
+            })
+            .map(|(referer, _)| referer)
+            .collect();
+
+        let removed = store.remove_many(&pruned).await?;
+        for referer in &pruned {
+            last_seen.forget(referer);
+        }
+
+        if removed > 0 {
+            log::info!(
+                "Pruned {removed} referers with fewer than {} visits from {store}",
+                self.below
+            );
+        }
+        Ok(pruned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStore;
+
+    #[tokio::test]
+    async fn prunes_counters_below_the_threshold() {
+        let store = MemoryStore::default();
+        store.set("spam", 1).await.unwrap();
+        store.set("more-spam", 2).await.unwrap();
+        store.set("blog", 3).await.unwrap();
+
+        let mut pruned = Prune::new(3, None)
+            .run(&store, &LastSeen::default())
+            .await
+            .unwrap();
+        pruned.sort();
+
+        assert_eq!(pruned, ["more-spam", "spam"]);
+        assert_eq!(
+            store
+                .snapshot()
+                .await
+                .unwrap()
+                .into_keys()
+                .collect::<Vec<_>>(),
+            ["blog"]
+        );
+    }
+
+    #[tokio::test]
+    async fn spares_recently_counted_referers() {
+        let store = MemoryStore::default();
+        store.set("new", 1).await.unwrap();
+
+        let prune = Prune::new(3, Some(TimeDelta::hours(1)));
+        let pruned = prune.run(&store, &LastSeen::default()).await.unwrap();
+
+        assert!(pruned.is_empty());
+        assert_eq!(store.get("new").await.unwrap(), 1);
+    }
+}
@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+
+use clap::ValueEnum;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use url::Url;
+
+use crate::namespace::Namespace;
+use crate::storage::VisitStore;
+
+/// What part of the referer names a counter.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Granularity {
+    /// One counter per site, like `https://example.com`.
+    Origin,
+    /// One counter per page, like `https://example.com/blog`.
+    #[default]
+    Page,
+    /// One counter per path, like `/blog`, shared by every site.
+    Path,
+}
+
+/// Turns the URLs of one page into one counter name, so
+/// `https://Example.com:443/blog/?utm_source=x#top` counts for
+/// `https://example.com/blog`, or only its origin or path with `granularity`.
+///
+/// The host is lowercased, default ports, the query, fragment, credentials and
+/// a trailing slash are dropped. Anything that isn't a URL, like a `?key=`,
+/// is kept as it is.
+pub fn normalize(referer: &str, granularity: Granularity) -> String {
+    let Ok(mut url) = Url::parse(referer) else {
+        return referer.to_string();
+    };
+    if url.cannot_be_a_base() {
+        return referer.to_string();
+    }
+
+    let path = url.path();
+    let path = if path.len() > 1 {
+        path.trim_end_matches('/')
+    } else {
+        path
+    }
+    .to_string();
+
+    match granularity {
+        Granularity::Origin => url.origin().ascii_serialization(),
+        Granularity::Path => path,
+        Granularity::Page => {
+            url.set_query(None);
+            url.set_fragment(None);
+            // Only fails for URLs without a host, which have no credentials.
+            let _ = url.set_username("");
+            let _ = url.set_password(None);
+            url.set_path(&path);
+            url.into()
+        }
+    }
+}
+
+/// Whether `name`, as sent by a client, can name a counter. Storage keeps one
+/// counter per line, so whitespace and control characters are out.
+pub fn valid_name(name: &str) -> bool {
+    !name.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+/// Turns referers into counter names, with `--granularity`, `--alias` and
+/// `--referer-salt`.
+pub struct Referers {
+    granularity: Granularity,
+    /// Canonical hosts of sites known under several, by alias.
+    aliases: HashMap<String, String>,
+    /// Counters are named after a hash of their referer with this salt, if set.
+    salt: Option<Vec<u8>>,
+}
+
+impl Referers {
+    pub fn new(
+        granularity: Granularity,
+        aliases: &[(String, String)],
+        salt: Option<Vec<u8>>,
+    ) -> Self {
+        Self {
+            granularity,
+            aliases: aliases.iter().cloned().collect(),
+            salt,
+        }
+    }
+
+    /// The counter of `referer`, counting aliases as their canonical host.
+    pub fn normalize(&self, referer: &str) -> String {
+        let normalized = match self.resolve(referer) {
+            Some(resolved) => normalize(&resolved, self.granularity),
+            None => normalize(referer, self.granularity),
+        };
+        self.hash(normalized)
+    }
+
+    /// The path of `page`, hashed like counters if they are, so that the pages
+    /// of a site aren't kept in plain text either.
+    pub fn path(&self, page: &str) -> String {
+        self.hash(normalize(page, Granularity::Path))
+    }
+
+    /// `counter` as a salted hash, if counters are hashed. The hash can't be
+    /// turned back into the referer without the salt, but the same referer
+    /// always hashes to the same counter.
+    fn hash(&self, counter: String) -> String {
+        let Some(salt) = &self.salt else {
+            return counter;
+        };
+        let mut mac = Hmac::<Sha256>::new_from_slice(salt).expect("HMAC takes keys of any size");
+        mac.update(counter.as_bytes());
+        let hash = mac.finalize().into_bytes();
+        hash[..16].iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    /// Whether `counter` is already hashed.
+    fn is_hashed(&self, counter: &str) -> bool {
+        self.salt.is_some()
+            && counter.len() == 32
+            && counter
+                .bytes()
+                .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    }
+
+    /// `referer` on its canonical host, if it's on an alias.
+    fn resolve(&self, referer: &str) -> Option<String> {
+        let mut url = Url::parse(referer).ok()?;
+        let host = url.host_str()?.trim_end_matches('.').to_lowercase();
+        let canonical = self.aliases.get(&host)?;
+        url.set_host(Some(canonical)).ok()?;
+        Some(url.into())
+    }
+
+    /// Moves the counts of counters on aliases in `store` to their canonical
+    /// host, and of counters named after a plain referer to its hash if
+    /// counters are hashed, adding them to what's already there. Counters of
+    /// `namespaces` stay in their namespace.
+    pub async fn merge_aliases(
+        &self,
+        store: &dyn VisitStore,
+        namespaces: &[Namespace],
+    ) -> anyhow::Result<()> {
+        let mut merged = 0;
+        for (referer, count) in store.snapshot().await? {
+            let (namespace, counter) = namespaces
+                .iter()
+                .find_map(|ns| Some((Some(ns), referer.strip_prefix(&format!("{}:", ns.name))?)))
+                .unwrap_or((None, &referer));
+            if self.is_hashed(counter) {
+                continue;
+            }
+            let canonical = match self.resolve(counter) {
+                Some(resolved) => normalize(&resolved, self.granularity),
+                None if self.salt.is_some() => counter.to_string(),
+                None => continue,
+            };
+            let canonical = self.hash(canonical);
+            let canonical = match namespace {
+                Some(namespace) => namespace.key(&canonical),
+                None => canonical,
+            };
+            if canonical == referer {
+                continue;
+            }
+
+            let existing = store.get(&canonical).await?;
+            store
+                .set(&canonical, existing.saturating_add(count))
+                .await?;
+            store.remove(&referer).await?;
+            merged += 1;
+        }
+
+        if merged > 0 {
+            log::info!("Merged {merged} counters into their canonical name in {store}");
+        }
+        Ok(())
+    }
+}
+
+/// Parses `--alias` values like `www.example.com=example.com`.
+pub fn parse_alias(s: &str) -> Result<(String, String), String> {
+    let (alias, canonical) = s
+        .split_once('=')
+        .filter(|(alias, canonical)| !alias.is_empty() && !canonical.is_empty())
+        .ok_or_else(|| format!("{s:?} isn't like `www.example.com=example.com`"))?;
+    let host = |host: &str| host.trim_end_matches('.').to_lowercase();
+    Ok((host(alias), host(canonical)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStore;
+
+    fn salted() -> Referers {
+        Referers::new(Granularity::Page, &[], Some(b"salt".to_vec()))
+    }
+
+    #[tokio::test]
+    async fn hashes_namespaced_counters_once() {
+        let referers = salted();
+        let namespaces = [Namespace::new("blog".to_string(), Vec::new())];
+        let store = MemoryStore::default();
+        store.set("blog:https://example.com/a", 5).await.unwrap();
+
+        referers.merge_aliases(&store, &namespaces).await.unwrap();
+        let first = store.snapshot().await.unwrap();
+        referers.merge_aliases(&store, &namespaces).await.unwrap();
+        let second = store.snapshot().await.unwrap();
+
+        let key = format!("blog:{}", referers.normalize("https://example.com/a"));
+        assert_eq!(first.get(&key), Some(&5));
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn hashes_with_the_salt() {
+        let referers = salted();
+        let hash = referers.normalize("https://Example.com/blog/");
+
+        assert!(referers.is_hashed(&hash));
+        assert_eq!(hash, referers.normalize("https://example.com/blog"));
+        let other = Referers::new(Granularity::Page, &[], Some(b"pepper".to_vec()));
+        assert_ne!(hash, other.normalize("https://example.com/blog"));
+    }
+
+    #[tokio::test]
+    async fn keeps_hashed_counters() {
+        let referers = salted();
+        let store = MemoryStore::default();
+        store.set("https://example.com/a", 3).await.unwrap();
+
+        referers.merge_aliases(&store, &[]).await.unwrap();
+        let first = store.snapshot().await.unwrap();
+        referers.merge_aliases(&store, &[]).await.unwrap();
+
+        assert_eq!(first.len(), 1);
+        assert_eq!(store.snapshot().await.unwrap(), first);
+    }
+
+    #[test]
+    fn normalizes_urls_of_a_page() {
+        assert_eq!(
+            normalize(
+                "https://Example.com:443/blog/?utm_source=x#top",
+                Granularity::Page
+            ),
+            "https://example.com/blog"
+        );
+        assert_eq!(
+            normalize("https://example.com/blog/", Granularity::Origin),
+            "https://example.com"
+        );
+        assert_eq!(
+            normalize("https://example.com/blog/", Granularity::Path),
+            "/blog"
+        );
+        assert_eq!(normalize("my-key", Granularity::Page), "my-key");
+    }
+}
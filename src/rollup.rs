@@ -0,0 +1,291 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use chrono::{
+    DateTime, Datelike, Days, FixedOffset, Local, NaiveDate, NaiveDateTime, TimeDelta, Timelike,
+    Utc,
+};
+use clap::ValueEnum;
+
+use crate::storage::{VisitStore, Visits};
+
+/// How `/api/history` groups the counts.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Period {
+    /// `2024-03-10T13`
+    Hour,
+    /// `2024-03-10`
+    #[default]
+    Day,
+    /// ISO weeks, `2024-W10`
+    Week,
+    /// `2024-03`
+    Month,
+}
+
+impl Period {
+    fn bucket(self, at: NaiveDateTime) -> String {
+        match self {
+            Period::Hour => at.format("%Y-%m-%dT%H").to_string(),
+            Period::Day => at.format("%Y-%m-%d").to_string(),
+            Period::Week => {
+                let week = at.iso_week();
+                format!("{}-W{:02}", week.year(), week.week())
+            }
+            Period::Month => at.format("%Y-%m").to_string(),
+        }
+    }
+}
+
+/// Where days start, for `--timezone`.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum TimeZone {
+    #[default]
+    Utc,
+    /// The time zone of the server.
+    Local,
+    /// A fixed offset from UTC, like `+02:00`.
+    Fixed(FixedOffset),
+}
+
+impl TimeZone {
+    fn naive(self, at: DateTime<Utc>) -> NaiveDateTime {
+        match self {
+            TimeZone::Utc => at.naive_utc(),
+            TimeZone::Local => at.with_timezone(&Local).naive_local(),
+            TimeZone::Fixed(offset) => at.with_timezone(&offset).naive_local(),
+        }
+    }
+}
+
+impl FromStr for TimeZone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "utc" | "z" => Ok(TimeZone::Utc),
+            "local" => Ok(TimeZone::Local),
+            _ => s.parse().map(TimeZone::Fixed).map_err(|_| {
+                format!("{s:?} is neither `utc`, `local` nor an offset like `+02:00`")
+            }),
+        }
+    }
+}
+
+/// Counts visits per referer and day or hour, in a store of its own. Its keys
+/// are `<day or hour> <referer>`, so any storage backend can hold them.
+pub struct Series {
+    store: Arc<dyn VisitStore>,
+    resolution: Period,
+    /// How long counts are kept, forever without one.
+    retention: Option<TimeDelta>,
+    timezone: TimeZone,
+}
+
+impl Series {
+    pub fn daily(
+        store: Arc<dyn VisitStore>,
+        retention: Option<TimeDelta>,
+        timezone: TimeZone,
+    ) -> Self {
+        Self {
+            store,
+            resolution: Period::Day,
+            retention,
+            timezone,
+        }
+    }
+
+    pub fn hourly(store: Arc<dyn VisitStore>, retention: TimeDelta, timezone: TimeZone) -> Self {
+        Self {
+            store,
+            resolution: Period::Hour,
+            retention: Some(retention),
+            timezone,
+        }
+    }
+
+    pub fn store(&self) -> &Arc<dyn VisitStore> {
+        &self.store
+    }
+
+    /// Whether the counts can be grouped by `period`.
+    pub fn covers(&self, period: Period) -> bool {
+        self.resolution == Period::Hour || period != Period::Hour
+    }
+
+    /// Counts a visit of `referer` at `at`, returning the count of its day or hour.
+    pub async fn increment(&self, referer: &str, at: DateTime<Utc>) -> anyhow::Result<usize> {
+        let key = format!(
+            "{} {referer}",
+            self.resolution.bucket(self.timezone.naive(at))
+        );
+        self.store.increment(&key).await
+    }
+
+    /// The visits of `referer` on the day of `now`.
+    pub async fn today(&self, referer: &str, now: DateTime<Utc>) -> anyhow::Result<usize> {
+        let now = self.timezone.naive(now);
+        if self.resolution == Period::Day {
+            return self
+                .store
+                .get(&format!("{} {referer}", Period::Day.bucket(now)))
+                .await;
+        }
+
+        let mut today = 0;
+        for hour in 0..=now.hour() {
+            let at = now.date().and_hms_opt(hour, 0, 0).expect("valid hour");
+            today += self
+                .store
+                .get(&format!("{} {referer}", Period::Hour.bucket(at)))
+                .await?;
+        }
+        Ok(today)
+    }
+
+    /// The visits of `referer` in every `period` that had any, oldest first.
+    pub async fn history(
+        &self,
+        referer: &str,
+        period: Period,
+    ) -> anyhow::Result<BTreeMap<String, usize>> {
+        let mut history = BTreeMap::new();
+        for (key, count) in self.store.snapshot().await? {
+            if let Some(at) = time_of(&key).filter(|_| referer_of(&key) == Some(referer)) {
+                *history.entry(period.bucket(at)).or_insert(0) += count;
+            }
+        }
+        Ok(history)
+    }
+
+    /// The visits of `referer` on each of the last `days` days up to `now`, oldest first.
+    pub async fn last_days(
+        &self,
+        referer: &str,
+        days: u64,
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<Vec<usize>> {
+        let history = self.history(referer, Period::Day).await?;
+        let today = self.timezone.naive(now).date();
+        Ok((0..days)
+            .rev()
+            .map(|ago| {
+                let day = (today - Days::new(ago))
+                    .and_hms_opt(0, 0, 0)
+                    .expect("valid time");
+                history.get(&Period::Day.bucket(day)).copied().unwrap_or(0)
+            })
+            .collect())
+    }
+
+    /// The visits of every referer in the `period` of `now`, like this week.
+    pub async fn totals(&self, period: Period, now: DateTime<Utc>) -> anyhow::Result<Visits> {
+        let current = period.bucket(self.timezone.naive(now));
+        let mut totals = Visits::new();
+        for (key, count) in self.store.snapshot().await? {
+            let (Some(at), Some(referer)) = (time_of(&key), referer_of(&key)) else {
+                continue;
+            };
+            if period.bucket(at) == current {
+                *totals.entry(referer.to_string()).or_insert(0) += count;
+            }
+        }
+        Ok(totals)
+    }
+
+    /// Drops every count of `referers`.
+    pub async fn forget(&self, referers: &[String]) -> anyhow::Result<()> {
+        let keys: Vec<String> = self
+            .store
+            .snapshot()
+            .await?
+            .into_keys()
+            .filter(|key| {
+                referer_of(key).is_some_and(|of| referers.iter().any(|referer| referer == of))
+            })
+            .collect();
+        self.store.remove_many(&keys).await?;
+        Ok(())
+    }
+
+    /// Drops the counts older than the retention.
+    pub async fn expire(&self, now: DateTime<Utc>) -> anyhow::Result<()> {
+        let Some(retention) = self.retention else {
+            return Ok(());
+        };
+        let oldest = self.timezone.naive(now) - retention;
+
+        let keys: Vec<String> = self
+            .store
+            .snapshot()
+            .await?
+            .into_keys()
+            .filter(|key| time_of(key).is_some_and(|at| at < oldest))
+            .collect();
+        let expired = self.store.remove_many(&keys).await?;
+
+        if expired > 0 {
+            log::debug!("Expired {expired} counts from {}", self.store);
+        }
+        Ok(())
+    }
+}
+
+/// The start of the day or hour of `key`.
+fn time_of(key: &str) -> Option<NaiveDateTime> {
+    let (bucket, _) = key.split_once(' ')?;
+    match bucket.split_once('T') {
+        Some((day, hour)) => day
+            .parse::<NaiveDate>()
+            .ok()?
+            .and_hms_opt(hour.parse().ok()?, 0, 0),
+        None => bucket.parse::<NaiveDate>().ok()?.and_hms_opt(0, 0, 0),
+    }
+}
+
+fn referer_of(key: &str) -> Option<&str> {
+    key.split_once(' ').map(|(_, referer)| referer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStore;
+
+    #[tokio::test]
+    async fn expires_counts_past_the_retention() {
+        let store = Arc::new(MemoryStore::default());
+        let series = Series::daily(store.clone(), Some(TimeDelta::days(7)), TimeZone::Utc);
+        let now = Utc::now();
+        series
+            .increment("blog", now - TimeDelta::days(30))
+            .await
+            .unwrap();
+        series
+            .increment("blog", now - TimeDelta::days(20))
+            .await
+            .unwrap();
+        series.increment("blog", now).await.unwrap();
+
+        series.expire(now).await.unwrap();
+
+        assert_eq!(store.snapshot().await.unwrap().len(), 1);
+        assert_eq!(series.today("blog", now).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn forgets_only_the_given_referers() {
+        let store = Arc::new(MemoryStore::default());
+        let series = Series::daily(store.clone(), None, TimeZone::Utc);
+        let now = Utc::now();
+        series.increment("blog", now).await.unwrap();
+        series.increment("spam", now).await.unwrap();
+
+        series.forget(&[String::from("spam")]).await.unwrap();
+
+        assert_eq!(series.today("blog", now).await.unwrap(), 1);
+        assert_eq!(series.today("spam", now).await.unwrap(), 0);
+    }
+}
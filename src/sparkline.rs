@@ -0,0 +1,36 @@
+use std::fmt::Write;
+
+use crate::admin::escape;
+
+/// Draws `counts` as a line in a `width`×`height` SVG, oldest on the left and
+/// scaled so the busiest day touches the top.
+pub fn svg(counts: &[usize], width: u32, height: u32, color: &str) -> String {
+    let max = counts.iter().copied().max().unwrap_or(0).max(1) as f64;
+    let steps = counts.len().saturating_sub(1).max(1) as f64;
+    // Keeps the stroke inside the image.
+    let (w, h) = (f64::from(width) - 2.0, f64::from(height) - 2.0);
+
+    let mut points = String::new();
+    for (i, count) in counts.iter().enumerate() {
+        let x = 1.0 + w * i as f64 / steps;
+        let y = 1.0 + h - h * *count as f64 / max;
+        write!(points, "{x:.1},{y:.1} ").unwrap();
+    }
+
+    format!(
+        concat!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}">"#,
+            r#"<title>{total} visits in the last {days} days</title>"#,
+            r#"<polyline points="{points}" fill="none" stroke="{color}" stroke-width="1.5" stroke-linejoin="round" stroke-linecap="round"/>"#,
+            "</svg>\n",
+        ),
+        width = width,
+        height = height,
+        total = counts
+            .iter()
+            .fold(0usize, |total, v| total.saturating_add(*v)),
+        days = counts.len(),
+        points = points.trim_end(),
+        color = escape(color),
+    )
+}
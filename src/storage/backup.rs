@@ -0,0 +1,90 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::Context;
+use chrono::Utc;
+use tokio::fs;
+use tokio::sync::Mutex;
+
+use super::crypt::{self, Cipher};
+use super::{persist, StorageFormat, Visits};
+
+const PREFIX: &str = "visits-";
+
+/// Timestamped copies of the counters, written into a directory on every
+/// periodic save, keeping only the newest few.
+pub struct Backups {
+    dir: PathBuf,
+    keep: usize,
+    format: StorageFormat,
+    cipher: Option<Arc<Cipher>>,
+    /// The counters in the newest backup, so unchanged ones aren't written again.
+    last: Mutex<Option<Visits>>,
+}
+
+impl Backups {
+    pub fn new(
+        dir: PathBuf,
+        keep: usize,
+        format: StorageFormat,
+        cipher: Option<Arc<Cipher>>,
+    ) -> Self {
+        Self {
+            dir,
+            keep,
+            format,
+            cipher,
+            last: Mutex::new(None),
+        }
+    }
+
+    pub async fn write(&self, visits: &Visits) -> anyhow::Result<()> {
+        let mut last = self.last.lock().await;
+        if last.as_ref() == Some(visits) {
+            return Ok(());
+        }
+
+        fs::create_dir_all(&self.dir)
+            .await
+            .with_context(|| format!("Can't create backup directory {:?}", self.dir))?;
+
+        let name = format!(
+            "{PREFIX}{}.{}",
+            Utc::now().format("%Y%m%dT%H%M%S%.3fZ"),
+            self.format.extension()
+        );
+        let path = self.dir.join(name);
+
+        let data = crypt::seal_file(self.cipher.as_deref(), self.format.encode(visits)?)?;
+        persist::write_atomic(&path, &data)
+            .await
+            .with_context(|| format!("Can't write backup {path:?}"))?;
+        *last = Some(visits.clone());
+
+        self.prune().await
+    }
+
+    /// Deletes all but the newest `keep` backups. The timestamp in the name
+    /// sorts chronologically, so the oldest backups sort first.
+    async fn prune(&self) -> anyhow::Result<()> {
+        let suffix = format!(".{}", self.format.extension());
+
+        let mut backups = Vec::new();
+        let mut entries = fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with(PREFIX) && name.ends_with(&suffix) {
+                backups.push(name);
+            }
+        }
+        backups.sort();
+
+        let stale = backups.len().saturating_sub(self.keep);
+        for name in &backups[..stale] {
+            log::debug!("Removing old backup {name:?}");
+            fs::remove_file(self.dir.join(name)).await?;
+        }
+
+        Ok(())
+    }
+}
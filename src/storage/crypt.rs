@@ -0,0 +1,143 @@
+use anyhow::Context;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use sha2::{Digest, Sha256};
+
+/// Marks the start of an encrypted storage file.
+const MAGIC: &[u8] = b"itc-encrypted-v1\n";
+const NONCE_LEN: usize = 24;
+
+/// Encrypts storage with XChaCha20-Poly1305, keyed by the SHA-256 of a secret.
+pub struct Cipher {
+    aead: XChaCha20Poly1305,
+}
+
+impl Cipher {
+    pub fn new(secret: &[u8]) -> Self {
+        let key = Sha256::digest(secret);
+        Self {
+            aead: XChaCha20Poly1305::new(Key::from_slice(&key)),
+        }
+    }
+
+    /// Encrypts a single line, returned as base64 so it can't contain a newline.
+    pub fn seal_line(&self, plain: &str) -> anyhow::Result<String> {
+        Ok(BASE64.encode(self.seal(plain.as_bytes())?))
+    }
+
+    pub fn open_line(&self, line: &str) -> anyhow::Result<String> {
+        let sealed = BASE64.decode(line)?;
+        Ok(String::from_utf8(self.open(&sealed)?)?)
+    }
+
+    fn seal(&self, plain: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .aead
+            .encrypt(&nonce, plain)
+            .map_err(|_| anyhow::anyhow!("Encryption failed"))?;
+
+        let mut out = nonce.to_vec();
+        out.extend(ciphertext);
+        Ok(out)
+    }
+
+    fn open(&self, sealed: &[u8]) -> anyhow::Result<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            anyhow::bail!("Encrypted data is truncated");
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+
+        self.aead
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow::anyhow!("Decryption failed, is the storage key right?"))
+    }
+}
+
+/// Encrypts a whole file if there is a cipher, prefixing it with the magic.
+pub fn seal_file(cipher: Option<&Cipher>, plain: String) -> anyhow::Result<Vec<u8>> {
+    match cipher {
+        Some(cipher) => {
+            let mut out = MAGIC.to_vec();
+            out.extend(cipher.seal(plain.as_bytes())?);
+            Ok(out)
+        }
+        None => Ok(plain.into_bytes()),
+    }
+}
+
+/// Decrypts a whole file if it is encrypted. Plain files are read as they
+/// are, so turning encryption on doesn't need a separate migration.
+pub fn open_file(cipher: Option<&Cipher>, data: Vec<u8>) -> anyhow::Result<String> {
+    let plain = match data.strip_prefix(MAGIC) {
+        Some(sealed) => cipher
+            .context("Storage is encrypted, but no storage key was given")?
+            .open(sealed)?,
+        None => data,
+    };
+
+    Ok(String::from_utf8(plain)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::storage::{FileOptions, FileStore, VisitStore};
+
+    #[test]
+    fn round_trips() {
+        let cipher = Cipher::new(b"secret");
+        let sealed = seal_file(Some(&cipher), "https://a.com/ 5\n".to_string()).unwrap();
+        assert!(sealed.starts_with(MAGIC));
+        assert_eq!(
+            open_file(Some(&cipher), sealed).unwrap(),
+            "https://a.com/ 5\n"
+        );
+
+        let line = cipher.seal_line("https://a.com/").unwrap();
+        assert!(!line.contains('\n'));
+        assert_eq!(cipher.open_line(&line).unwrap(), "https://a.com/");
+    }
+
+    #[test]
+    fn refuses_the_wrong_key() {
+        let sealed = seal_file(Some(&Cipher::new(b"secret")), "data".to_string()).unwrap();
+        let wrong = Cipher::new(b"wrong");
+
+        assert!(open_file(Some(&wrong), sealed.clone()).is_err());
+        assert!(open_file(None, sealed.clone()).is_err());
+        assert!(open_file(Some(&wrong), sealed[..MAGIC.len() + 4].to_vec()).is_err());
+        assert!(wrong.open_line("not base64!").is_err());
+    }
+
+    #[tokio::test]
+    async fn wrong_key_fails_to_load() {
+        let dir = std::env::temp_dir().join(format!("itc-crypt-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("visits.txt");
+        let options = |secret: &[u8]| FileOptions {
+            cipher: Some(Arc::new(Cipher::new(secret))),
+            ..FileOptions::default()
+        };
+
+        let store = FileStore::open(path.clone(), options(b"secret"))
+            .await
+            .unwrap();
+        store.set("https://a.com/", 5).await.unwrap();
+        store.flush().await.unwrap();
+
+        let wrong = FileStore::open(path.clone(), options(b"wrong"))
+            .await
+            .unwrap();
+        assert!(wrong.load().await.is_err());
+
+        let right = FileStore::open(path, options(b"secret")).await.unwrap();
+        right.load().await.unwrap();
+        assert_eq!(right.get("https://a.com/").await.unwrap(), 5);
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,331 @@
+use std::ffi::OsString;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use tokio::sync::Mutex;
+
+use super::crypt::{self, Cipher};
+use super::wal::Wal;
+use super::{migrate, persist, MemoryStore, StorageFormat, VisitStore, Visits, VERSION};
+
+/// Settings for the file backend.
+#[derive(Clone, Default)]
+pub struct FileOptions {
+    pub format: StorageFormat,
+    /// Append each visit to a write-ahead log, compacting it into the
+    /// snapshot once it holds this many records.
+    pub wal_compact_after: Option<u64>,
+    /// Encrypt the file and the write-ahead log at rest.
+    pub cipher: Option<Arc<Cipher>>,
+    /// Load the lines that can be read instead of refusing a damaged file.
+    pub skip_bad_lines: bool,
+}
+
+impl FileOptions {
+    /// Reads the counters in `data`, and the lines skipped with `skip_bad_lines`.
+    fn decode(&self, data: &str) -> anyhow::Result<(Visits, Vec<String>)> {
+        match self.skip_bad_lines {
+            true => self.format.decode_skipping(data),
+            false => Ok((self.format.decode(data)?, Vec::new())),
+        }
+    }
+}
+
+/// Keeps every counter in a single file, atomically replaced on each flush.
+///
+/// With a write-ahead log, visits are appended to the log as they happen and
+/// flushing only rewrites the file once the log grows past the threshold.
+pub struct FileStore {
+    path: PathBuf,
+    options: FileOptions,
+    /// Also held while writing the snapshot, so writes never interleave.
+    wal: Mutex<Option<Wal>>,
+    visits: MemoryStore,
+}
+
+impl FileStore {
+    pub async fn open(path: PathBuf, options: FileOptions) -> anyhow::Result<Self> {
+        let wal = match options.wal_compact_after {
+            Some(_) => Some(Wal::open(&path, options.cipher.clone()).await?),
+            None => None,
+        };
+
+        Ok(Self {
+            path,
+            options,
+            wal: Mutex::new(wal),
+            visits: MemoryStore::default(),
+        })
+    }
+
+    /// Reads the counters of the file at `path` and its write-ahead log like
+    /// [`VisitStore::load`], but without writing anything: old files aren't
+    /// upgraded or backed up, and the log isn't created or compacted.
+    pub async fn read(path: &Path, options: &FileOptions) -> anyhow::Result<Visits> {
+        let data = match tokio::fs::read(path).await {
+            Ok(data) => crypt::open_file(options.cipher.as_deref(), data)
+                .with_context(|| format!("Can't decrypt {path:?}"))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err).with_context(|| format!("Can't read {path:?}")),
+        };
+
+        let (mut visits, skipped) = options
+            .decode(&data)
+            .with_context(|| format!("Can't read visits from {path:?}"))?;
+        if !skipped.is_empty() {
+            log::warn!(
+                "Skipped unreadable lines of {path:?}, {}",
+                skipped.join(", ")
+            );
+        }
+        if options.wal_compact_after.is_some() {
+            Wal::read(path, options.cipher.as_deref(), &mut visits)
+                .await
+                .with_context(|| format!("Can't replay the write-ahead log of {path:?}"))?;
+        }
+        Ok(visits)
+    }
+
+    /// A file next to the snapshot, named like it with `suffix` added.
+    fn beside(&self, suffix: &str) -> PathBuf {
+        let mut name = self
+            .path
+            .file_name()
+            .map(OsString::from)
+            .unwrap_or_default();
+        name.push(suffix);
+        self.path.with_file_name(name)
+    }
+
+    /// Writes the snapshot file, emptying the write-ahead log it now covers.
+    ///
+    /// Must be called with the log locked, so no visit lands in the log
+    /// between taking the snapshot and clearing the log.
+    async fn compact(&self, wal: &mut Option<Wal>, visits: &Visits) -> anyhow::Result<()> {
+        let data = self.options.format.encode(visits)?;
+        let data = crypt::seal_file(self.options.cipher.as_deref(), data)?;
+
+        persist::write_atomic(&self.path, &data)
+            .await
+            .with_context(|| format!("Can't write visits to {:?}", self.path))?;
+
+        if let Some(wal) = wal {
+            wal.clear()
+                .await
+                .with_context(|| format!("Can't clear {:?}", wal.path()))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl VisitStore for FileStore {
+    async fn load(&self) -> anyhow::Result<()> {
+        let read_visits = match tokio::fs::read(&self.path).await {
+            Ok(data) => crypt::open_file(self.options.cipher.as_deref(), data)
+                .with_context(|| format!("Can't decrypt {:?}", self.path))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Can't read {:?}", self.path));
+            }
+        };
+
+        let format = self.options.format;
+        let version = match read_visits.trim() {
+            "" => VERSION,
+            _ => migrate::version(format, &read_visits)
+                .with_context(|| format!("Can't read the version of {:?}", self.path))?,
+        };
+
+        let (mut visits, skipped) = self
+            .options
+            .decode(&read_visits)
+            .with_context(|| format!("Can't read visits from {:?}", self.path))?;
+
+        if !skipped.is_empty() {
+            // The skipped lines are gone from the next save, so keep them somewhere.
+            let backup = self.beside(".bad.bak");
+            tokio::fs::copy(&self.path, &backup).await?;
+            log::warn!(
+                "Skipped unreadable lines of {:?}, {}, the original is kept as {backup:?}",
+                self.path,
+                skipped.join(", ")
+            );
+        }
+
+        let outdated = version < VERSION;
+        if outdated {
+            // Keep the original around in case the upgrade went wrong.
+            let backup = self.beside(&format!(".v{version}.bak"));
+            tokio::fs::copy(&self.path, &backup).await?;
+            log::info!(
+                "Upgraded {:?} from v{version} to v{VERSION}, the original is kept as {backup:?}",
+                self.path
+            );
+        }
+
+        let mut wal = self.wal.lock().await;
+        if let Some(wal) = wal.as_mut() {
+            wal.replay(&mut visits)
+                .await
+                .with_context(|| format!("Can't replay {:?}", wal.path()))?;
+        }
+
+        let replayed = wal.as_ref().map(Wal::records).unwrap_or(0);
+        if replayed > 0 {
+            log::info!("Replayed {replayed} visits from the write-ahead log");
+        }
+
+        if replayed > 0 || outdated || !skipped.is_empty() {
+            self.compact(&mut wal, &visits).await?;
+        }
+
+        self.visits.replace(visits).await;
+        Ok(())
+    }
+
+    async fn increment(&self, server: &str) -> anyhow::Result<usize> {
+        let mut wal = self.wal.lock().await;
+        if let Some(wal) = wal.as_mut() {
+            wal.append(server).await?;
+        }
+
+        self.visits.increment(server).await
+    }
+
+    /// Changes that aren't a single visit don't fit the write-ahead log, so
+    /// with a log they are compacted into the file right away.
+    async fn set(&self, server: &str, count: usize) -> anyhow::Result<()> {
+        let mut wal = self.wal.lock().await;
+        self.visits.set(server, count).await?;
+
+        if wal.is_some() {
+            let visits = self.visits.counts().await;
+            self.compact(&mut wal, &visits).await?;
+        }
+        Ok(())
+    }
+
+    async fn get(&self, server: &str) -> anyhow::Result<usize> {
+        self.visits.get(server).await
+    }
+
+    async fn remove(&self, server: &str) -> anyhow::Result<bool> {
+        let mut wal = self.wal.lock().await;
+        let removed = self.visits.remove(server).await?;
+
+        if removed && wal.is_some() {
+            let visits = self.visits.counts().await;
+            self.compact(&mut wal, &visits).await?;
+        }
+        Ok(removed)
+    }
+
+    /// Compacts once for all of them, rather than once per counter.
+    async fn remove_many(&self, servers: &[String]) -> anyhow::Result<usize> {
+        let mut wal = self.wal.lock().await;
+        let removed = self.visits.remove_many(servers).await?;
+
+        if removed > 0 && wal.is_some() {
+            let visits = self.visits.counts().await;
+            self.compact(&mut wal, &visits).await?;
+        }
+        Ok(removed)
+    }
+
+    async fn flush(&self) -> anyhow::Result<()> {
+        let mut wal = self.wal.lock().await;
+
+        self.visits
+            .write_changes(|visits| async move {
+                match (wal.as_mut(), self.options.wal_compact_after) {
+                    (Some(log), Some(limit)) if log.records() < limit => log.sync().await,
+                    _ => self.compact(&mut wal, &visits).await,
+                }
+            })
+            .await
+    }
+
+    async fn snapshot(&self) -> anyhow::Result<Visits> {
+        Ok(self.visits.counts().await)
+    }
+}
+
+impl fmt::Display for FileStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reading_leaves_old_files_alone() {
+        let dir = std::env::temp_dir().join(format!("itc-read-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("visits.txt");
+        tokio::fs::write(&path, "https://a.com/ 5\n").await.unwrap();
+
+        let options = FileOptions {
+            wal_compact_after: Some(1000),
+            ..FileOptions::default()
+        };
+        let visits = FileStore::read(&path, &options).await.unwrap();
+
+        assert_eq!(visits.get("https://a.com/"), Some(&5));
+        assert_eq!(
+            tokio::fs::read_to_string(&path).await.unwrap(),
+            "https://a.com/ 5\n"
+        );
+        let mut files = tokio::fs::read_dir(&dir).await.unwrap();
+        let mut count = 0;
+        while files.next_entry().await.unwrap().is_some() {
+            count += 1;
+        }
+        assert_eq!(count, 1);
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn skips_bad_lines_only_when_asked() {
+        let dir = std::env::temp_dir().join(format!("itc-bad-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("visits.txt");
+        let data =
+            format!("# iframe-traffic-counter storage v{VERSION}\nhttps://a.com/ 5\nbroken\n");
+        tokio::fs::write(&path, &data).await.unwrap();
+
+        let strict = FileStore::open(path.clone(), FileOptions::default())
+            .await
+            .unwrap();
+        assert!(strict.load().await.is_err());
+
+        let options = FileOptions {
+            skip_bad_lines: true,
+            ..FileOptions::default()
+        };
+        let store = FileStore::open(path.clone(), options).await.unwrap();
+        store.load().await.unwrap();
+
+        assert_eq!(store.get("https://a.com/").await.unwrap(), 5);
+        assert_eq!(
+            tokio::fs::read_to_string(dir.join("visits.txt.bad.bak"))
+                .await
+                .unwrap(),
+            data
+        );
+        FileStore::open(path, FileOptions::default())
+            .await
+            .unwrap()
+            .load()
+            .await
+            .unwrap();
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,204 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use super::{migrate, Visits};
+
+/// The version of the storage layout written by this build. Older files are
+/// upgraded by [`migrate`] when they are read.
+pub const VERSION: u32 = 3;
+
+/// The first line of text storage files, followed by the version.
+pub const TEXT_HEADER: &str = "# iframe-traffic-counter storage v";
+
+/// How the visits file is laid out on disk.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StorageFormat {
+    /// A version header, then one `server count` pair per line, with
+    /// backslashes, line breaks and a leading `#` in servers escaped.
+    #[default]
+    Text,
+    /// A JSON document mapping each server to its count, plus metadata.
+    Json,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonVisits {
+    version: u32,
+    #[serde(default)]
+    updated_at: Option<DateTime<Utc>>,
+    visits: BTreeMap<String, usize>,
+}
+
+impl StorageFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            StorageFormat::Text => "txt",
+            StorageFormat::Json => "json",
+        }
+    }
+
+    /// Reads the counters from `data`, upgrading older layouts first.
+    pub fn decode(self, data: &str) -> anyhow::Result<Visits> {
+        let (visits, corrupt) = self.decode_skipping(data)?;
+        if !corrupt.is_empty() {
+            anyhow::bail!(
+                "Some lines aren't `server count` pairs, {}. Fix them, or pass \
+                 `--skip-bad-lines` to load the rest",
+                corrupt.join(", ")
+            );
+        }
+        Ok(visits)
+    }
+
+    /// Like [`StorageFormat::decode`], but skips the lines that can't be read
+    /// instead of failing, returning them like `line 3 "broken"`.
+    pub fn decode_skipping(self, data: &str) -> anyhow::Result<(Visits, Vec<String>)> {
+        if data.trim().is_empty() {
+            return Ok((Visits::default(), Vec::new()));
+        }
+
+        let data = migrate::upgrade(self, data)?;
+
+        match self {
+            StorageFormat::Text => Ok(decode_text(&data)),
+            StorageFormat::Json => {
+                let json: JsonVisits = serde_json::from_str(&data)?;
+                Ok((json.visits.into_iter().collect(), Vec::new()))
+            }
+        }
+    }
+
+    pub fn encode(self, visits: &Visits) -> anyhow::Result<String> {
+        match self {
+            StorageFormat::Text => Ok(encode_text(visits)),
+            StorageFormat::Json => {
+                let json = JsonVisits {
+                    version: VERSION,
+                    updated_at: Some(Utc::now()),
+                    visits: visits.iter().map(|(k, v)| (k.clone(), *v)).collect(),
+                };
+                Ok(serde_json::to_string_pretty(&json)? + "\n")
+            }
+        }
+    }
+}
+
+/// `server` made safe for a line of its own: backslashes and line breaks are
+/// escaped, as is a leading `#`, which would read as a comment.
+pub fn escape(server: &str) -> Cow<'_, str> {
+    if !server.starts_with('#') && !server.contains(['\\', '\n', '\r']) {
+        return Cow::Borrowed(server);
+    }
+
+    let mut escaped = String::with_capacity(server.len() + 2);
+    if server.starts_with('#') {
+        escaped.push('\\');
+    }
+    for c in server.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+/// Undoes [`escape`]. Unknown escapes are kept as they are.
+pub fn unescape(server: &str) -> Cow<'_, str> {
+    if !server.contains('\\') {
+        return Cow::Borrowed(server);
+    }
+
+    let mut unescaped = String::with_capacity(server.len());
+    let mut chars = server.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some(c @ ('\\' | '#')) => unescaped.push(c),
+            Some(c) => {
+                unescaped.push('\\');
+                unescaped.push(c);
+            }
+            None => unescaped.push('\\'),
+        }
+    }
+    Cow::Owned(unescaped)
+}
+
+fn decode_text(data: &str) -> (Visits, Vec<String>) {
+    let mut visits = Visits::default();
+    // Every one of them, so a damaged file can be fixed in one go.
+    let mut corrupt = Vec::new();
+
+    for (i, line) in data.lines().enumerate() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let Some((server, v)) = line
+            .rsplit_once(' ')
+            .and_then(|(server, v)| Some((server, v.parse::<usize>().ok()?)))
+        else {
+            corrupt.push(format!("line {} {line:?}", i + 1));
+            continue;
+        };
+
+        visits.insert(unescape(server).into_owned(), v);
+    }
+
+    (visits, corrupt)
+}
+
+fn encode_text(visits: &Visits) -> String {
+    let mut servers: Vec<_> = visits.iter().collect();
+    servers.sort();
+
+    servers
+        .into_iter()
+        .fold(format!("{TEXT_HEADER}{VERSION}\n"), |s, (server, v)| {
+            format!("{s}{} {v}\n", escape(server))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_back_every_name_it_writes() {
+        let visits: Visits = ["a\nb 1", "#not-a-comment", "C:\\visits\\n", "\r", "", "a b"]
+            .into_iter()
+            .enumerate()
+            .map(|(i, server)| (server.to_string(), i))
+            .collect();
+
+        let encoded = StorageFormat::Text.encode(&visits).unwrap();
+        assert_eq!(encoded.lines().count(), visits.len() + 1);
+        assert_eq!(StorageFormat::Text.decode(&encoded).unwrap(), visits);
+    }
+
+    #[test]
+    fn names_the_lines_it_cant_read() {
+        let data = format!("{TEXT_HEADER}{VERSION}\nhttps://a.com/ 1\nbroken\nhttps://b.com/ x\n");
+
+        let err = StorageFormat::Text.decode(&data).unwrap_err().to_string();
+        assert!(err.contains(r#"line 3 "broken""#), "{err}");
+        assert!(err.contains(r#"line 4 "https://b.com/ x""#), "{err}");
+
+        let (visits, skipped) = StorageFormat::Text.decode_skipping(&data).unwrap();
+        assert_eq!(visits.get("https://a.com/"), Some(&1));
+        assert_eq!(visits.len(), 1);
+        assert_eq!(skipped.len(), 2);
+    }
+}
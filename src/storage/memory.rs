@@ -0,0 +1,121 @@
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use async_trait::async_trait;
+use tokio::sync::Mutex;
+
+use super::{VisitStore, Visits};
+
+/// Keeps counters in memory only, they are lost on shutdown.
+///
+/// Also used by the persistent stores to hold counters between flushes.
+#[derive(Default)]
+pub struct MemoryStore {
+    visits: Mutex<Visits>,
+    /// Set whenever a counter changes, cleared by [`MemoryStore::write_changes`].
+    dirty: AtomicBool,
+}
+
+impl MemoryStore {
+    pub async fn replace(&self, visits: Visits) {
+        *self.visits.lock().await = visits;
+    }
+
+    pub async fn counts(&self) -> Visits {
+        self.visits.lock().await.clone()
+    }
+
+    /// Hands the counters to `write` if any changed since the last write,
+    /// so quiet servers don't rewrite their storage for nothing.
+    ///
+    /// If `write` fails, the next call tries again.
+    pub async fn write_changes<F, Fut>(&self, write: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(Visits) -> Fut,
+        Fut: Future<Output = anyhow::Result<()>>,
+    {
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+
+        let result = write(self.counts().await).await;
+        if result.is_err() {
+            self.dirty.store(true, Ordering::Release);
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl VisitStore for MemoryStore {
+    async fn load(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn increment(&self, server: &str) -> anyhow::Result<usize> {
+        let mut lock = self.visits.lock().await;
+        let visit = lock.entry(server.to_string()).or_insert(0);
+        // The admin API can set a counter all the way up to usize::MAX.
+        *visit = visit.saturating_add(1);
+        self.dirty.store(true, Ordering::Release);
+        Ok(*visit)
+    }
+
+    async fn set(&self, server: &str, count: usize) -> anyhow::Result<()> {
+        self.visits.lock().await.insert(server.to_string(), count);
+        self.dirty.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    async fn get(&self, server: &str) -> anyhow::Result<usize> {
+        Ok(self.visits.lock().await.get(server).copied().unwrap_or(0))
+    }
+
+    async fn remove(&self, server: &str) -> anyhow::Result<bool> {
+        let removed = self.visits.lock().await.remove(server).is_some();
+        if removed {
+            self.dirty.store(true, Ordering::Release);
+        }
+        Ok(removed)
+    }
+
+    async fn remove_many(&self, servers: &[String]) -> anyhow::Result<usize> {
+        let mut visits = self.visits.lock().await;
+        let removed = servers
+            .iter()
+            .filter(|server| visits.remove(*server).is_some())
+            .count();
+        if removed > 0 {
+            self.dirty.store(true, Ordering::Release);
+        }
+        Ok(removed)
+    }
+
+    async fn flush(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn snapshot(&self) -> anyhow::Result<Visits> {
+        Ok(self.visits.lock().await.clone())
+    }
+}
+
+impl fmt::Display for MemoryStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "memory://")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn increments_stop_at_the_largest_count() {
+        let store = MemoryStore::default();
+        store.set("https://a.com/", usize::MAX).await.unwrap();
+        assert_eq!(store.increment("https://a.com/").await.unwrap(), usize::MAX);
+        assert_eq!(store.get("https://a.com/").await.unwrap(), usize::MAX);
+    }
+}
@@ -0,0 +1,186 @@
+//! Upgrades storage files written by older versions to the current layout.
+//!
+//! Every layout change bumps [`VERSION`] and adds a step to [`MIGRATIONS`]
+//! that turns a file of the previous version into the next one.
+
+use std::collections::BTreeMap;
+
+use super::format::{escape, StorageFormat, TEXT_HEADER, VERSION};
+
+type Migration = fn(StorageFormat, &str) -> anyhow::Result<String>;
+
+/// `MIGRATIONS[n]` upgrades a version `n + 1` file to version `n + 2`.
+const MIGRATIONS: &[Migration] = &[v1_to_v2, v2_to_v3];
+
+/// Returns the layout version `data` was written with.
+pub fn version(format: StorageFormat, data: &str) -> anyhow::Result<u32> {
+    match format {
+        StorageFormat::Text => {
+            let first = data.lines().next().unwrap_or_default();
+            match first.strip_prefix(TEXT_HEADER) {
+                Some(v) => Ok(v.trim().parse()?),
+                // The original files had no header at all.
+                None => Ok(1),
+            }
+        }
+        StorageFormat::Json => {
+            let json: serde_json::Value = serde_json::from_str(data)?;
+            match json.get("version") {
+                Some(v) => Ok(v
+                    .as_u64()
+                    .ok_or_else(|| anyhow::anyhow!("Invalid version {v}"))?
+                    as u32),
+                None => Ok(1),
+            }
+        }
+    }
+}
+
+/// Runs every migration needed to bring `data` up to [`VERSION`].
+pub fn upgrade(format: StorageFormat, data: &str) -> anyhow::Result<String> {
+    let from = version(format, data)?;
+
+    if from == 0 {
+        anyhow::bail!("Storage claims to be v0, versions start at v1");
+    }
+    if from > VERSION {
+        anyhow::bail!(
+            "Storage was written by a newer version (v{from}), this build only understands up to v{VERSION}"
+        );
+    }
+
+    let mut data = data.to_string();
+    for (i, migration) in MIGRATIONS
+        .iter()
+        .enumerate()
+        .skip(from.saturating_sub(1) as usize)
+    {
+        log::info!("Migrating storage from v{} to v{}", i + 1, i + 2);
+        data = migration(format, &data)?;
+    }
+
+    Ok(data)
+}
+
+/// v1 text files had no header and silently skipped lines that didn't parse,
+/// those are now reported and dropped here once. v1 JSON only lacks the
+/// `version` field.
+fn v1_to_v2(format: StorageFormat, data: &str) -> anyhow::Result<String> {
+    match format {
+        StorageFormat::Text => {
+            let mut visits = BTreeMap::new();
+
+            for (i, line) in data.lines().enumerate() {
+                let mut split = line.split(' ');
+                let parsed = split
+                    .next()
+                    .zip(split.next())
+                    .and_then(|(server, v)| Some((server, v.parse::<usize>().ok()?)));
+
+                match parsed {
+                    Some((server, v)) => {
+                        visits.insert(server, v);
+                    }
+                    None if line.is_empty() => {}
+                    None => log::warn!("Dropping unreadable line {}: {line:?}", i + 1),
+                }
+            }
+
+            Ok(visits
+                .into_iter()
+                .fold(format!("{TEXT_HEADER}2\n"), |s, (server, v)| {
+                    format!("{s}{server} {v}\n")
+                }))
+        }
+        StorageFormat::Json => {
+            let mut json: serde_json::Value = serde_json::from_str(data)?;
+            if let Some(json) = json.as_object_mut() {
+                json.insert("version".to_string(), 2.into());
+            }
+            Ok(serde_json::to_string(&json)?)
+        }
+    }
+}
+
+/// v3 text files escape servers, so those with a backslash in v2 get it
+/// escaped. v2 couldn't hold line breaks, and a leading `#` was a comment.
+/// JSON only gets the new `version`.
+fn v2_to_v3(format: StorageFormat, data: &str) -> anyhow::Result<String> {
+    match format {
+        StorageFormat::Text => {
+            Ok(data
+                .lines()
+                .skip(1)
+                .fold(format!("{TEXT_HEADER}3\n"), |s, line| {
+                    match line.rsplit_once(' ') {
+                        Some((server, v)) if !line.starts_with('#') => {
+                            format!("{s}{} {v}\n", escape(server))
+                        }
+                        // Unreadable lines are left to be reported when decoding.
+                        _ => format!("{s}{line}\n"),
+                    }
+                }))
+        }
+        StorageFormat::Json => {
+            let mut json: serde_json::Value = serde_json::from_str(data)?;
+            if let Some(json) = json.as_object_mut() {
+                json.insert("version".to_string(), 3.into());
+            }
+            Ok(serde_json::to_string(&json)?)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_version_0() {
+        let data = format!("{TEXT_HEADER}0\nhttps://a.com/ 1\n");
+        assert!(upgrade(StorageFormat::Text, &data).is_err());
+        assert!(upgrade(StorageFormat::Json, r#"{"version": 0, "visits": {}}"#).is_err());
+    }
+
+    #[test]
+    fn upgrades_version_1() {
+        let upgraded = upgrade(
+            StorageFormat::Text,
+            "https://b.com/ 2\nbroken\nhttps://a.com/ 1\n",
+        )
+        .unwrap();
+        assert_eq!(
+            upgraded,
+            format!("{TEXT_HEADER}{VERSION}\nhttps://a.com/ 1\nhttps://b.com/ 2\n")
+        );
+        assert_eq!(version(StorageFormat::Text, &upgraded).unwrap(), VERSION);
+
+        let upgraded = upgrade(StorageFormat::Json, r#"{"visits": {}}"#).unwrap();
+        assert_eq!(version(StorageFormat::Json, &upgraded).unwrap(), VERSION);
+    }
+
+    #[test]
+    fn escapes_backslashes_of_version_2() {
+        let data = format!("{TEXT_HEADER}2\nC:\\visits 1\nbroken\n");
+        let upgraded = upgrade(StorageFormat::Text, &data).unwrap();
+        assert_eq!(
+            upgraded,
+            format!("{TEXT_HEADER}3\nC:\\\\visits 1\nbroken\n")
+        );
+
+        let visits = StorageFormat::Text.decode_skipping(&data).unwrap().0;
+        assert_eq!(visits.get("C:\\visits"), Some(&1));
+    }
+
+    #[test]
+    fn leaves_the_current_version_alone() {
+        let data = format!("{TEXT_HEADER}{VERSION}\nhttps://a.com/ 1\n");
+        assert_eq!(upgrade(StorageFormat::Text, &data).unwrap(), data);
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let data = format!("{TEXT_HEADER}{}\n", VERSION + 1);
+        assert!(upgrade(StorageFormat::Text, &data).is_err());
+    }
+}
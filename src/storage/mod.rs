@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Context;
+use async_trait::async_trait;
+
+#[cfg(feature = "redis")]
+pub use self::redis::RedisStore;
+#[cfg(feature = "sled")]
+pub use self::sled::SledStore;
+pub use backup::Backups;
+pub use crypt::{seal_file, Cipher};
+pub use file::{FileOptions, FileStore};
+pub use format::{StorageFormat, VERSION};
+pub use memory::MemoryStore;
+pub use persist::write_atomic;
+#[cfg(feature = "postgres")]
+pub use postgres::PostgresStore;
+pub use s3::{S3Config, S3Sync};
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStore;
+pub use write_behind::WriteBehind;
+
+mod backup;
+mod crypt;
+mod file;
+mod format;
+mod memory;
+mod migrate;
+mod persist;
+#[cfg(feature = "postgres")]
+mod postgres;
+#[cfg(feature = "redis")]
+mod redis;
+mod s3;
+#[cfg(feature = "sled")]
+mod sled;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod wal;
+mod write_behind;
+
+pub type Visits = HashMap<String, usize>;
+
+/// Adds up every counter, stopping at `usize::MAX` rather than overflowing.
+pub fn total(visits: &Visits) -> usize {
+    visits.values().fold(0, |total, v| total.saturating_add(*v))
+}
+
+/// A place visit counters are kept and persisted.
+///
+/// Stores are shared between every connection task, so all methods take
+/// `&self` and implementations handle their own locking.
+#[async_trait]
+pub trait VisitStore: Display + Send + Sync {
+    /// Reads the persisted counters into the store. Called once on startup.
+    async fn load(&self) -> anyhow::Result<()>;
+
+    /// Counts one visit for `server`, returning the new total.
+    async fn increment(&self, server: &str) -> anyhow::Result<usize>;
+
+    /// Overwrites the count of `server`, e.g. when importing old stats.
+    async fn set(&self, server: &str, count: usize) -> anyhow::Result<()>;
+
+    /// Returns the count of `server`, 0 if it has none, without reading every
+    /// other counter.
+    async fn get(&self, server: &str) -> anyhow::Result<usize>;
+
+    /// Drops the counter of `server`, returning whether it had one.
+    async fn remove(&self, server: &str) -> anyhow::Result<bool>;
+
+    /// Drops the counters of all `servers` at once, returning how many had one.
+    async fn remove_many(&self, servers: &[String]) -> anyhow::Result<usize>;
+
+    /// Writes any counters that haven't been persisted yet.
+    async fn flush(&self) -> anyhow::Result<()>;
+
+    /// Returns the current count of every server.
+    async fn snapshot(&self) -> anyhow::Result<Visits>;
+}
+
+/// Opens the store described by the `--storage` value.
+///
+/// `memory://` keeps counters in memory only, `sqlite://<path>` and
+/// `sled://<path>` select the embedded SQLite and sled backends, `redis://`
+/// and `postgres://` URLs select the Redis and PostgreSQL backends, and
+/// anything else is treated as a path to a file stored according to `file`.
+pub async fn open(spec: &str, file: FileOptions) -> anyhow::Result<Arc<dyn VisitStore>> {
+    if spec == "memory://" {
+        return Ok(Arc::new(MemoryStore::default()));
+    }
+
+    if let Some(path) = spec.strip_prefix("sqlite://") {
+        #[cfg(feature = "sqlite")]
+        return Ok(Arc::new(SqliteStore::open(PathBuf::from(path)).await?));
+
+        #[cfg(not(feature = "sqlite"))]
+        anyhow::bail!("Can't open {path:?}, SQLite storage requires the `sqlite` feature");
+    }
+
+    if let Some(path) = spec.strip_prefix("sled://") {
+        #[cfg(feature = "sled")]
+        return Ok(Arc::new(SledStore::open(PathBuf::from(path))?));
+
+        #[cfg(not(feature = "sled"))]
+        anyhow::bail!("Can't open {path:?}, sled storage requires the `sled` feature");
+    }
+
+    if spec.starts_with("redis://") {
+        #[cfg(feature = "redis")]
+        return Ok(Arc::new(RedisStore::open(spec).await?));
+
+        #[cfg(not(feature = "redis"))]
+        anyhow::bail!("Can't open {spec:?}, Redis storage requires the `redis` feature");
+    }
+
+    if spec.starts_with("postgres://") || spec.starts_with("postgresql://") {
+        #[cfg(feature = "postgres")]
+        return Ok(Arc::new(PostgresStore::open(spec).await?));
+
+        #[cfg(not(feature = "postgres"))]
+        anyhow::bail!("Can't open PostgreSQL storage, it requires the `postgres` feature");
+    }
+
+    let path = file_path(spec).with_context(|| format!("Unknown storage {spec:?}"))?;
+    Ok(Arc::new(FileStore::open(path, file).await?))
+}
+
+/// Reads the counters of the store described by `spec` without changing it,
+/// to check it. Files aren't upgraded and their write-ahead log isn't
+/// compacted, and embedded databases that don't exist yet aren't created.
+/// Servers are opened as usual.
+pub async fn read(spec: &str, file: FileOptions) -> anyhow::Result<Visits> {
+    if let Some(path) = file_path(spec) {
+        return FileStore::read(&path, &file).await;
+    }
+
+    let embedded = spec
+        .strip_prefix("sqlite://")
+        .or_else(|| spec.strip_prefix("sled://"));
+    if embedded.is_some_and(|path| !Path::new(path).exists()) {
+        return Ok(Visits::default());
+    }
+
+    let store = open(spec, file).await?;
+    store.load().await?;
+    store.snapshot().await
+}
+
+/// Returns the path of the file `spec` names, if it selects the file backend.
+pub fn file_path(spec: &str) -> Option<PathBuf> {
+    (!spec.contains("://")).then(|| PathBuf::from(spec))
+}
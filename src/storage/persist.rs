@@ -0,0 +1,45 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+
+/// Replaces the contents of `path` with `data` without ever leaving a
+/// truncated file behind.
+///
+/// The data is written and fsynced to a temporary file next to `path`, which
+/// is then renamed over it. The rename is atomic, so a crash at any point
+/// leaves either the old or the new contents.
+pub async fn write_atomic(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let tmp_path = tmp_path(path);
+
+    let mut tmp = File::create(&tmp_path).await?;
+    tmp.write_all(data).await?;
+    tmp.sync_all().await?;
+    drop(tmp);
+
+    fs::rename(&tmp_path, path).await?;
+    sync_dir(path).await
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+/// Fsyncs the directory holding `path`, so the rename itself is durable.
+#[cfg(unix)]
+async fn sync_dir(path: &Path) -> anyhow::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir).await?.sync_all().await?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn sync_dir(_path: &Path) -> anyhow::Result<()> {
+    Ok(())
+}
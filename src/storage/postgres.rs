@@ -0,0 +1,125 @@
+use std::fmt;
+
+use async_trait::async_trait;
+use tokio_postgres::{Client, NoTls};
+
+use super::{MemoryStore, VisitStore, Visits};
+
+/// Keeps one row per referer, upserted in a single batched statement on flush.
+pub struct PostgresStore {
+    client: Client,
+    visits: MemoryStore,
+}
+
+impl PostgresStore {
+    pub async fn open(url: &str) -> anyhow::Result<Self> {
+        let (client, connection) = tokio_postgres::connect(url, NoTls).await?;
+
+        tokio::spawn(async move {
+            if let Err(err) = connection.await {
+                log::error!("PostgreSQL connection error: {err:?}");
+            }
+        });
+
+        client
+            .batch_execute(
+                "CREATE TABLE IF NOT EXISTS visits (
+                    server TEXT PRIMARY KEY NOT NULL,
+                    count BIGINT NOT NULL
+                );",
+            )
+            .await?;
+
+        Ok(Self {
+            client,
+            visits: MemoryStore::default(),
+        })
+    }
+}
+
+#[async_trait]
+impl VisitStore for PostgresStore {
+    async fn load(&self) -> anyhow::Result<()> {
+        let rows = self
+            .client
+            .query("SELECT server, count FROM visits", &[])
+            .await?;
+
+        let mut visits = Visits::default();
+        for row in rows {
+            visits.insert(row.get(0), row.get::<_, i64>(1) as usize);
+        }
+
+        self.visits.replace(visits).await;
+        Ok(())
+    }
+
+    async fn increment(&self, server: &str) -> anyhow::Result<usize> {
+        self.visits.increment(server).await
+    }
+
+    async fn set(&self, server: &str, count: usize) -> anyhow::Result<()> {
+        self.visits.set(server, count).await
+    }
+
+    /// Every counter is held in memory between flushes, newer than the table.
+    async fn get(&self, server: &str) -> anyhow::Result<usize> {
+        self.visits.get(server).await
+    }
+
+    /// Flushes only upsert the counters that exist, so the row is deleted right away.
+    async fn remove(&self, server: &str) -> anyhow::Result<bool> {
+        if !self.visits.remove(server).await? {
+            return Ok(false);
+        }
+
+        self.client
+            .execute("DELETE FROM visits WHERE server = $1", &[&server])
+            .await?;
+        Ok(true)
+    }
+
+    async fn remove_many(&self, servers: &[String]) -> anyhow::Result<usize> {
+        let removed = self.visits.remove_many(servers).await?;
+        if removed == 0 {
+            return Ok(0);
+        }
+
+        self.client
+            .execute("DELETE FROM visits WHERE server = ANY($1)", &[&servers])
+            .await?;
+        Ok(removed)
+    }
+
+    async fn flush(&self) -> anyhow::Result<()> {
+        self.visits
+            .write_changes(|visits| async move {
+                let (servers, counts): (Vec<String>, Vec<i64>) = visits
+                    .into_iter()
+                    .map(|(server, v)| (server, i64::try_from(v).unwrap_or(i64::MAX)))
+                    .unzip();
+
+                self.client
+                    .execute(
+                        "INSERT INTO visits (server, count)
+                         SELECT * FROM UNNEST($1::TEXT[], $2::BIGINT[])
+                         ON CONFLICT (server) DO UPDATE SET count = EXCLUDED.count",
+                        &[&servers, &counts],
+                    )
+                    .await?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn snapshot(&self) -> anyhow::Result<Visits> {
+        Ok(self.visits.counts().await)
+    }
+}
+
+impl fmt::Display for PostgresStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The URL may carry a password, so only name the backend.
+        write!(f, "PostgreSQL")
+    }
+}
@@ -0,0 +1,103 @@
+use std::fmt;
+
+use async_trait::async_trait;
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+
+use super::{VisitStore, Visits};
+
+const KEY_PREFIX: &str = "visits:";
+
+/// Keeps every counter in Redis, so several instances can share them.
+///
+/// Each visit is a single `INCR`, there is nothing to load or flush.
+pub struct RedisStore {
+    conn: ConnectionManager,
+}
+
+impl RedisStore {
+    pub async fn open(url: &str) -> anyhow::Result<Self> {
+        let client = redis::Client::open(url)?;
+        let conn = client.get_connection_manager().await?;
+
+        Ok(Self { conn })
+    }
+}
+
+#[async_trait]
+impl VisitStore for RedisStore {
+    async fn load(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn increment(&self, server: &str) -> anyhow::Result<usize> {
+        let mut conn = self.conn.clone();
+        Ok(conn.incr(format!("{KEY_PREFIX}{server}"), 1).await?)
+    }
+
+    async fn set(&self, server: &str, count: usize) -> anyhow::Result<()> {
+        let mut conn = self.conn.clone();
+        let () = conn.set(format!("{KEY_PREFIX}{server}"), count).await?;
+        Ok(())
+    }
+
+    async fn get(&self, server: &str) -> anyhow::Result<usize> {
+        let mut conn = self.conn.clone();
+        let count: Option<usize> = conn.get(format!("{KEY_PREFIX}{server}")).await?;
+        Ok(count.unwrap_or(0))
+    }
+
+    async fn remove(&self, server: &str) -> anyhow::Result<bool> {
+        let mut conn = self.conn.clone();
+        let removed: usize = conn.del(format!("{KEY_PREFIX}{server}")).await?;
+        Ok(removed > 0)
+    }
+
+    async fn remove_many(&self, servers: &[String]) -> anyhow::Result<usize> {
+        if servers.is_empty() {
+            return Ok(0);
+        }
+        let keys: Vec<String> = servers
+            .iter()
+            .map(|server| format!("{KEY_PREFIX}{server}"))
+            .collect();
+        let mut conn = self.conn.clone();
+        Ok(conn.del(keys).await?)
+    }
+
+    async fn flush(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn snapshot(&self) -> anyhow::Result<Visits> {
+        let mut conn = self.conn.clone();
+
+        let keys: Vec<String> = {
+            let mut iter = conn.scan_match(format!("{KEY_PREFIX}*")).await?;
+            let mut keys = Vec::new();
+            while let Some(key) = iter.next_item().await {
+                keys.push(key?);
+            }
+            keys
+        };
+
+        if keys.is_empty() {
+            return Ok(Visits::default());
+        }
+
+        let counts: Vec<Option<usize>> = conn.mget(&keys).await?;
+
+        Ok(keys
+            .into_iter()
+            .zip(counts)
+            .filter_map(|(key, v)| Some((key.strip_prefix(KEY_PREFIX)?.to_string(), v?)))
+            .collect())
+    }
+}
+
+impl fmt::Display for RedisStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The URL may carry a password, so only name the backend.
+        write!(f, "Redis")
+    }
+}
@@ -0,0 +1,205 @@
+use std::sync::Arc;
+
+use anyhow::Context;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use reqwest::{Method, StatusCode, Url};
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+
+use super::crypt::{self, Cipher};
+use super::{StorageFormat, Visits};
+
+/// Credentials and location of an S3-compatible bucket.
+pub struct S3Config {
+    /// An `s3://bucket/key` URL naming the object to sync.
+    pub url: String,
+    /// The endpoint of an S3-compatible service, e.g. `http://localhost:9000`.
+    /// Objects are addressed path-style there. Defaults to AWS.
+    pub endpoint: Option<String>,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    pub session_token: Option<String>,
+}
+
+/// Uploads a copy of the counters to S3-compatible storage after saves, and
+/// fetches it back when the local storage is missing.
+pub struct S3Sync {
+    config: S3Config,
+    object: Url,
+    format: StorageFormat,
+    cipher: Option<Arc<Cipher>>,
+    client: reqwest::Client,
+    /// The counters last uploaded, so unchanged ones aren't uploaded again.
+    last: Mutex<Option<Visits>>,
+}
+
+impl S3Sync {
+    pub fn new(
+        config: S3Config,
+        format: StorageFormat,
+        cipher: Option<Arc<Cipher>>,
+    ) -> anyhow::Result<Self> {
+        let (bucket, key) = config
+            .url
+            .strip_prefix("s3://")
+            .and_then(|rest| rest.split_once('/'))
+            .filter(|(bucket, key)| !bucket.is_empty() && !key.is_empty())
+            .with_context(|| format!("{:?} isn't an `s3://bucket/key` URL", config.url))?;
+
+        let object = match &config.endpoint {
+            Some(endpoint) => format!(
+                "{}/{bucket}/{}",
+                endpoint.trim_end_matches('/'),
+                encode_path(key)
+            ),
+            None => format!(
+                "https://{bucket}.s3.{}.amazonaws.com/{}",
+                config.region,
+                encode_path(key)
+            ),
+        };
+
+        Ok(Self {
+            object: Url::parse(&object)?,
+            config,
+            format,
+            cipher,
+            client: reqwest::Client::new(),
+            last: Mutex::new(None),
+        })
+    }
+
+    pub async fn upload(&self, visits: &Visits) -> anyhow::Result<()> {
+        let mut last = self.last.lock().await;
+        if last.as_ref() == Some(visits) {
+            return Ok(());
+        }
+
+        let data = crypt::seal_file(self.cipher.as_deref(), self.format.encode(visits)?)?;
+        let response = self.request(Method::PUT, data).await?;
+        if !response.status().is_success() {
+            anyhow::bail!(
+                "Uploading to {} failed with {}: {}",
+                self.config.url,
+                response.status(),
+                response.text().await.unwrap_or_default()
+            );
+        }
+
+        *last = Some(visits.clone());
+        Ok(())
+    }
+
+    /// Fetches the synced object, or `None` if there isn't one yet.
+    pub async fn download(&self) -> anyhow::Result<Option<Vec<u8>>> {
+        let response = self.request(Method::GET, Vec::new()).await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => Ok(Some(response.bytes().await?.to_vec())),
+            status => anyhow::bail!(
+                "Downloading {} failed with {status}: {}",
+                self.config.url,
+                response.text().await.unwrap_or_default()
+            ),
+        }
+    }
+
+    /// Sends a request signed with AWS Signature Version 4.
+    async fn request(&self, method: Method, body: Vec<u8>) -> anyhow::Result<reqwest::Response> {
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = hex(&Sha256::digest(&body));
+
+        let host = match self.object.port() {
+            Some(port) => format!("{}:{port}", self.object.host_str().unwrap_or_default()),
+            None => self.object.host_str().unwrap_or_default().to_string(),
+        };
+
+        let mut headers = vec![
+            ("host", host),
+            ("x-amz-content-sha256", payload_hash.clone()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = &self.config.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{name}:{}\n", value.trim()))
+            .collect();
+
+        let canonical_request = format!(
+            "{method}\n{}\n\n{canonical_headers}\n{signed_headers}\n{payload_hash}",
+            self.object.path()
+        );
+
+        let scope = format!("{date}/{}/s3/aws4_request", self.config.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let key = [
+            date.as_str(),
+            self.config.region.as_str(),
+            "s3",
+            "aws4_request",
+        ]
+        .iter()
+        .fold(
+            format!("AWS4{}", self.config.secret_key).into_bytes(),
+            |key, part| hmac(&key, part.as_bytes()),
+        );
+        let signature = hex(&hmac(&key, string_to_sign.as_bytes()));
+
+        let mut request = self.client.request(method, self.object.clone()).header(
+            "authorization",
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+                self.config.access_key
+            ),
+        );
+        for (name, value) in headers.into_iter().filter(|(name, _)| *name != "host") {
+            request = request.header(name, value);
+        }
+
+        Ok(request.body(body).send().await?)
+    }
+}
+
+impl std::fmt::Display for S3Sync {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.config.url)
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Percent-encodes an object key the way SigV4 expects, keeping the slashes.
+fn encode_path(key: &str) -> String {
+    key.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
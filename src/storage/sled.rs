@@ -0,0 +1,89 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+
+use super::{VisitStore, Visits};
+
+/// Keeps counters in an embedded sled database.
+///
+/// Every visit is an atomic update of the referer's key, and flushing asks
+/// sled to make those durable, so no external service is needed.
+pub struct SledStore {
+    path: PathBuf,
+    db: sled::Db,
+}
+
+impl SledStore {
+    pub fn open(path: PathBuf) -> anyhow::Result<Self> {
+        let db = sled::open(&path)?;
+        Ok(Self { path, db })
+    }
+}
+
+#[async_trait]
+impl VisitStore for SledStore {
+    async fn load(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn increment(&self, server: &str) -> anyhow::Result<usize> {
+        let visit = self.db.update_and_fetch(server, |old| {
+            let visit = old.map(decode).unwrap_or(0).saturating_add(1);
+            Some(visit.to_be_bytes().to_vec())
+        })?;
+
+        Ok(visit.as_deref().map(decode).unwrap_or(0) as usize)
+    }
+
+    async fn set(&self, server: &str, count: usize) -> anyhow::Result<()> {
+        self.db
+            .insert(server, (count as u64).to_be_bytes().to_vec())?;
+        Ok(())
+    }
+
+    async fn get(&self, server: &str) -> anyhow::Result<usize> {
+        Ok(self.db.get(server)?.as_deref().map(decode).unwrap_or(0) as usize)
+    }
+
+    async fn remove(&self, server: &str) -> anyhow::Result<bool> {
+        Ok(self.db.remove(server)?.is_some())
+    }
+
+    async fn remove_many(&self, servers: &[String]) -> anyhow::Result<usize> {
+        let mut removed = 0;
+        for server in servers {
+            if self.db.remove(server)?.is_some() {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    async fn flush(&self) -> anyhow::Result<()> {
+        self.db.flush_async().await?;
+        Ok(())
+    }
+
+    async fn snapshot(&self) -> anyhow::Result<Visits> {
+        let mut visits = Visits::default();
+        for entry in self.db.iter() {
+            let (server, v) = entry?;
+            visits.insert(
+                String::from_utf8_lossy(&server).into_owned(),
+                decode(&v) as usize,
+            );
+        }
+        Ok(visits)
+    }
+}
+
+impl fmt::Display for SledStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sled://{}", self.path.display())
+    }
+}
+
+fn decode(bytes: &[u8]) -> u64 {
+    bytes.try_into().map(u64::from_be_bytes).unwrap_or(0)
+}
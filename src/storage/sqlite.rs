@@ -0,0 +1,153 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use rusqlite::Connection;
+
+use super::{MemoryStore, VisitStore, Visits};
+
+/// Keeps one row per referer, upserted inside a single transaction on flush.
+pub struct SqliteStore {
+    path: PathBuf,
+    conn: Arc<Mutex<Connection>>,
+    visits: MemoryStore,
+}
+
+impl SqliteStore {
+    pub async fn open(path: PathBuf) -> anyhow::Result<Self> {
+        let conn = {
+            let path = path.clone();
+            tokio::task::spawn_blocking(move || -> anyhow::Result<Connection> {
+                let conn = Connection::open(path)?;
+                conn.execute_batch(
+                    "CREATE TABLE IF NOT EXISTS visits (
+                        server TEXT PRIMARY KEY NOT NULL,
+                        count INTEGER NOT NULL
+                    );",
+                )?;
+                Ok(conn)
+            })
+            .await??
+        };
+
+        Ok(Self {
+            path,
+            conn: Arc::new(Mutex::new(conn)),
+            visits: MemoryStore::default(),
+        })
+    }
+}
+
+#[async_trait]
+impl VisitStore for SqliteStore {
+    async fn load(&self) -> anyhow::Result<()> {
+        let conn = self.conn.clone();
+        let visits = tokio::task::spawn_blocking(move || -> anyhow::Result<Visits> {
+            let conn = conn.lock().unwrap();
+            let mut stmt = conn.prepare("SELECT server, count FROM visits")?;
+            let rows = stmt.query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+            })?;
+
+            let mut visits = Visits::default();
+            for row in rows {
+                let (server, v) = row?;
+                visits.insert(server, v as usize);
+            }
+            Ok(visits)
+        })
+        .await??;
+
+        self.visits.replace(visits).await;
+        Ok(())
+    }
+
+    async fn increment(&self, server: &str) -> anyhow::Result<usize> {
+        self.visits.increment(server).await
+    }
+
+    async fn set(&self, server: &str, count: usize) -> anyhow::Result<()> {
+        self.visits.set(server, count).await
+    }
+
+    /// Every counter is held in memory between flushes, newer than the table.
+    async fn get(&self, server: &str) -> anyhow::Result<usize> {
+        self.visits.get(server).await
+    }
+
+    /// Flushes only upsert the counters that exist, so the row is deleted right away.
+    async fn remove(&self, server: &str) -> anyhow::Result<bool> {
+        if !self.visits.remove(server).await? {
+            return Ok(false);
+        }
+
+        let conn = self.conn.clone();
+        let server = server.to_string();
+        tokio::task::spawn_blocking(move || {
+            conn.lock()
+                .unwrap()
+                .execute("DELETE FROM visits WHERE server = ?1", [server])
+        })
+        .await??;
+        Ok(true)
+    }
+
+    async fn remove_many(&self, servers: &[String]) -> anyhow::Result<usize> {
+        let removed = self.visits.remove_many(servers).await?;
+        if removed == 0 {
+            return Ok(0);
+        }
+
+        let conn = self.conn.clone();
+        let servers = servers.to_vec();
+        tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+            let mut conn = conn.lock().unwrap();
+            let tx = conn.transaction()?;
+            {
+                let mut stmt = tx.prepare("DELETE FROM visits WHERE server = ?1")?;
+                for server in &servers {
+                    stmt.execute([server])?;
+                }
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await??;
+        Ok(removed)
+    }
+
+    async fn flush(&self) -> anyhow::Result<()> {
+        let conn = self.conn.clone();
+        self.visits
+            .write_changes(|visits| async move {
+                tokio::task::spawn_blocking(move || {
+                    let mut conn = conn.lock().unwrap();
+                    let tx = conn.transaction()?;
+                    {
+                        let mut stmt = tx.prepare(
+                            "INSERT INTO visits (server, count) VALUES (?1, ?2)
+                             ON CONFLICT(server) DO UPDATE SET count = excluded.count",
+                        )?;
+                        for (server, v) in &visits {
+                            stmt.execute((server, i64::try_from(*v).unwrap_or(i64::MAX)))?;
+                        }
+                    }
+                    tx.commit()?;
+                    Ok(())
+                })
+                .await?
+            })
+            .await
+    }
+
+    async fn snapshot(&self) -> anyhow::Result<Visits> {
+        Ok(self.visits.counts().await)
+    }
+}
+
+impl fmt::Display for SqliteStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sqlite://{}", self.path.display())
+    }
+}
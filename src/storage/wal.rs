@@ -0,0 +1,227 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Context;
+
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+use super::crypt::Cipher;
+use super::format::{escape, unescape};
+use super::Visits;
+
+/// An append-only log of visits, one server per line, kept next to the
+/// snapshot file as `<path>.wal`. Servers are escaped like in text files, or
+/// with a cipher, every line is encrypted on its own.
+///
+/// Each visit is appended as it happens, and [`Wal::clear`] truncates the log
+/// once its records have been compacted into the snapshot.
+pub struct Wal {
+    path: PathBuf,
+    file: File,
+    records: u64,
+    cipher: Option<Arc<Cipher>>,
+}
+
+impl Wal {
+    pub async fn open(snapshot: &Path, cipher: Option<Arc<Cipher>>) -> anyhow::Result<Self> {
+        let path = Self::path_of(snapshot);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+
+        Ok(Self {
+            path,
+            file,
+            records: 0,
+            cipher,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Where the log of the snapshot file `snapshot` is kept.
+    pub fn path_of(snapshot: &Path) -> PathBuf {
+        let mut name = snapshot.file_name().map(OsString::from).unwrap_or_default();
+        name.push(".wal");
+        snapshot.with_file_name(name)
+    }
+
+    /// Adds the visits recorded in the log of `snapshot` on top of `visits`
+    /// without opening it for writing, so a missing log isn't created.
+    pub async fn read(
+        snapshot: &Path,
+        cipher: Option<&Cipher>,
+        visits: &mut Visits,
+    ) -> anyhow::Result<()> {
+        let path = Self::path_of(snapshot);
+        match fs::read_to_string(&path).await {
+            Ok(data) => replay(&data, cipher, visits).map(drop),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err).with_context(|| format!("Can't read {path:?}")),
+        }
+    }
+
+    /// Number of records appended since the log was last cleared.
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    /// Adds the visits recorded in the log on top of `visits`.
+    ///
+    /// A torn last line from a crash mid-append is ignored.
+    pub async fn replay(&mut self, visits: &mut Visits) -> anyhow::Result<()> {
+        let data = fs::read_to_string(&self.path).await?;
+        self.records += replay(&data, self.cipher.as_deref(), visits)?;
+        Ok(())
+    }
+
+    pub async fn append(&mut self, server: &str) -> anyhow::Result<()> {
+        let line = match &self.cipher {
+            Some(cipher) => cipher.seal_line(server)?,
+            None => escape(server).into_owned(),
+        };
+
+        self.file.write_all(format!("{line}\n").as_bytes()).await?;
+        self.records += 1;
+        Ok(())
+    }
+
+    pub async fn sync(&mut self) -> anyhow::Result<()> {
+        self.file.sync_data().await?;
+        Ok(())
+    }
+
+    pub async fn clear(&mut self) -> anyhow::Result<()> {
+        self.file.set_len(0).await?;
+        self.file.sync_all().await?;
+        self.records = 0;
+        Ok(())
+    }
+}
+
+/// Adds the visits of the log `data` on top of `visits`, returning how many
+/// there were. A torn last line from a crash mid-append is ignored.
+fn replay(data: &str, cipher: Option<&Cipher>, visits: &mut Visits) -> anyhow::Result<u64> {
+    let complete = match data.rfind('\n') {
+        Some(end) => &data[..end],
+        None => "",
+    };
+
+    let mut records = 0;
+    for (i, line) in complete.lines().enumerate().filter(|(_, s)| !s.is_empty()) {
+        let server = match cipher {
+            Some(cipher) => cipher
+                .open_line(line)
+                .with_context(|| format!("Can't decrypt line {}", i + 1))?,
+            None => unescape(line).into_owned(),
+        };
+
+        let visit = visits.entry(server).or_insert(0);
+        *visit = visit.saturating_add(1);
+        records += 1;
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{FileOptions, FileStore, VisitStore};
+
+    fn logged(compact_after: u64) -> FileOptions {
+        FileOptions {
+            wal_compact_after: Some(compact_after),
+            ..FileOptions::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn replays_visits_that_werent_compacted() {
+        let dir = std::env::temp_dir().join(format!("itc-wal-replay-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("visits.txt");
+
+        let store = FileStore::open(path.clone(), logged(1000)).await.unwrap();
+        store.load().await.unwrap();
+        for server in ["https://a.com/", r"https://a.com/\n", "https://a.com/"] {
+            store.increment(server).await.unwrap();
+        }
+        store.flush().await.unwrap();
+        drop(store);
+        assert!(!tokio::fs::try_exists(&path).await.unwrap());
+
+        let store = FileStore::open(path.clone(), logged(1000)).await.unwrap();
+        store.load().await.unwrap();
+        assert_eq!(store.get("https://a.com/").await.unwrap(), 2);
+        assert_eq!(store.get(r"https://a.com/\n").await.unwrap(), 1);
+
+        // Replaying compacts, so the same visits aren't replayed twice.
+        assert_eq!(
+            tokio::fs::read_to_string(Wal::path_of(&path))
+                .await
+                .unwrap(),
+            ""
+        );
+        assert_eq!(
+            FileStore::read(&path, &logged(1000)).await.unwrap()["https://a.com/"],
+            2
+        );
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn compacts_once_full() {
+        let dir = std::env::temp_dir().join(format!("itc-wal-compact-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("visits.txt");
+
+        let store = FileStore::open(path.clone(), logged(2)).await.unwrap();
+        store.load().await.unwrap();
+        store.increment("https://a.com/").await.unwrap();
+        store.flush().await.unwrap();
+        assert!(!tokio::fs::try_exists(&path).await.unwrap());
+
+        store.increment("https://a.com/").await.unwrap();
+        store.flush().await.unwrap();
+        assert_eq!(
+            tokio::fs::read_to_string(Wal::path_of(&path))
+                .await
+                .unwrap(),
+            ""
+        );
+        assert_eq!(
+            FileStore::read(&path, &FileOptions::default())
+                .await
+                .unwrap()["https://a.com/"],
+            2
+        );
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[test]
+    fn ignores_a_torn_last_line() {
+        let mut visits = Visits::new();
+        let records = replay(
+            "https://a.com/\nhttps://b.com/\nhttps://c.c",
+            None,
+            &mut visits,
+        )
+        .unwrap();
+
+        assert_eq!(records, 2);
+        assert_eq!(
+            visits,
+            Visits::from([
+                ("https://a.com/".to_string(), 1),
+                ("https://b.com/".to_string(), 1),
+            ])
+        );
+    }
+}
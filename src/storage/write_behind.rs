@@ -0,0 +1,83 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::sync::Notify;
+
+use super::{VisitStore, Visits};
+
+/// Wraps a store to ask for a flush once `every` visits are waiting to be
+/// persisted, on top of the periodic saves.
+///
+/// The flush itself happens off the request path, whoever waits on
+/// [`WriteBehind::due`] runs it. Any flush resets the count.
+pub struct WriteBehind {
+    inner: Arc<dyn VisitStore>,
+    every: u64,
+    pending: AtomicU64,
+    due: Notify,
+}
+
+impl WriteBehind {
+    pub fn new(inner: Arc<dyn VisitStore>, every: u64) -> Self {
+        Self {
+            inner,
+            every,
+            pending: AtomicU64::new(0),
+            due: Notify::new(),
+        }
+    }
+
+    /// Resolves once enough visits are pending.
+    pub async fn due(&self) {
+        self.due.notified().await
+    }
+}
+
+#[async_trait]
+impl VisitStore for WriteBehind {
+    async fn load(&self) -> anyhow::Result<()> {
+        self.inner.load().await
+    }
+
+    async fn increment(&self, server: &str) -> anyhow::Result<usize> {
+        let visit = self.inner.increment(server).await?;
+
+        if self.pending.fetch_add(1, Ordering::AcqRel) + 1 == self.every {
+            self.due.notify_one();
+        }
+        Ok(visit)
+    }
+
+    async fn set(&self, server: &str, count: usize) -> anyhow::Result<()> {
+        self.inner.set(server, count).await
+    }
+
+    async fn get(&self, server: &str) -> anyhow::Result<usize> {
+        self.inner.get(server).await
+    }
+
+    async fn remove(&self, server: &str) -> anyhow::Result<bool> {
+        self.inner.remove(server).await
+    }
+
+    async fn remove_many(&self, servers: &[String]) -> anyhow::Result<usize> {
+        self.inner.remove_many(servers).await
+    }
+
+    async fn flush(&self) -> anyhow::Result<()> {
+        self.pending.store(0, Ordering::Release);
+        self.inner.flush().await
+    }
+
+    async fn snapshot(&self) -> anyhow::Result<Visits> {
+        self.inner.snapshot().await
+    }
+}
+
+impl fmt::Display for WriteBehind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
@@ -0,0 +1,685 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fs::{self, read_to_string};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Context as _};
+use clap::ValueEnum;
+use regex::Regex;
+use tera::{Context, Tera, Value};
+
+use crate::domains::{self, DomainPattern};
+use crate::locale;
+
+static LAZY_TEMPLATE: &str = include_str!("../lazy.html");
+
+/// The built-in templates, for counters without a `TEMPLATE` or picking one
+/// with `?style=`.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Theme {
+    /// The text of example.html, ticking up as visits arrive.
+    #[default]
+    Plain,
+    /// Only the number.
+    Minimal,
+    /// Digits rolling into place like a mechanical odometer as visits arrive.
+    Odometer,
+    /// Boxed digits on a blue page, like the hit counters of the 90s.
+    #[value(name = "retro-90s")]
+    Retro90s,
+    /// A label and the number, like the badges of README files.
+    Badge,
+    /// The text on a dark background, ticking up as visits arrive.
+    Dark,
+}
+
+impl Theme {
+    fn source(self) -> &'static str {
+        match self {
+            Theme::Plain => include_str!("../example.html"),
+            Theme::Minimal => include_str!("../themes/minimal.html"),
+            Theme::Odometer => include_str!("../themes/odometer.html"),
+            Theme::Retro90s => include_str!("../themes/retro-90s.html"),
+            Theme::Badge => include_str!("../themes/badge.html"),
+            Theme::Dark => include_str!("../themes/dark.html"),
+        }
+    }
+}
+
+thread_local! {
+    /// The separators of thousands and decimals the filters use by default, for the
+    /// locale of the page being rendered. Tera's filters only see their value, and
+    /// pages render in one go.
+    static SEPARATOR: Cell<&'static str> = const { Cell::new(",") };
+    static DECIMAL: Cell<&'static str> = const { Cell::new(".") };
+}
+
+/// Everything templates can fill in, like `{{VISIT_COUNT}}`.
+pub const VARIABLES: &[&str] = &[
+    "VISIT_COUNT",
+    "HIT_COUNT",
+    "UNIQUE_COUNT",
+    "SESSION_COUNT",
+    "TODAY_COUNT",
+    "ONLINE_NOW",
+    "TOTAL_ALL_SITES",
+    "TOP",
+    "COUNT",
+    "GOAL",
+    "PERCENT",
+    "REFERER",
+    "REFERER_HOST",
+    "COUNTER",
+    "UPDATED_AT",
+    "LOCALE",
+    "LABELS",
+    "DIGITS",
+    "COLOR",
+    "COLOR_LIGHT",
+    "COLOR_DARK",
+    "FONT_FAMILY",
+    "FONT_SIZE",
+    "BACKGROUND",
+    "CUSTOM_CSS",
+];
+
+/// The counts of [`VARIABLES`], a counter template should show one of them.
+const COUNTS: &[&str] = &[
+    "VISIT_COUNT",
+    "HIT_COUNT",
+    "UNIQUE_COUNT",
+    "SESSION_COUNT",
+    "TODAY_COUNT",
+    "ONLINE_NOW",
+    "TOTAL_ALL_SITES",
+    "TOP",
+    "COUNT",
+];
+
+/// The counts `{{COUNT:<name>}}` shows, like `{{COUNT:unique}}`, so one template can
+/// show several. `hits` and `visits` are both `{{VISIT_COUNT}}`.
+pub const COUNT_NAMES: &[&str] = &["hits", "visits", "unique", "sessions", "today", "online"];
+
+/// What the template is called in Tera, ending in `.html` so the values filled
+/// in are escaped.
+const NAME: &str = "counter.html";
+
+/// A counter template, rendered with [Tera](https://keats.github.io/tera/docs/).
+/// Besides `{{VISIT_COUNT}}` and friends, templates can use conditionals, loops
+/// and filters, like `{% if GOAL %}` or `{{VISIT_COUNT | thousands}}`.
+pub struct Template {
+    tera: Tera,
+    source: String,
+}
+
+impl Template {
+    /// Parses `source`, with `pad` padding to `digits` by default.
+    pub fn parse(source: &str, digits: Option<usize>) -> anyhow::Result<Self> {
+        let source = &expand_counts(source)?;
+        check(source)?;
+        let mut tera = Tera::default();
+        tera.register_filter("thousands", thousands);
+        tera.register_filter("abbreviate", abbreviate);
+        tera.register_filter("css", css);
+        tera.register_filter(
+            "pad",
+            move |value: &Value, args: &HashMap<String, Value>| pad(value, args, digits),
+        );
+        tera.add_raw_template(NAME, source)
+            .map_err(|err| anyhow!(error_chain(&err)))?;
+        Ok(Self {
+            tera,
+            source: source.to_string(),
+        })
+    }
+
+    /// Whether the template mentions `variable`, so values that are expensive to
+    /// work out are only filled in for templates that show them.
+    pub fn uses(&self, variable: &str) -> bool {
+        self.source.contains(variable)
+    }
+
+    /// Fills in `context`, formatting numbers for `locale`.
+    pub fn render(&self, context: &Context, locale: &str) -> anyhow::Result<String> {
+        SEPARATOR.set(locale::separator(locale));
+        DECIMAL.set(locale::decimal(locale));
+        self.tera
+            .render(NAME, context)
+            .map_err(|err| anyhow!(error_chain(&err)))
+    }
+}
+
+/// Every counter template.
+pub struct Templates {
+    pub main: Template,
+    /// Served at `/lazy`.
+    pub lazy: Template,
+    /// The `--template-map`, by domain.
+    by_domain: Vec<(DomainPattern, Template)>,
+    /// The `--namespace-template`s, by namespace name.
+    by_namespace: HashMap<String, Template>,
+    /// Every built-in template.
+    themes: HashMap<Theme, Template>,
+    /// The templates of the `--template-dir`, by file name without `.html`.
+    styles: HashMap<String, Template>,
+    /// The `--css`, or else nothing.
+    pub css: String,
+}
+
+impl Templates {
+    pub fn theme(&self, theme: Theme) -> &Template {
+        &self.themes[&theme]
+    }
+
+    /// The template an embed picks with `?style=<name>`: one of the
+    /// `--template-dir`, or else a built-in one.
+    pub fn style(&self, name: &str) -> Option<&Template> {
+        self.styles.get(name).or_else(|| {
+            let theme = Theme::from_str(name, true).ok()?;
+            Some(self.theme(theme))
+        })
+    }
+
+    /// The names `?style=` takes, in order.
+    pub fn style_names(&self) -> Vec<String> {
+        let themes = Theme::value_variants()
+            .iter()
+            .filter_map(|theme| theme.to_possible_value())
+            .map(|theme| theme.get_name().to_string());
+        let mut names: Vec<_> = self.styles.keys().cloned().chain(themes).collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// The template of counters in `namespace` embedded in `page`: the
+    /// namespace's own, or else the first of the `--template-map` listing the
+    /// host of `page`, or else the main one.
+    pub fn select(&self, namespace: Option<&str>, page: Option<&str>) -> &Template {
+        let by_domain = || {
+            let host = page.and_then(domains::host)?;
+            self.by_domain
+                .iter()
+                .find(|(domain, _)| domain.matches(&host))
+                .map(|(_, template)| template)
+        };
+        namespace
+            .and_then(|name| self.by_namespace.get(name))
+            .or_else(by_domain)
+            .unwrap_or(&self.main)
+    }
+}
+
+/// The files templates are read from. Templates without one are built in.
+#[derive(Clone, Debug)]
+pub struct Sources {
+    pub main: Option<PathBuf>,
+    /// Used without a `main` file.
+    pub theme: Theme,
+    pub lazy: Option<PathBuf>,
+    pub by_domain: Vec<(DomainPattern, PathBuf)>,
+    pub by_namespace: Vec<(String, PathBuf)>,
+    /// The `--template-dir`, of templates `?style=` picks.
+    pub dir: Option<PathBuf>,
+    /// The `--css` filled in as `{{CUSTOM_CSS}}`.
+    pub css: Option<PathBuf>,
+    /// The `--digits` counts are padded to.
+    pub digits: Option<usize>,
+}
+
+impl Sources {
+    pub fn load(&self) -> anyhow::Result<Templates> {
+        let main = match &self.main {
+            Some(path) => self.read(path)?,
+            None => Template::parse(self.theme.source(), self.digits)
+                .with_context(|| format!("Can't parse the {:?} theme", self.theme))?,
+        };
+        let lazy = match &self.lazy {
+            Some(path) => self.read(path)?,
+            None => Template::parse(LAZY_TEMPLATE, self.digits)?,
+        };
+
+        let mut by_domain = Vec::new();
+        for (domain, path) in &self.by_domain {
+            by_domain.push((domain.clone(), self.read(path)?));
+        }
+        let mut by_namespace = HashMap::new();
+        for (name, path) in &self.by_namespace {
+            by_namespace.insert(name.clone(), self.read(path)?);
+        }
+        let mut themes = HashMap::new();
+        for theme in Theme::value_variants() {
+            let template = Template::parse(theme.source(), self.digits)
+                .with_context(|| format!("Can't parse the {theme:?} theme"))?;
+            themes.insert(*theme, template);
+        }
+        let mut styles = HashMap::new();
+        for path in self.dir_files()? {
+            let Some(name) = path.file_stem().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            styles.insert(name.to_string(), self.read(&path)?);
+        }
+        let css = match &self.css {
+            Some(path) => {
+                read_to_string(path).with_context(|| format!("Can't read the CSS {path:?}"))?
+            }
+            None => String::new(),
+        };
+
+        Ok(Templates {
+            main,
+            lazy,
+            by_domain,
+            by_namespace,
+            themes,
+            styles,
+            css,
+        })
+    }
+
+    /// When each template and CSS file was last modified, to tell when they changed.
+    pub fn modified(&self) -> Vec<Option<SystemTime>> {
+        let paths = self.main.iter().chain(&self.lazy).chain(&self.css);
+        let paths = paths.chain(self.by_domain.iter().map(|(_, path)| path));
+        let paths = paths.chain(self.by_namespace.iter().map(|(_, path)| path));
+        // Files added to or removed from the directory change the length.
+        let dir = self.dir_files().unwrap_or_default();
+        paths
+            .chain(&dir)
+            .map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+            .collect()
+    }
+
+    /// The `.html` files of the `--template-dir`, sorted.
+    fn dir_files(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let Some(dir) = &self.dir else {
+            return Ok(Vec::new());
+        };
+        let mut files = Vec::new();
+        let entries = fs::read_dir(dir)
+            .with_context(|| format!("Can't read the template directory {dir:?}"))?;
+        for entry in entries {
+            let path = entry
+                .with_context(|| format!("Can't read the template directory {dir:?}"))?
+                .path();
+            if path.is_file() && path.extension().is_some_and(|ext| ext == "html") {
+                files.push(path);
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    fn read(&self, path: &Path) -> anyhow::Result<Template> {
+        let source =
+            read_to_string(path).with_context(|| format!("Can't read the template {path:?}"))?;
+        let template = Template::parse(&source, self.digits)
+            .with_context(|| format!("Can't parse the template {path:?}"))?;
+        if !COUNTS.iter().any(|count| template.uses(count)) {
+            log::warn!(
+                "The template {path:?} shows none of the counts, {}",
+                COUNTS.join(", ")
+            );
+        }
+        Ok(template)
+    }
+}
+
+/// Finds the `{{ }}` and `{% %}` tags of a template.
+fn tags() -> Regex {
+    Regex::new(r"(?s)\{\{.*?\}\}|\{%.*?%\}").unwrap()
+}
+
+/// Rewrites `{{COUNT:hits}}` into the `{{COUNT.hits}}` Tera understands, turning
+/// away counts that don't exist.
+fn expand_counts(source: &str) -> anyhow::Result<String> {
+    let tags = tags();
+    let counts = Regex::new(r"\bCOUNT:(\w+)").unwrap();
+
+    let mut unknown = Vec::new();
+    for tag in tags.find_iter(source) {
+        for count in counts.captures_iter(tag.as_str()) {
+            let name = &count[1];
+            if !COUNT_NAMES.contains(&name) {
+                let at = tag.start() + count.get(0).unwrap().start();
+                let line = source[..at].matches('\n').count() + 1;
+                unknown.push(format!("COUNT:{name} on line {line}"));
+            }
+        }
+    }
+    if !unknown.is_empty() {
+        anyhow::bail!(
+            "Unknown counts {}, expected one of {}",
+            unknown.join(", "),
+            COUNT_NAMES.join(", ")
+        );
+    }
+
+    let expanded = tags.replace_all(source, |tag: &regex::Captures| {
+        counts.replace_all(&tag[0], "COUNT.$1").into_owned()
+    });
+    Ok(expanded.into_owned())
+}
+
+/// Turns away templates with placeholders that aren't filled in, like a mistyped
+/// `{{VISITS_COUNT}}`, which Tera only notices when rendering, failing every request.
+/// Placeholders are the words in capitals of `{{ }}` and `{% %}` tags, besides
+/// those the template sets itself.
+fn check(source: &str) -> anyhow::Result<()> {
+    let tags = tags();
+    let strings = Regex::new(r#""[^"]*"|'[^']*'|`[^`]*`"#).unwrap();
+    let words = Regex::new(r"\b[A-Z][A-Z0-9_]*\b").unwrap();
+    let declared = Regex::new(r"\{%-?\s*(?:set|set_global|for)\s+(\w+)(?:\s*,\s*(\w+))?").unwrap();
+
+    let own: Vec<&str> = declared
+        .captures_iter(source)
+        .flat_map(|captures| captures.iter().skip(1).flatten().collect::<Vec<_>>())
+        .map(|name| name.as_str())
+        .collect();
+
+    let mut unknown = Vec::new();
+    for tag in tags.find_iter(source) {
+        let line = source[..tag.start()].matches('\n').count() + 1;
+        let code = strings.replace_all(tag.as_str(), "");
+        for word in words.find_iter(&code) {
+            let word = word.as_str();
+            if !VARIABLES.contains(&word) && !own.contains(&word) {
+                unknown.push(format!("{{{{{word}}}}} on line {line}"));
+            }
+        }
+    }
+    if !unknown.is_empty() {
+        anyhow::bail!(
+            "Unknown placeholders {}, expected one of {}",
+            unknown.join(", "),
+            VARIABLES.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Parses `--template-map` values like `example.com=dark.html`.
+pub fn parse_map(s: &str) -> Result<(DomainPattern, PathBuf), String> {
+    let (domain, path) = s
+        .split_once('=')
+        .filter(|(domain, path)| !domain.is_empty() && !path.is_empty())
+        .ok_or_else(|| format!("{s:?} isn't like `example.com=dark.html`"))?;
+    Ok((domain.parse()?, PathBuf::from(path)))
+}
+
+/// Parses flags going into CSS, like `--color` or `--font-family`, turning away
+/// values that could end the declaration they're in, or the `<style>` block.
+pub fn parse_css(s: &str) -> Result<String, String> {
+    let breaks_out = s.contains(['<', '>', ';', '{', '}', '\\'])
+        || s.contains("/*")
+        || s.chars().any(char::is_control)
+        || !s.matches('"').count().is_multiple_of(2)
+        || !s.matches('\'').count().is_multiple_of(2);
+    if s.trim().is_empty() || breaks_out {
+        return Err(format!(
+            "{s:?} isn't a CSS value like `#f0f0f0` or `\"Comic Sans MS\", cursive`"
+        ));
+    }
+    Ok(s.to_string())
+}
+
+/// Tera's errors only say which template failed, what went wrong is in their sources.
+fn error_chain(err: &tera::Error) -> String {
+    let mut message = err.to_string();
+    let mut source = std::error::Error::source(err);
+    while let Some(err) = source {
+        message.push_str(": ");
+        message.push_str(&err.to_string());
+        source = err.source();
+    }
+    message
+}
+
+/// `{{VISIT_COUNT | thousands}}` shows 1234567 as `1,234,567`, or as `1.234.567`
+/// and so on for the locale of the page, or with `thousands(sep=".")`.
+fn thousands(value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let Some(number) = value.as_u64() else {
+        return Ok(value.clone());
+    };
+    let separator = match args.get("sep") {
+        Some(Value::String(separator)) => separator.as_str(),
+        Some(_) => return Err("`sep` of `thousands` has to be a string".into()),
+        None => SEPARATOR.get(),
+    };
+
+    let digits = number.to_string();
+    let mut separated = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            separated.push_str(separator);
+        }
+        separated.push(digit);
+    }
+    Ok(Value::String(separated))
+}
+
+/// `{{VISIT_COUNT | abbreviate}}` shows 1234567 as `1.2M`, and 1234 as `1.2K`,
+/// or with more digits like `abbreviate(digits=2)`. Numbers below 1000 are kept.
+fn abbreviate(value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let Some(number) = value.as_u64() else {
+        return Ok(value.clone());
+    };
+    let digits = match args.get("digits") {
+        Some(digits) => digits
+            .as_u64()
+            .filter(|digits| *digits <= 3)
+            .ok_or("`digits` of `abbreviate` has to be a number up to 3")?
+            as usize,
+        None => 1,
+    };
+
+    const UNITS: [&str; 5] = ["", "K", "M", "B", "T"];
+    let mut unit = 0;
+    while unit + 1 < UNITS.len() && number >= 1000u64.pow(unit as u32 + 1) {
+        unit += 1;
+    }
+    if unit == 0 {
+        return Ok(Value::String(number.to_string()));
+    }
+
+    let mut shown = format!("{:.digits$}", number as f64 / 1000f64.powi(unit as i32));
+    // 999,999 rounds up to `1000.0K`, which is better said as `1.0M`.
+    if shown.starts_with("1000") && unit + 1 < UNITS.len() {
+        unit += 1;
+        shown = format!("{:.digits$}", number as f64 / 1000f64.powi(unit as i32));
+    }
+    if shown.contains('.') {
+        shown = shown
+            .trim_end_matches('0')
+            .trim_end_matches('.')
+            .to_string();
+    }
+    Ok(Value::String(format!(
+        "{}{}",
+        shown.replace('.', DECIMAL.get()),
+        UNITS[unit]
+    )))
+}
+
+/// `{{COLOR | css | safe}}` fills in a value in a `<style>` block, where escaping
+/// HTML garbles it: anything that could end the declaration, like `;` or `}`, is
+/// escaped for CSS instead.
+fn css(value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
+    let value = match value {
+        Value::String(value) => value.clone(),
+        Value::Null => String::new(),
+        value => value.to_string(),
+    };
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c.is_alphanumeric() || " #%.,()-_+/!\"'".contains(c) {
+            escaped.push(c);
+        } else {
+            escaped.push_str(&format!("\\{:x} ", c as u32));
+        }
+    }
+    Ok(Value::String(escaped))
+}
+
+/// `{{VISIT_COUNT | pad}}` shows 4210 as `0004210` with `--digits 7`, or with
+/// `pad(digits=7)`. Without either, the number is kept as it is, so
+/// `{{VISIT_COUNT | pad | thousands}}` only shows separators when not padding.
+fn pad(value: &Value, args: &HashMap<String, Value>, digits: Option<usize>) -> tera::Result<Value> {
+    let Some(number) = value.as_u64() else {
+        return Ok(value.clone());
+    };
+    let digits = match args.get("digits") {
+        Some(digits) => Some(
+            digits
+                .as_u64()
+                .filter(|digits| *digits <= 20)
+                .ok_or("`digits` of `pad` has to be a number up to 20")? as usize,
+        ),
+        None => digits,
+    };
+    match digits {
+        Some(digits) => Ok(Value::String(format!("{number:0digits$}"))),
+        None => Ok(value.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(
+        source: &str,
+        digits: Option<usize>,
+        locale: &str,
+        values: &[(&str, Value)],
+    ) -> String {
+        let mut context = Context::new();
+        for (name, value) in values {
+            context.insert(*name, value);
+        }
+        Template::parse(source, digits)
+            .unwrap()
+            .render(&context, locale)
+            .unwrap()
+    }
+
+    #[test]
+    fn separates_thousands() {
+        let count = [("VISIT_COUNT", Value::from(1234567))];
+        assert_eq!(
+            render("{{VISIT_COUNT | thousands}}", None, "en", &count),
+            "1,234,567"
+        );
+        assert_eq!(
+            render("{{VISIT_COUNT | thousands}}", None, "de", &count),
+            "1.234.567"
+        );
+        assert_eq!(
+            render(
+                r#"{{VISIT_COUNT | thousands(sep=" ")}}"#,
+                None,
+                "en",
+                &count
+            ),
+            "1 234 567"
+        );
+        let small = [("VISIT_COUNT", Value::from(999))];
+        assert_eq!(
+            render("{{VISIT_COUNT | thousands}}", None, "en", &small),
+            "999"
+        );
+    }
+
+    #[test]
+    fn abbreviates() {
+        let shown = |count: u64, source: &str| {
+            render(source, None, "en", &[("VISIT_COUNT", Value::from(count))])
+        };
+        assert_eq!(shown(999, "{{VISIT_COUNT | abbreviate}}"), "999");
+        assert_eq!(shown(1234, "{{VISIT_COUNT | abbreviate}}"), "1.2K");
+        assert_eq!(shown(2000, "{{VISIT_COUNT | abbreviate}}"), "2K");
+        assert_eq!(shown(999_999, "{{VISIT_COUNT | abbreviate}}"), "1M");
+        assert_eq!(
+            shown(1_234_567, "{{VISIT_COUNT | abbreviate(digits=2)}}"),
+            "1.23M"
+        );
+        let mut context = Context::new();
+        context.insert("VISIT_COUNT", &1234);
+        let template = Template::parse("{{VISIT_COUNT | abbreviate(digits=4)}}", None).unwrap();
+        assert!(template.render(&context, "en").is_err());
+    }
+
+    #[test]
+    fn pads_to_the_digits() {
+        let count = [("VISIT_COUNT", Value::from(4210))];
+        assert_eq!(
+            render("{{VISIT_COUNT | pad}}", Some(7), "en", &count),
+            "0004210"
+        );
+        assert_eq!(
+            render("{{VISIT_COUNT | pad(digits=5)}}", None, "en", &count),
+            "04210"
+        );
+        assert_eq!(
+            render("{{VISIT_COUNT | pad | thousands}}", None, "en", &count),
+            "4,210"
+        );
+    }
+
+    #[test]
+    fn escapes_css() {
+        let style = r#"<style>a { color: {{COLOR | css | safe}}; }</style><p style="background: {{BACKGROUND | css}};">{{VISIT_COUNT}}</p>"#;
+        let values = [
+            ("VISIT_COUNT", Value::from(1)),
+            ("COLOR", Value::from("red; } body { display: none")),
+            ("BACKGROUND", Value::from(r#"url("x") ;"#)),
+        ];
+        assert_eq!(
+            render(style, None, "en", &values),
+            concat!(
+                r"<style>a { color: red\3b  \7d  body \7b  display\3a  none; }</style>",
+                r#"<p style="background: url(&quot;x&quot;) \3b ;">1</p>"#,
+            )
+        );
+        assert_eq!(
+            render(
+                r#"{{FONT_FAMILY | css | safe}}"#,
+                None,
+                "en",
+                &[("FONT_FAMILY", Value::from(r#""Comic Sans MS", cursive"#))]
+            ),
+            r#""Comic Sans MS", cursive"#
+        );
+    }
+
+    #[test]
+    fn names_unknown_placeholders() {
+        let err = Template::parse("<p>\n{{VISITS_COUNT}}</p>", None)
+            .err()
+            .unwrap();
+        assert!(
+            err.to_string().contains("{{VISITS_COUNT}} on line 2"),
+            "{err}"
+        );
+
+        let err = Template::parse("{{COUNT:views}}", None).err().unwrap();
+        assert!(err.to_string().contains("COUNT:views on line 1"), "{err}");
+
+        Template::parse("{% set SHOWN = VISIT_COUNT %}{{SHOWN}}", None).unwrap();
+    }
+
+    #[test]
+    fn themes_escape_their_styles() {
+        for theme in Theme::value_variants() {
+            let source = theme.source();
+            for name in ["BACKGROUND", "COLOR", "FONT_FAMILY", "FONT_SIZE"] {
+                assert!(
+                    !source.contains(&format!("{{{{{name}}}}}")),
+                    "{theme:?} {name}"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use anyhow::Context;
+use futures_util::StreamExt;
+use rustls_acme::caches::DirCache;
+use rustls_acme::{is_tls_alpn_challenge, AcmeConfig};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::Acceptor;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::LazyConfigAcceptor;
+
+/// Terminates TLS for incoming connections.
+#[derive(Clone)]
+pub struct Tls {
+    /// Replaced as a whole when the certificate is reloaded.
+    config: Arc<RwLock<Arc<ServerConfig>>>,
+    /// Answers the TLS-ALPN-01 challenges of the ACME server, when certificates
+    /// are obtained automatically.
+    challenge: Option<Arc<ServerConfig>>,
+    /// The certificate chain and key files, when they're not obtained automatically.
+    files: Option<(PathBuf, PathBuf)>,
+}
+
+impl Tls {
+    /// Serves the PEM certificate chain at `cert` with its private key at `key`.
+    pub fn from_files(cert: &Path, key: &Path) -> anyhow::Result<Self> {
+        let mut tls = Self::new(read_files(cert, key)?, None);
+        tls.files = Some((cert.to_path_buf(), key.to_path_buf()));
+        Ok(tls)
+    }
+
+    /// Reads the certificate and key files again, e.g. after they were renewed.
+    /// Connections already open keep the old certificate. Certificates obtained
+    /// automatically are renewed on their own.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let Some((cert, key)) = &self.files else {
+            return Ok(());
+        };
+        *self.config.write().unwrap() = with_alpn(read_files(cert, key)?);
+        Ok(())
+    }
+
+    /// Obtains certificates for `domains` from Let's Encrypt and renews them in
+    /// the background. They are kept in `cache`, so restarts don't request new ones.
+    pub fn acme(domains: &[String], contact: &[String], cache: PathBuf, staging: bool) -> Self {
+        let mut state = AcmeConfig::new(domains)
+            .contact(contact.iter().map(|email| format!("mailto:{email}")))
+            .cache(DirCache::new(cache))
+            .directory_lets_encrypt(!staging)
+            .state();
+
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(state.resolver());
+        let challenge = state.challenge_rustls_config();
+
+        tokio::spawn(async move {
+            while let Some(event) = state.next().await {
+                match event {
+                    Ok(event) => log::info!("ACME: {event:?}"),
+                    Err(err) => log::error!("ACME error: {err:?}"),
+                }
+            }
+        });
+
+        Self::new(config, Some(challenge))
+    }
+
+    fn new(config: ServerConfig, challenge: Option<Arc<ServerConfig>>) -> Self {
+        Self {
+            config: Arc::new(RwLock::new(with_alpn(config))),
+            challenge,
+            files: None,
+        }
+    }
+
+    /// Completes the handshake on `stream`. Returns `None` for challenge
+    /// connections of the ACME server, which are done after the handshake.
+    pub async fn accept<IO>(&self, stream: IO) -> io::Result<Option<TlsStream<IO>>>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin,
+    {
+        let handshake = LazyConfigAcceptor::new(Acceptor::default(), stream).await?;
+
+        if let Some(challenge) = &self.challenge {
+            if is_tls_alpn_challenge(&handshake.client_hello()) {
+                log::debug!("Answering an ACME challenge");
+                let mut stream = handshake.into_stream(challenge.clone()).await?;
+                stream.shutdown().await?;
+                return Ok(None);
+            }
+        }
+
+        let config = self.config.read().unwrap().clone();
+        Ok(Some(handshake.into_stream(config).await?))
+    }
+}
+
+fn read_files(cert: &Path, key: &Path) -> anyhow::Result<ServerConfig> {
+    let chain = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Can't read certificates from {cert:?}"))?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .with_context(|| format!("Can't read a private key from {key:?}"))?;
+
+    ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(chain, key)
+        .context("The TLS certificate and key don't work together")
+}
+
+fn with_alpn(mut config: ServerConfig) -> Arc<ServerConfig> {
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Arc::new(config)
+}
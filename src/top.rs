@@ -0,0 +1,56 @@
+use std::fmt::Write;
+
+use crate::admin::escape;
+use crate::storage::Visits;
+
+static PAGE: &str = include_str!("top.html");
+
+/// The periods `/top` links to, as their `?period=` and label.
+const PERIODS: &[(&str, &str)] = &[
+    ("", "All time"),
+    ("month", "This month"),
+    ("week", "This week"),
+    ("day", "Today"),
+    ("hour", "This hour"),
+];
+
+/// The `limit` busiest referers of `visits`, busiest first.
+pub fn leaders(visits: Visits, limit: usize) -> Vec<(String, usize)> {
+    let mut leaders: Vec<_> = visits.into_iter().filter(|(_, count)| *count > 0).collect();
+    leaders.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+    leaders.truncate(limit);
+    leaders
+}
+
+/// Renders `leaders` as the `/top` page. `period` is the `?period=` shown, and
+/// `periods` those that can be picked.
+pub fn page(leaders: &[(String, usize)], period: &str, periods: &[&str]) -> String {
+    let mut links = String::new();
+    for (value, label) in PERIODS.iter().filter(|(value, _)| periods.contains(value)) {
+        let href = if value.is_empty() {
+            String::from("top")
+        } else {
+            format!("top?period={value}")
+        };
+        let current = if *value == period {
+            r#" class="current""#
+        } else {
+            ""
+        };
+        writeln!(links, r#"        <a href="{href}"{current}>{label}</a>"#).unwrap();
+    }
+
+    let mut rows = String::new();
+    for (rank, (referer, count)) in leaders.iter().enumerate() {
+        writeln!(
+            rows,
+            r#"            <tr><td>{}</td><td>{}</td><td class="count">{count}</td></tr>"#,
+            rank + 1,
+            escape(referer),
+        )
+        .unwrap();
+    }
+
+    PAGE.replace("{{PERIODS}}", links.trim_end())
+        .replace("{{ROWS}}", rows.trim_end())
+}
@@ -0,0 +1,67 @@
+use hyper::header::HeaderValue;
+use hyper::{header, Request};
+use sha2::{Digest, Sha256};
+
+/// How long a visitor is recognized, in seconds.
+const MAX_AGE: u64 = 365 * 24 * 60 * 60;
+
+/// Visitors are recognized by a cookie per referer, so nothing about them has
+/// to be stored on the server. It's named after a hash of the referer, which
+/// keeps the name short and free of characters cookies don't allow.
+fn cookie_name(referer: &str) -> String {
+    let hash = Sha256::digest(referer.as_bytes());
+    let hex: String = hash[..8].iter().map(|b| format!("{b:02x}")).collect();
+    format!("itc_{hex}")
+}
+
+/// Whether the visitor already got the cookie of `referer`.
+pub fn seen<B>(req: &Request<B>, referer: &str) -> bool {
+    let name = cookie_name(referer);
+    req.headers()
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .any(|cookie| {
+            cookie
+                .trim()
+                .split_once('=')
+                .is_some_and(|(n, _)| n == name)
+        })
+}
+
+/// The `Set-Cookie` value marking the visitor as seen on `referer`. The counter
+/// is embedded on other sites, so the cookie has to be allowed cross-site, but
+/// browsers only accept that for `Secure` cookies. Over plain HTTP, where those
+/// would be dropped, it falls back to `SameSite=Lax`.
+pub fn cookie(referer: &str, https: bool) -> HeaderValue {
+    let attributes = if https {
+        "Secure; SameSite=None"
+    } else {
+        "SameSite=Lax"
+    };
+    let cookie = format!(
+        "{}=1; Max-Age={MAX_AGE}; Path=/; HttpOnly; {attributes}",
+        cookie_name(referer)
+    );
+    HeaderValue::from_str(&cookie).expect("cookie is plain ASCII")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_asks_for_cross_site_cookies_over_https() {
+        let cookie = cookie("https://example.com/", true);
+        let cookie = cookie.to_str().unwrap();
+        assert!(cookie.starts_with(&format!("{}=1;", cookie_name("https://example.com/"))));
+        assert!(cookie.ends_with("; HttpOnly; Secure; SameSite=None"));
+
+        let cookie = super::cookie("https://example.com/", false);
+        assert!(cookie
+            .to_str()
+            .unwrap()
+            .ends_with("; HttpOnly; SameSite=Lax"));
+    }
+}